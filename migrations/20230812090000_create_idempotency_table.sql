@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TYPE header_pair AS (
+    name TEXT,
+    value BYTEA
+);
+CREATE TABLE idempotency (
+    -- requests without an authenticated user are stored under the nil uuid
+    user_id uuid NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    -- the response columns stay NULL while the first request is in flight
+    response_status_code SMALLINT NULL,
+    response_headers header_pair[] NULL,
+    response_body BYTEA NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY(user_id, idempotency_key)
+);
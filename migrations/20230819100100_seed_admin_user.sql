@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Bootstrap admin account. Its initial password is shared out of band and
+-- has to be changed at /admin/password on first login, see must_change_password.
+INSERT INTO users (user_id, username, password_hash)
+VALUES (
+    'ddf8994f-d522-4659-8d02-c1d479057be6',
+    'admin',
+    '$argon2id$v=19$m=15000,t=2,p=1$SZ0CMa/0bNVp3OEns3qvHQ$CyqZiFrCdE83fSdejjPSVmIn1UZY9STwNqs+2GdFUak'
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE sessions(
+    session_key TEXT NOT NULL,
+    -- serialized HashMap<String, String> handed to us by actix-session
+    session_state JSONB NOT NULL,
+    expires_at timestamptz NOT NULL,
+    PRIMARY KEY (session_key)
+);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,13 @@
+-- Add migration script here
+-- where password reset links are sent, NULL means the user cannot reset by email
+ALTER TABLE users ADD COLUMN email TEXT NULL UNIQUE;
+CREATE TABLE password_reset_tokens(
+    password_reset_token TEXT NOT NULL,
+    user_id uuid NOT NULL
+     REFERENCES users (user_id),
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    -- set once the token has been redeemed, tokens are single use
+    used_at timestamptz NULL,
+    PRIMARY KEY (password_reset_token)
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+-- one long-lived token per subscriber, carried by every email they get
+BEGIN;
+ALTER TABLE subscriptions ADD COLUMN unsubscribe_token TEXT NULL;
+UPDATE subscriptions
+SET unsubscribe_token = md5(random()::text || id::text)
+WHERE unsubscribe_token IS NULL;
+ALTER TABLE subscriptions ALTER COLUMN unsubscribe_token SET NOT NULL;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_unsubscribe_token_key UNIQUE (unsubscribe_token);
+COMMIT;
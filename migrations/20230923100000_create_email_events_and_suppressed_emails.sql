@@ -0,0 +1,20 @@
+-- Add migration script here
+-- every bounce/complaint/delivery notification the provider sends us, as received
+CREATE TABLE email_events(
+    email_event_id uuid NOT NULL,
+    record_type TEXT NOT NULL,
+    email TEXT NULL,
+    message_id TEXT NULL,
+    payload JSONB NOT NULL,
+    received_at timestamptz NOT NULL,
+    PRIMARY KEY (email_event_id)
+);
+CREATE INDEX email_events_email_idx ON email_events (email);
+-- addresses we must never email again, whatever table they show up in
+CREATE TABLE suppressed_emails(
+    email TEXT NOT NULL,
+    -- hard_bounce | spam_complaint
+    reason TEXT NOT NULL,
+    suppressed_at timestamptz NOT NULL,
+    PRIMARY KEY (email)
+);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- free-form per subscriber values for `{{ custom.<field> }}` merge tags
+ALTER TABLE subscriptions ADD COLUMN custom_fields JSONB NOT NULL DEFAULT '{}'::jsonb;
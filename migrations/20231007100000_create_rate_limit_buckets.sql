@@ -0,0 +1,8 @@
+-- Add migration script here
+-- token buckets of the postgres rate limiter backend, shared between instances
+CREATE TABLE rate_limit_buckets(
+    key TEXT NOT NULL,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (key)
+);
@@ -0,0 +1,20 @@
+-- Add migration script here
+-- emails written in the same transaction as the change that triggers them,
+-- the outbox dispatcher sends them once that transaction has committed
+CREATE TABLE outbox(
+    outbox_id uuid NOT NULL,
+    -- what triggered the email, e.g. subscription_confirmation
+    kind TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    created_at timestamptz NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at timestamptz NOT NULL DEFAULT now(),
+    last_error TEXT NULL,
+    sent_at timestamptz NULL,
+    message_id TEXT NULL,
+    PRIMARY KEY (outbox_id)
+);
+CREATE INDEX outbox_pending_idx ON outbox (next_attempt_at) WHERE sent_at IS NULL;
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- emails that exhausted their retries, kept until an admin requeues or discards them
+CREATE TABLE dead_letters(
+    dead_letter_id uuid NOT NULL,
+    -- newsletter_issue or outbox, tells how to requeue the payload
+    source TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INT NOT NULL,
+    last_error TEXT NOT NULL,
+    failed_at timestamptz NOT NULL,
+    PRIMARY KEY (dead_letter_id)
+);
+CREATE INDEX dead_letters_failed_at_idx ON dead_letters (failed_at);
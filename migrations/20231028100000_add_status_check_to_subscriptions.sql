@@ -0,0 +1,5 @@
+-- Add migration script here
+-- mirrors domain::SubscriptionStatus, the transitions between them are enforced in the app
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check CHECK (
+    status IN ('pending_confirmation', 'confirmed', 'unsubscribed', 'bounced', 'complained')
+);
@@ -0,0 +1,8 @@
+-- Set for accounts whose password is known to someone other than their owner.
+-- Such a user can only reach the change password page until they pick a new one.
+ALTER TABLE users ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT false;
+-- the seeded admin, unless it was rotated already
+UPDATE users
+SET must_change_password = true
+WHERE user_id = 'ddf8994f-d522-4659-8d02-c1d479057be6'
+    AND password_hash = '$argon2id$v=19$m=15000,t=2,p=1$SZ0CMa/0bNVp3OEns3qvHQ$CyqZiFrCdE83fSdejjPSVmIn1UZY9STwNqs+2GdFUak';
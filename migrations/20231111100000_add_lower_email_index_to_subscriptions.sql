@@ -0,0 +1,3 @@
+-- Emails are stored as typed, but Foo@x.com and foo@x.com are one mailbox.
+-- Inserts target this index with ON CONFLICT ((lower(email))).
+CREATE UNIQUE INDEX subscriptions_lower_email_key ON subscriptions (lower(email));
@@ -0,0 +1,7 @@
+-- Anonymous requests are no longer stored under the nil uuid, their user_id is
+-- derived from the submitted email, see idempotency::anonymous_scope.
+-- What the key was first used for, a different request under the same key is rejected.
+-- NULL for rows saved before it was recorded.
+ALTER TABLE idempotency ADD COLUMN request_fingerprint TEXT NULL;
+-- the cleanup worker expires keys by age
+CREATE INDEX idempotency_created_at_idx ON idempotency (created_at);
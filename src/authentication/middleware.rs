@@ -0,0 +1,60 @@
+use std::ops::Deref;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::{FromRequest, HttpMessage};
+use actix_web_lab::middleware::Next;
+use uuid::Uuid;
+
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+
+//inserted in the request extensions by reject_anonymous_users,
+//handlers behind the middleware extract it with web::ReqData<UserId>
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl Deref for UserId {
+    type Target = Uuid;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+const PASSWORD_CHANGE_EXEMPT_PATHS: [&str; 2] = ["/admin/password", "/admin/logout"];
+
+pub async fn reject_anonymous_users(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let session = {
+        let (http_request, payload) = req.parts_mut();
+        TypedSession::from_request(http_request, payload).await
+    }?;
+    match session.get_user_id().map_err(e500)? {
+        //the password change form and logging out are all that is left
+        Some(_)
+            if session.get_must_change_password().map_err(e500)?
+                && !PASSWORD_CHANGE_EXEMPT_PATHS.contains(&req.path()) =>
+        {
+            let response = see_other("/admin/password");
+            let e = anyhow::anyhow!("The user must change their password first");
+            Err(InternalError::from_response(e, response).into())
+        }
+        Some(user_id) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req).await
+        }
+        None => {
+            let response = see_other("/login");
+            let e = anyhow::anyhow!("The user has not logged in");
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
@@ -0,0 +1,7 @@
+mod middleware;
+mod password;
+pub use middleware::{reject_anonymous_users, UserId};
+pub use password::{
+    basic_authentication, change_password, compute_password_hash, must_change_password,
+    validate_credentials, validate_new_password, AuthError, Credentials,
+};
@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+
+use anyhow::Context;
+use secrecy::{ExposeSecret, Secret};
+
+use crate::email_templates::escape_html;
+
+#[async_trait::async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    //Ok(false) when the provider says the challenge was not solved,
+    //Err when we could not ask it
+    async fn verify(&self, response: &str, remote_ip: Option<IpAddr>)
+        -> Result<bool, anyhow::Error>;
+    //what the subscription form needs to show the challenge
+    fn widget_html(&self) -> String;
+}
+
+//https://docs.hcaptcha.com/#verify-the-user-response-server-side
+pub struct HCaptchaVerifier {
+    http_client: reqwest::Client,
+    verify_url: String,
+    site_key: String,
+    secret: Secret<String>,
+}
+impl HCaptchaVerifier {
+    pub fn new(
+        verify_url: String,
+        site_key: String,
+        secret: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("failed to build the captcha http client");
+        Self {
+            http_client,
+            verify_url,
+            site_key,
+            secret,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for HCaptchaVerifier {
+    #[tracing::instrument(name = "Verify a captcha response", skip(self, response))]
+    async fn verify(
+        &self,
+        response: &str,
+        remote_ip: Option<IpAddr>,
+    ) -> Result<bool, anyhow::Error> {
+        let remote_ip = remote_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let answer: SiteVerifyResponse = self
+            .http_client
+            .post(&self.verify_url)
+            .form(&[
+                ("secret", self.secret.expose_secret().as_str()),
+                ("response", response),
+                ("sitekey", &self.site_key),
+                ("remoteip", &remote_ip),
+            ])
+            .send()
+            .await
+            .context("Failed to reach the captcha provider.")?
+            .error_for_status()
+            .context("The captcha provider returned an error.")?
+            .json()
+            .await
+            .context("Failed to parse the captcha provider's answer.")?;
+        Ok(answer.success)
+    }
+
+    fn widget_html(&self) -> String {
+        let mut site_key = String::new();
+        escape_html(&mut site_key, &self.site_key);
+        format!(
+            r#"<div class="h-captcha" data-sitekey="{}"></div>
+        <script src="https://js.hcaptcha.com/1/api.js" async defer></script>"#,
+            site_key
+        )
+    }
+}
+
+//accepts one configured answer, for local development and the test suite
+pub struct FakeCaptchaVerifier {
+    accepted_response: String,
+}
+impl FakeCaptchaVerifier {
+    pub fn new(accepted_response: String) -> Self {
+        Self { accepted_response }
+    }
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for FakeCaptchaVerifier {
+    async fn verify(
+        &self,
+        response: &str,
+        _remote_ip: Option<IpAddr>,
+    ) -> Result<bool, anyhow::Error> {
+        Ok(response == self.accepted_response)
+    }
+
+    fn widget_html(&self) -> String {
+        let mut accepted_response = String::new();
+        escape_html(&mut accepted_response, &self.accepted_response);
+        format!(
+            r#"<label>Type "{}" to prove you are human
+            <input type="text" name="h-captcha-response">
+        </label>"#,
+            accepted_response
+        )
+    }
+}
@@ -0,0 +1,81 @@
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+//keeps these signatures from being valid anywhere else the secret is used
+const DOMAIN: &[u8] = b"subscribe-form-token:";
+
+//`<issued at, unix seconds>.<base64url hmac-sha256>`, stateless so any instance
+//can check a token another one issued
+pub struct FormTokenSigner {
+    key: Secret<String>,
+}
+impl FormTokenSigner {
+    pub fn new(key: Secret<String>) -> Self {
+        Self { key }
+    }
+
+    pub fn issue(&self, issued_at: DateTime<Utc>) -> String {
+        let timestamp = issued_at.timestamp().to_string();
+        let signature = self.mac(&timestamp).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            timestamp,
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    //the time the token was issued at, None if it was not issued by us
+    pub fn verify(&self, token: &str) -> Option<DateTime<Utc>> {
+        let (timestamp, signature) = token.split_once('.')?;
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(signature)
+            .ok()?;
+        //constant time comparison
+        self.mac(timestamp).verify_slice(&signature).ok()?;
+        Utc.timestamp_opt(timestamp.parse().ok()?, 0).single()
+    }
+
+    fn mac(&self, timestamp: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.expose_secret().as_bytes())
+            .expect("hmac accepts keys of any size");
+        mac.update(DOMAIN);
+        mac.update(timestamp.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FormTokenSigner;
+    use chrono::{TimeZone, Utc};
+    use secrecy::Secret;
+
+    fn signer(key: &str) -> FormTokenSigner {
+        FormTokenSigner::new(Secret::new(key.to_owned()))
+    }
+
+    #[test]
+    fn issued_tokens_verify_to_their_issue_time() {
+        let issued_at = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let token = signer("key").issue(issued_at);
+        assert_eq!(signer("key").verify(&token), Some(issued_at));
+    }
+
+    #[test]
+    fn tokens_signed_with_another_key_are_rejected() {
+        let token = signer("key").issue(Utc::now());
+        assert_eq!(signer("another key").verify(&token), None);
+    }
+
+    #[test]
+    fn tampered_timestamps_are_rejected() {
+        let token = signer("key").issue(Utc.timestamp_opt(1_700_000_000, 0).unwrap());
+        let (_, signature) = token.split_once('.').unwrap();
+        let tampered = format!("1600000000.{}", signature);
+        assert_eq!(signer("key").verify(&tampered), None);
+        assert_eq!(signer("key").verify("garbage"), None);
+    }
+}
@@ -0,0 +1,173 @@
+mod captcha;
+mod form_token;
+
+pub use captcha::{CaptchaVerifier, FakeCaptchaVerifier, HCaptchaVerifier};
+pub use form_token::FormTokenSigner;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+
+//the honeypot field of the subscription form, hidden from humans,
+//bots tend to fill in every field they find
+pub const HONEYPOT_FIELD: &str = "website";
+
+//what the subscription form sent us besides name and email
+pub struct BotSubmission<'a> {
+    pub honeypot: Option<&'a str>,
+    pub form_token: Option<&'a str>,
+    pub captcha_response: Option<&'a str>,
+    pub client_ip: Option<IpAddr>,
+}
+
+//checked by `subscribe` before anything is stored or sent
+pub struct BotProtection {
+    //None when form tokens are disabled
+    pub form_tokens: Option<FormTokens>,
+    //None when no captcha is configured
+    pub captcha: Option<Arc<dyn CaptchaVerifier>>,
+}
+
+pub struct FormTokens {
+    pub signer: FormTokenSigner,
+    //humans take a few seconds to fill in a form, scripts do not
+    pub min_fill_time: Duration,
+    pub max_age: Duration,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BotRejection {
+    #[error("The honeypot field was filled in.")]
+    HoneypotFilled,
+    #[error("The form token is missing or invalid.")]
+    InvalidFormToken,
+    #[error("The form was submitted too quickly.")]
+    TooFast,
+    #[error("The form has expired, please reload the page.")]
+    ExpiredFormToken,
+    #[error("The captcha was not solved.")]
+    CaptchaFailed,
+    #[error("Failed to verify the captcha.")]
+    CaptchaUnavailable(#[source] anyhow::Error),
+}
+
+impl BotProtection {
+    //a fresh token for the subscription form, None when they are disabled
+    pub fn issue_form_token(&self) -> Option<String> {
+        self.form_tokens
+            .as_ref()
+            .map(|form_tokens| form_tokens.signer.issue(Utc::now()))
+    }
+
+    pub async fn check(&self, submission: &BotSubmission<'_>) -> Result<(), BotRejection> {
+        if submission.honeypot.is_some_and(|value| !value.is_empty()) {
+            return Err(BotRejection::HoneypotFilled);
+        }
+        //tokens are not single use, replaying one is left to the rate limits
+        if let Some(form_tokens) = &self.form_tokens {
+            let issued_at = submission
+                .form_token
+                .and_then(|token| form_tokens.signer.verify(token))
+                .ok_or(BotRejection::InvalidFormToken)?;
+            let age = Utc::now() - issued_at;
+            if age < form_tokens.min_fill_time {
+                return Err(BotRejection::TooFast);
+            }
+            if age > form_tokens.max_age {
+                return Err(BotRejection::ExpiredFormToken);
+            }
+        }
+        if let Some(captcha) = &self.captcha {
+            let response = submission
+                .captcha_response
+                .filter(|response| !response.is_empty())
+                .ok_or(BotRejection::CaptchaFailed)?;
+            let solved = captcha
+                .verify(response, submission.client_ip)
+                .await
+                .map_err(BotRejection::CaptchaUnavailable)?;
+            if !solved {
+                return Err(BotRejection::CaptchaFailed);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BotProtection, BotRejection, BotSubmission, FakeCaptchaVerifier, FormTokens};
+    use super::FormTokenSigner;
+    use chrono::{Duration, Utc};
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::sync::Arc;
+
+    fn protection(min_fill_seconds: i64) -> BotProtection {
+        BotProtection {
+            form_tokens: Some(FormTokens {
+                signer: FormTokenSigner::new(Secret::new("key".into())),
+                min_fill_time: Duration::seconds(min_fill_seconds),
+                max_age: Duration::hours(1),
+            }),
+            captcha: Some(Arc::new(FakeCaptchaVerifier::new("pass".into()))),
+        }
+    }
+
+    fn submission<'a>(form_token: Option<&'a str>, captcha: Option<&'a str>) -> BotSubmission<'a> {
+        BotSubmission {
+            honeypot: Some(""),
+            form_token,
+            captcha_response: captcha,
+            client_ip: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_valid_submission_is_accepted() {
+        let protection = protection(0);
+        let token = protection.issue_form_token().unwrap();
+        assert_ok!(protection.check(&submission(Some(&token), Some("pass"))).await);
+    }
+
+    #[tokio::test]
+    async fn a_filled_honeypot_is_rejected() {
+        let protection = BotProtection {
+            form_tokens: None,
+            captcha: None,
+        };
+        let mut submission = submission(None, None);
+        submission.honeypot = Some("http://spam.example.com");
+        let rejection = assert_err!(protection.check(&submission).await);
+        assert!(matches!(rejection, BotRejection::HoneypotFilled));
+    }
+
+    #[tokio::test]
+    async fn fast_missing_and_expired_tokens_are_rejected() {
+        let protection = protection(30);
+        let fresh = protection.issue_form_token().unwrap();
+        let rejection = assert_err!(protection.check(&submission(Some(&fresh), Some("pass"))).await);
+        assert!(matches!(rejection, BotRejection::TooFast));
+        let rejection = assert_err!(protection.check(&submission(None, Some("pass"))).await);
+        assert!(matches!(rejection, BotRejection::InvalidFormToken));
+        let stale = protection
+            .form_tokens
+            .as_ref()
+            .unwrap()
+            .signer
+            .issue(Utc::now() - Duration::hours(2));
+        let rejection = assert_err!(protection.check(&submission(Some(&stale), Some("pass"))).await);
+        assert!(matches!(rejection, BotRejection::ExpiredFormToken));
+    }
+
+    #[tokio::test]
+    async fn a_wrong_captcha_answer_is_rejected() {
+        let protection = protection(0);
+        let token = protection.issue_form_token().unwrap();
+        let rejection = assert_err!(protection.check(&submission(Some(&token), Some("fail"))).await);
+        assert!(matches!(rejection, BotRejection::CaptchaFailed));
+        let rejection = assert_err!(protection.check(&submission(Some(&token), None)).await);
+        assert!(matches!(rejection, BotRejection::CaptchaFailed));
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+//what the health endpoint reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("The circuit breaker is open, the call was not attempted.")]
+pub struct CircuitOpen;
+
+//closed: calls go through, consecutive failures are counted
+//open: calls fail fast until the cool-down has elapsed
+//half-open: a single trial call goes through, its outcome closes or reopens the circuit
+pub struct CircuitBreaker {
+    name: &'static str,
+    failure_threshold: u32,
+    cool_down: Duration,
+    state: Mutex<State>,
+}
+
+enum State {
+    Closed { consecutive_failures: u32 },
+    Open { until: Instant },
+    HalfOpen { trial_in_flight: bool },
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, failure_threshold: u32, cool_down: Duration) -> Self {
+        Self {
+            name,
+            failure_threshold: failure_threshold.max(1),
+            cool_down,
+            state: Mutex::new(State::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => CircuitState::Closed,
+            //the next call will be let through as a trial
+            State::Open { until } if Instant::now() >= until => CircuitState::HalfOpen,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    //the permit reports how the call went, see CircuitPermit
+    pub fn try_acquire(&self) -> Result<CircuitPermit<'_>, CircuitOpen> {
+        let mut state = self.state.lock().unwrap();
+        //built only when granted, dropping one here would re-take the lock
+        let permit = |trial| CircuitPermit {
+            breaker: self,
+            trial,
+            done: false,
+        };
+        match *state {
+            State::Closed { .. } => Ok(permit(false)),
+            State::Open { until } if Instant::now() >= until => {
+                tracing::info!(circuit_breaker = self.name, "Circuit breaker is half-open, trying a call.");
+                *state = State::HalfOpen {
+                    trial_in_flight: true,
+                };
+                Ok(permit(true))
+            }
+            State::Open { .. } => Err(CircuitOpen),
+            State::HalfOpen {
+                trial_in_flight: true,
+            } => Err(CircuitOpen),
+            State::HalfOpen {
+                trial_in_flight: false,
+            } => {
+                *state = State::HalfOpen {
+                    trial_in_flight: true,
+                };
+                Ok(permit(true))
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            tracing::info!(circuit_breaker = self.name, "Circuit breaker is closed again.");
+        }
+        *state = State::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let consecutive_failures = match *state {
+            State::Closed {
+                consecutive_failures,
+            } => consecutive_failures + 1,
+            //the trial failed, straight back to open
+            State::HalfOpen { .. } => self.failure_threshold,
+            State::Open { .. } => return,
+        };
+        if consecutive_failures >= self.failure_threshold {
+            tracing::warn!(
+                circuit_breaker = self.name,
+                consecutive_failures,
+                cool_down_milliseconds = self.cool_down.as_millis() as u64,
+                "Circuit breaker is open, calls will fail fast.",
+            );
+            *state = State::Open {
+                until: Instant::now() + self.cool_down,
+            };
+        } else {
+            *state = State::Closed {
+                consecutive_failures,
+            };
+        }
+    }
+}
+
+//a call the breaker let through, to be resolved with succeeded or failed.
+//Dropped without either, e.g. because the caller's future was cancelled,
+//it says nothing about the provider and is not counted. Except for the
+//half-open trial: it counts as failed, or the circuit would stay half-open for good
+#[must_use]
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+    done: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub fn succeeded(mut self) {
+        self.done = true;
+        self.breaker.record_success();
+    }
+
+    pub fn failed(mut self) {
+        self.done = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl std::fmt::Debug for CircuitPermit<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CircuitPermit")
+            .field("breaker", &self.breaker.name)
+            .finish()
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if !self.done && self.trial {
+            self.breaker.record_failure();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState, State};
+    use claims::{assert_err, assert_ok};
+    use std::time::Duration;
+
+    #[test]
+    fn the_circuit_opens_after_the_failure_threshold() {
+        let breaker = CircuitBreaker::new("test", 3, Duration::from_secs(60));
+        for _ in 0..2 {
+            assert_ok!(breaker.try_acquire()).failed();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_err!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new("test", 2, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn a_single_trial_is_let_through_after_the_cool_down() {
+        let breaker = CircuitBreaker::new("test", 1, Duration::from_millis(10));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        let trial = assert_ok!(breaker.try_acquire());
+        //the trial is still running
+        assert_err!(breaker.try_acquire());
+        trial.succeeded();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_ok!(breaker.try_acquire()).succeeded();
+    }
+
+    #[test]
+    fn a_failed_trial_reopens_the_circuit() {
+        let breaker = CircuitBreaker::new("test", 3, Duration::from_millis(10));
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        assert_ok!(breaker.try_acquire()).failed();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_err!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn an_abandoned_trial_does_not_keep_the_circuit_half_open() {
+        let breaker = CircuitBreaker::new("test", 1, Duration::from_millis(10));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+        //e.g. the future making the call was dropped
+        drop(assert_ok!(breaker.try_acquire()));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_ok!(breaker.try_acquire()).succeeded();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn a_call_abandoned_while_closed_is_not_counted() {
+        let breaker = CircuitBreaker::new("test", 2, Duration::from_secs(60));
+        breaker.record_failure();
+        drop(assert_ok!(breaker.try_acquire()));
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            State::Closed {
+                consecutive_failures: 1
+            }
+        ));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use sqlx::PgPool;
+
+//how often the stale rows are swept
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+//expired tokens are kept around for a while so their links keep answering
+//"expired" instead of "unknown", after that they are just noise
+const EXPIRED_TOKEN_GRACE_DAYS: i32 = 7;
+const SENT_OUTBOX_RETENTION_DAYS: i32 = 7;
+//clients retry within minutes, a saved response is of no use after that
+const IDEMPOTENCY_KEY_RETENTION_DAYS: i32 = 1;
+
+//loops forever, the only way out is the runtime shutting down
+pub async fn run_cleanup_until_stopped(pool: PgPool) -> Result<(), anyhow::Error> {
+    loop {
+        //errors are already logged by the span, try again on the next tick
+        let _ = delete_stale_rows(&pool).await;
+        tokio::time::sleep(CLEANUP_INTERVAL).await;
+    }
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        subscription_tokens = tracing::field::Empty,
+        password_reset_tokens = tracing::field::Empty,
+        sessions = tracing::field::Empty,
+        rate_limit_buckets = tracing::field::Empty,
+        outbox = tracing::field::Empty,
+        idempotency_keys = tracing::field::Empty
+    ),
+    err
+)]
+pub async fn delete_stale_rows(pool: &PgPool) -> Result<(), anyhow::Error> {
+    let subscription_tokens = sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE expires_at < now() - make_interval(days => $1)
+        "#,
+        EXPIRED_TOKEN_GRACE_DAYS
+    )
+    .execute(pool)
+    .await
+    .context("Failed to delete stale subscription tokens.")?
+    .rows_affected();
+    let password_reset_tokens = sqlx::query!(
+        r#"
+        DELETE FROM password_reset_tokens
+        WHERE expires_at < now() - make_interval(days => $1)
+        "#,
+        EXPIRED_TOKEN_GRACE_DAYS
+    )
+    .execute(pool)
+    .await
+    .context("Failed to delete stale password reset tokens.")?
+    .rows_affected();
+    //the session store already ignores these on load
+    let sessions = sqlx::query!("DELETE FROM sessions WHERE expires_at <= now()")
+        .execute(pool)
+        .await
+        .context("Failed to delete expired sessions.")?
+        .rows_affected();
+    //a day without requests refills any of our buckets, dropping the row
+    //just starts the next request from a full bucket as well
+    let rate_limit_buckets = sqlx::query!(
+        "DELETE FROM rate_limit_buckets WHERE updated_at < now() - interval '1 day'"
+    )
+    .execute(pool)
+    .await
+    .context("Failed to delete idle rate limit buckets.")?
+    .rows_affected();
+    //kept for a while so "did we send it?" can be answered from the table
+    let outbox = sqlx::query!(
+        r#"
+        DELETE FROM outbox
+        WHERE sent_at < now() - make_interval(days => $1)
+        "#,
+        SENT_OUTBOX_RETENTION_DAYS
+    )
+    .execute(pool)
+    .await
+    .context("Failed to delete sent outbox emails.")?
+    .rows_affected();
+    let idempotency_keys = sqlx::query!(
+        r#"
+        DELETE FROM idempotency
+        WHERE created_at < now() - make_interval(days => $1)
+        "#,
+        IDEMPOTENCY_KEY_RETENTION_DAYS
+    )
+    .execute(pool)
+    .await
+    .context("Failed to delete expired idempotency keys.")?
+    .rows_affected();
+    tracing::Span::current()
+        .record("subscription_tokens", subscription_tokens)
+        .record("password_reset_tokens", password_reset_tokens)
+        .record("sessions", sessions)
+        .record("rate_limit_buckets", rate_limit_buckets)
+        .record("outbox", outbox)
+        .record("idempotency_keys", idempotency_keys);
+    Ok(())
+}
@@ -7,11 +7,24 @@ use serde::Deserialize;
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
-    ConnectOptions,
+    ConnectOptions, PgPool,
 };
 use std::convert::From;
 
 use crate::domain::SubscriberEmail;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::email_client::{EmailClient, RetryPolicy};
+use crate::bot_protection::{
+    BotProtection, CaptchaVerifier, FakeCaptchaVerifier, FormTokenSigner, FormTokens,
+    HCaptchaVerifier,
+};
+use crate::rate_limit::{
+    InMemoryRateLimiter, PasswordResetRateLimits, PostgresRateLimiter, RateLimiter,
+    SubscribeRateLimits, TokenBucket,
+};
+use crate::email_templates::{EmailTemplates, TemplateError};
+use crate::email_transport::{EmailTransport, FileEmailTransport, SmtpEmailTransport};
+use std::sync::Arc;
 //name of fields should match 1:1 with yaml,
 //application_port , database
 #[derive(Deserialize,Clone)]
@@ -19,21 +32,268 @@ pub struct Settings {
     pub application: ApplicationSettings,
     pub db_settings: DatabaseSettings,
     pub email_client : EmailClientSettings,
+    pub email_templates: EmailTemplateSettings,
+    pub rate_limit: RateLimitSettings,
+    pub bot_protection: BotProtectionSettings,
+}
+#[derive(Deserialize,Clone)]
+pub struct BotProtectionSettings {
+    pub form_token: FormTokenSettings,
+    pub captcha: CaptchaSettings,
+}
+#[derive(Deserialize,Clone)]
+pub struct FormTokenSettings {
+    //the subscription form must carry a token issued by GET /subscriptions
+    pub enabled: bool,
+    //submissions faster than this are treated as scripted
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_fill_seconds: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_age_minutes: i64,
+}
+#[derive(Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    None,
+    Hcaptcha,
+    //accepts `fake_response`, never use outside of development and tests
+    Fake,
+}
+#[derive(Deserialize,Clone)]
+pub struct CaptchaSettings {
+    pub provider: CaptchaProvider,
+    pub verify_url: String,
+    pub site_key: String,
+    pub secret: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_milliseconds: u64,
+    pub fake_response: String,
+}
+impl BotProtectionSettings {
+    //form tokens are signed with the application's hmac secret
+    pub fn bot_protection(&self, hmac_secret: &Secret<String>) -> BotProtection {
+        let form_tokens = self.form_token.enabled.then(|| FormTokens {
+            signer: FormTokenSigner::new(hmac_secret.clone()),
+            min_fill_time: chrono::Duration::seconds(self.form_token.min_fill_seconds),
+            max_age: chrono::Duration::minutes(self.form_token.max_age_minutes),
+        });
+        let captcha: Option<Arc<dyn CaptchaVerifier>> = match self.captcha.provider {
+            CaptchaProvider::None => None,
+            CaptchaProvider::Hcaptcha => Some(Arc::new(HCaptchaVerifier::new(
+                self.captcha.verify_url.clone(),
+                self.captcha.site_key.clone(),
+                self.captcha.secret.clone(),
+                std::time::Duration::from_millis(self.captcha.timeout_milliseconds),
+            ))),
+            CaptchaProvider::Fake => Some(Arc::new(FakeCaptchaVerifier::new(
+                self.captcha.fake_response.clone(),
+            ))),
+        };
+        BotProtection {
+            form_tokens,
+            captcha,
+        }
+    }
+}
+#[derive(Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    Memory,
+    Postgres,
+}
+#[derive(Deserialize,Clone)]
+pub struct RateLimitSettings {
+    //memory counts per instance, postgres shares the counters between instances
+    pub backend: RateLimitBackend,
+    //peers allowed to tell us the client ip through X-Forwarded-For
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+    //subscription form submissions per client ip
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscribe_burst: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscribe_per_minute: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub confirmation_emails_per_address_per_day: u32,
+    //password reset form submissions per client ip
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub password_reset_burst: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub password_reset_per_minute: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub password_reset_emails_per_address_per_day: u32,
+}
+impl RateLimitSettings {
+    fn limiter(&self, pool: PgPool) -> Arc<dyn RateLimiter> {
+        match self.backend {
+            RateLimitBackend::Memory => Arc::new(InMemoryRateLimiter::new()),
+            RateLimitBackend::Postgres => Arc::new(PostgresRateLimiter::new(pool)),
+        }
+    }
+    pub fn subscribe_limits(&self, pool: PgPool) -> SubscribeRateLimits {
+        let limiter = self.limiter(pool);
+        let per_day = self.confirmation_emails_per_address_per_day.max(1);
+        SubscribeRateLimits {
+            limiter,
+            per_ip: TokenBucket {
+                capacity: self.subscribe_burst.max(1),
+                refill_interval: std::time::Duration::from_secs(60)
+                    / self.subscribe_per_minute.max(1),
+            },
+            //the whole daily allowance is available at once, then drips back over the day
+            per_address: TokenBucket {
+                capacity: per_day,
+                refill_interval: std::time::Duration::from_secs(24 * 60 * 60) / per_day,
+            },
+            trusted_proxies: self.trusted_proxies.clone(),
+        }
+    }
+    pub fn password_reset_limits(&self, pool: PgPool) -> PasswordResetRateLimits {
+        let per_day = self.password_reset_emails_per_address_per_day.max(1);
+        PasswordResetRateLimits {
+            limiter: self.limiter(pool),
+            per_ip: TokenBucket {
+                capacity: self.password_reset_burst.max(1),
+                refill_interval: std::time::Duration::from_secs(60)
+                    / self.password_reset_per_minute.max(1),
+            },
+            per_address: TokenBucket {
+                capacity: per_day,
+                refill_interval: std::time::Duration::from_secs(24 * 60 * 60) / per_day,
+            },
+            trusted_proxies: self.trusted_proxies.clone(),
+        }
+    }
+}
+#[derive(Deserialize,Clone)]
+pub struct EmailTemplateSettings {
+    //files in here override the compiled-in templates of the same name,
+    //unset to use the defaults only
+    pub directory: Option<String>,
+}
+impl EmailTemplateSettings {
+    pub fn templates(&self) -> Result<EmailTemplates, TemplateError> {
+        EmailTemplates::load(self.directory.as_deref().map(std::path::Path::new))
+    }
 }
 #[derive(Deserialize,Clone)]
 
 pub struct EmailClientSettings {
+    //which backend sends the emails, the remaining fields configure them
+    pub transport : EmailTransportKind,
+    //postmark
     pub base_url : String,
     pub sender_email : String,
     pub authorization_token : Secret<String>,
     pub timeout_milliseconds : u64,
+    pub retry: EmailRetrySettings,
+    pub circuit_breaker: CircuitBreakerSettings,
+    pub smtp: SmtpSettings,
+    //where the file transport drops its .eml files
+    pub file_directory: String,
+    //shared with the provider, sent back on every bounce/complaint webhook
+    pub webhook_secret: Secret<String>,
+}
+#[derive(Deserialize,Clone)]
+pub struct CircuitBreakerSettings {
+    //consecutive transient failures before calls start failing fast
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_threshold: u32,
+    //how long the circuit stays open before a trial call is let through
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cool_down_milliseconds: u64,
+}
+impl CircuitBreakerSettings {
+    pub fn circuit_breaker(&self, name: &'static str) -> CircuitBreaker {
+        CircuitBreaker::new(
+            name,
+            self.failure_threshold,
+            std::time::Duration::from_millis(self.cool_down_milliseconds),
+        )
+    }
+}
+#[derive(Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTransportKind {
+    Postmark,
+    Smtp,
+    File,
+}
+#[derive(Deserialize,Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    //leave empty for relays without authentication
+    pub username: String,
+    pub password: Secret<String>,
+    pub starttls: bool,
+}
+#[derive(Deserialize,Clone)]
+pub struct EmailRetrySettings {
+    //including the first attempt, 1 disables retries
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub jitter_milliseconds: u64,
+}
+impl EmailRetrySettings {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.max(1),
+            base_delay: std::time::Duration::from_millis(self.base_delay_milliseconds),
+            max_delay: std::time::Duration::from_millis(self.max_delay_milliseconds),
+            jitter: std::time::Duration::from_millis(self.jitter_milliseconds),
+        }
+    }
 }
 impl EmailClientSettings {
     pub fn sender(&self) -> Result<SubscriberEmail,String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
     pub fn timeout(&self)-> std::time::Duration {
-        std::time::Duration::from_secs(self.timeout_milliseconds)
+        std::time::Duration::from_millis(self.timeout_milliseconds)
+    }
+    //built once and shared by the api and the delivery worker,
+    //so the circuit breaker sees all the traffic
+    pub fn transport(self) -> Arc<dyn EmailTransport> {
+        let sender_email = self.sender().expect("invalid sender email");
+        match self.transport {
+            EmailTransportKind::Postmark => Arc::new(self.client()),
+            EmailTransportKind::Smtp => Arc::new(
+                SmtpEmailTransport::new(
+                    sender_email,
+                    &self.smtp.host,
+                    self.smtp.port,
+                    &self.smtp.username,
+                    self.smtp.password.clone(),
+                    self.smtp.starttls,
+                    self.timeout(),
+                )
+                .expect("invalid smtp settings"),
+            ),
+            EmailTransportKind::File => Arc::new(
+                FileEmailTransport::new(sender_email, &self.file_directory)
+                    .expect("failed to create the email directory"),
+            ),
+        }
+    }
+    pub fn client(self) -> EmailClient {
+        let sender_email = self.sender().expect("invalid sender email");
+        let timeout = self.timeout();
+        let retry_policy = self.retry.policy();
+        let circuit_breaker = self.circuit_breaker.circuit_breaker("postmark");
+        EmailClient::new(
+            self.base_url,
+            sender_email,
+            self.authorization_token,
+            timeout,
+            retry_policy,
+            circuit_breaker,
+        )
     }
 }
 #[derive(Deserialize,Clone)]
@@ -53,7 +313,9 @@ pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
-    pub base_url : String
+    pub base_url : String,
+    //signs the session and flash message cookies, at least 64 bytes
+    pub hmac_secret: Secret<String>,
 }
 pub enum Environment {
     Local,
@@ -124,7 +386,7 @@ impl DatabaseSettings {
         PgConnectOptions::new()
             .host(&self.host)
             .username(&self.username)
-            .password(&self.password.expose_secret())
+            .password(self.password.expose_secret())
             .port(self.port)
             .ssl_mode(ssl_mode)
     }
@@ -0,0 +1,185 @@
+use std::string::FromUtf8Error;
+
+pub type CsvRecord = Result<Vec<String>, FromUtf8Error>;
+
+//the buffers grow with the record, this keeps a single endless record
+//from taking all the memory
+pub const MAX_RECORD_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+#[error("A record is longer than {0} bytes.")]
+pub struct RecordTooLong(pub usize);
+
+//push based csv parsing, the body is fed chunk by chunk as it arrives
+//instead of being buffered so that csv::Reader can pull from it
+pub struct CsvRecordParser {
+    reader: csv_core::Reader,
+    //the record being parsed, it can span several chunks
+    output: Vec<u8>,
+    ends: Vec<usize>,
+    output_len: usize,
+    ends_len: usize,
+    //input bytes of the record being parsed, they bound both buffers
+    record_len: usize,
+    max_record_len: usize,
+}
+
+impl Default for CsvRecordParser {
+    fn default() -> Self {
+        Self::with_max_record_len(MAX_RECORD_LEN)
+    }
+}
+
+impl CsvRecordParser {
+    pub fn with_max_record_len(max_record_len: usize) -> Self {
+        Self {
+            reader: csv_core::Reader::new(),
+            output: vec![0; 1024],
+            ends: vec![0; 16],
+            output_len: 0,
+            ends_len: 0,
+            record_len: 0,
+            max_record_len,
+        }
+    }
+
+    //the records completed by this chunk, a record cut at the end of the
+    //chunk is returned by a later call.
+    //once a record is too long the rest of the body cannot be parsed
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<CsvRecord>, RecordTooLong> {
+        let mut records = Vec::new();
+        let mut input = chunk;
+        loop {
+            match self.read(input)? {
+                (Some(record), consumed) => {
+                    records.push(record);
+                    input = &input[consumed..];
+                }
+                (None, _) => return Ok(records),
+            }
+        }
+    }
+
+    //flushes a last record without a trailing newline
+    pub fn finish(&mut self) -> Result<Option<CsvRecord>, RecordTooLong> {
+        Ok(self.read(&[])?.0)
+    }
+
+    //an empty input tells csv-core the body is over
+    fn read(&mut self, mut input: &[u8]) -> Result<(Option<CsvRecord>, usize), RecordTooLong> {
+        use csv_core::ReadRecordResult::*;
+        let mut consumed = 0;
+        loop {
+            let (result, nin, nout, nend) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[nin..];
+            consumed += nin;
+            self.record_len += nin;
+            self.output_len += nout;
+            self.ends_len += nend;
+            if self.record_len > self.max_record_len {
+                return Err(RecordTooLong(self.max_record_len));
+            }
+            match result {
+                InputEmpty | End => return Ok((None, consumed)),
+                OutputFull => self.output.resize(self.output.len() * 2, 0),
+                OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+                Record => return Ok((Some(self.take_record()), consumed)),
+            }
+        }
+    }
+
+    fn take_record(&mut self) -> CsvRecord {
+        let mut start = 0;
+        let fields = self.ends[..self.ends_len]
+            .iter()
+            .map(|&end| {
+                let field = String::from_utf8(self.output[start..end].to_vec());
+                start = end;
+                field
+            })
+            .collect();
+        self.output_len = 0;
+        self.ends_len = 0;
+        self.record_len = 0;
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CsvRecordParser, RecordTooLong};
+
+    fn parse_in_chunks(input: &str, chunk_size: usize) -> Vec<Vec<String>> {
+        let mut parser = CsvRecordParser::default();
+        let mut records: Vec<_> = input
+            .as_bytes()
+            .chunks(chunk_size)
+            .flat_map(|chunk| parser.feed(chunk).unwrap())
+            .collect();
+        records.extend(parser.finish().unwrap());
+        records.into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn records_split_across_chunks_are_put_back_together() {
+        let input = "email,name\nursula@gmail.com,\"Le Guin, Ursula\"\noctavia@gmail.com,Octavia\n";
+        let expected = vec![
+            vec!["email", "name"],
+            vec!["ursula@gmail.com", "Le Guin, Ursula"],
+            vec!["octavia@gmail.com", "Octavia"],
+        ];
+        for chunk_size in [1, 2, 3, 7, 1024] {
+            assert_eq!(parse_in_chunks(input, chunk_size), expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn the_last_record_does_not_need_a_trailing_newline() {
+        assert_eq!(
+            parse_in_chunks("email,name\r\nursula@gmail.com,Ursula", 4),
+            vec![vec!["email", "name"], vec!["ursula@gmail.com", "Ursula"]]
+        );
+    }
+
+    #[test]
+    fn long_fields_grow_the_buffers() {
+        let name = "a".repeat(5000);
+        let input = format!("{},{},{}\n", name, name, "b,".repeat(40));
+        let records = parse_in_chunks(&input, 100);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0][0], name);
+        assert_eq!(records[0].len(), 43);
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_per_record() {
+        let mut parser = CsvRecordParser::default();
+        let records = parser.feed(b"ok\n\xff\xfe\nok\n").unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records[0].is_ok());
+        assert!(records[1].is_err());
+        assert!(records[2].is_ok());
+    }
+
+    #[test]
+    fn records_longer_than_the_limit_are_an_error() {
+        let mut parser = CsvRecordParser::with_max_record_len(100);
+        let records = parser.feed(&[b'a'; 100]).unwrap();
+        assert!(records.is_empty());
+        assert!(matches!(parser.feed(b"a"), Err(RecordTooLong(100))));
+    }
+
+    #[test]
+    fn the_limit_applies_to_each_record_on_its_own() {
+        let mut parser = CsvRecordParser::with_max_record_len(10);
+        for _ in 0..5 {
+            assert_eq!(parser.feed(b"abc,defg\n").unwrap().len(), 1);
+        }
+        //empty fields do not add output, but they still count
+        assert!(parser.feed(&[b','; 11]).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    domain::SubscriberEmail,
+    outbox::{enqueue_email, OutboxEmail},
+    suppression::is_suppressed,
+};
+
+//what failed, with enough of it stored to put it back in its queue
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum DeadLetterPayload {
+    NewsletterIssue {
+        newsletter_issue_id: Uuid,
+    },
+    Outbox {
+        kind: String,
+        subject: String,
+        html_content: String,
+        text_content: String,
+    },
+}
+
+impl DeadLetterPayload {
+    pub fn source(&self) -> &'static str {
+        match self {
+            DeadLetterPayload::NewsletterIssue { .. } => "newsletter_issue",
+            DeadLetterPayload::Outbox { .. } => "outbox",
+        }
+    }
+}
+
+//the list view leaves out the payload, outbox payloads carry whole emails
+#[derive(serde::Serialize)]
+pub struct DeadLetterSummary {
+    pub dead_letter_id: Uuid,
+    pub source: String,
+    pub recipient: String,
+    pub attempts: i32,
+    pub last_error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct DeadLetter {
+    pub dead_letter_id: Uuid,
+    pub source: String,
+    pub recipient: String,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub last_error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+//called with the transaction that removes the email from its queue, so it
+//is either retried or dead-lettered, never both or neither
+#[tracing::instrument(
+    name = "Move an email to the dead-letter queue",
+    skip(transaction, payload),
+    fields(source = payload.source())
+)]
+pub async fn store_dead_letter(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &str,
+    payload: &DeadLetterPayload,
+    attempts: i32,
+    last_error: &str,
+) -> Result<Uuid, anyhow::Error> {
+    let dead_letter_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO dead_letters (
+            dead_letter_id,
+            source,
+            recipient,
+            payload,
+            attempts,
+            last_error,
+            failed_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, now())
+        "#,
+        dead_letter_id,
+        payload.source(),
+        recipient,
+        serde_json::to_value(payload)?,
+        attempts,
+        last_error
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to store a dead letter.")?;
+    //monotonic_counter is picked up as a metric by tracing-opentelemetry
+    tracing::error!(
+        monotonic_counter.dead_letters = 1_u64,
+        %dead_letter_id,
+        source = payload.source(),
+        attempts,
+        last_error,
+        "An email could not be delivered and was moved to the dead-letter queue.",
+    );
+    Ok(dead_letter_id)
+}
+
+//most recent first
+#[tracing::instrument(skip(pool))]
+pub async fn fetch_dead_letters(
+    pool: &PgPool,
+    source: Option<&str>,
+    limit: i64,
+) -> Result<Vec<DeadLetterSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        DeadLetterSummary,
+        r#"
+        SELECT dead_letter_id, source, recipient, attempts, last_error, failed_at
+        FROM dead_letters
+        WHERE $1::text IS NULL OR source = $1
+        ORDER BY failed_at DESC
+        LIMIT $2
+        "#,
+        source,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn fetch_dead_letter(
+    pool: &PgPool,
+    dead_letter_id: Uuid,
+) -> Result<Option<DeadLetter>, sqlx::Error> {
+    sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT dead_letter_id, source, recipient, payload, attempts, last_error, failed_at
+        FROM dead_letters
+        WHERE dead_letter_id = $1
+        "#,
+        dead_letter_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+//puts the email back in the queue it came from with a fresh attempt count,
+//false if there is no such dead letter
+#[tracing::instrument(skip(pool))]
+pub async fn requeue(pool: &PgPool, dead_letter_id: Uuid) -> Result<bool, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let Some(dead_letter) = sqlx::query!(
+        r#"
+        DELETE FROM dead_letters
+        WHERE dead_letter_id = $1
+        RETURNING recipient, payload
+        "#,
+        dead_letter_id
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to remove a dead letter.")?
+    else {
+        return Ok(false);
+    };
+    let payload: DeadLetterPayload = serde_json::from_value(dead_letter.payload)
+        .context("Failed to parse the payload of a dead letter.")?;
+    match payload {
+        DeadLetterPayload::NewsletterIssue {
+            newsletter_issue_id,
+        } => {
+            //the subscriber may have left or bounced since, then there is
+            //nobody to deliver to and the dead letter is only removed.
+            //the issue may also already be queued again for them
+            let requeued = sqlx::query!(
+                r#"
+                INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+                SELECT $1, email
+                FROM subscriptions
+                WHERE email = $2
+                    AND status = 'confirmed'
+                    AND NOT EXISTS (
+                        SELECT 1 FROM suppressed_emails WHERE email = lower($2)
+                    )
+                ON CONFLICT DO NOTHING
+                "#,
+                newsletter_issue_id,
+                dead_letter.recipient
+            )
+            .execute(&mut transaction)
+            .await
+            .context("Failed to requeue an issue delivery task.")?
+            .rows_affected();
+            if requeued == 0 {
+                tracing::info!(
+                    "Not requeueing an issue delivery, the recipient is no longer a confirmed subscriber."
+                );
+            }
+        }
+        DeadLetterPayload::Outbox {
+            kind,
+            subject,
+            html_content,
+            text_content,
+        } => {
+            //like an issue, nothing goes back out to an address that bounced or complained
+            if is_suppressed(&mut transaction, &dead_letter.recipient)
+                .await
+                .context("Failed to check the suppression list.")?
+            {
+                tracing::info!("Not requeueing an outbox email, the recipient is suppressed.");
+                transaction.commit().await?;
+                return Ok(true);
+            }
+            let recipient = SubscriberEmail::parse(dead_letter.recipient)
+                .map_err(anyhow::Error::msg)
+                .context("The recipient of a dead letter is not a valid email.")?;
+            enqueue_email(
+                &mut transaction,
+                OutboxEmail {
+                    kind: &kind,
+                    recipient: &recipient,
+                    subject: &subject,
+                    html_content: &html_content,
+                    text_content: &text_content,
+                },
+            )
+            .await
+            .context("Failed to requeue an outbox email.")?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(true)
+}
+
+//false if there is no such dead letter
+#[tracing::instrument(skip(pool))]
+pub async fn discard(pool: &PgPool, dead_letter_id: Uuid) -> Result<bool, sqlx::Error> {
+    let discarded = sqlx::query!(
+        r#"DELETE FROM dead_letters WHERE dead_letter_id = $1"#,
+        dead_letter_id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(discarded > 0)
+}
@@ -6,14 +6,18 @@ pub struct NewSubscriber {
     pub email: SubscriberEmail,
 }
 
+impl NewSubscriber {
+    pub fn parse(name: String, email: String) -> Result<Self, String> {
+        let name = SubscriberName::parse(name)?;
+        let email = SubscriberEmail::parse(email)?;
+        Ok(Self { email, name })
+    }
+}
+
 impl TryFrom<FormData> for NewSubscriber {
     type Error = String;
     fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
-
-        
+        Self::parse(value.name, value.email)
     }
 }
 
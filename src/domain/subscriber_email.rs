@@ -1,5 +1,5 @@
 use validator::validate_email;
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SubscriberEmail(String);
 
 impl SubscriberEmail {
@@ -0,0 +1,171 @@
+use sqlx::{
+    error::BoxDynError,
+    postgres::{PgTypeInfo, PgValueRef},
+    Decode, Postgres, Type,
+};
+
+//stored as TEXT, the CHECK constraint on subscriptions.status lists the same values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    Complained,
+}
+
+impl SubscriptionStatus {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "pending_confirmation" => Ok(Self::PendingConfirmation),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            "bounced" => Ok(Self::Bounced),
+            "complained" => Ok(Self::Complained),
+            other => Err(format!("{} is not a valid subscription status", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PendingConfirmation => "pending_confirmation",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::Bounced => "bounced",
+            Self::Complained => "complained",
+        }
+    }
+
+    //the only place that decides which changes are allowed,
+    //moving to the current status is a no-op and always fine
+    pub fn transition_to(self, next: Self) -> Result<Self, IllegalStatusTransition> {
+        use SubscriptionStatus::*;
+        match (self, next) {
+            (current, next) if current == next => Ok(next),
+            (PendingConfirmation, Confirmed)
+            | (Confirmed, Unsubscribed)
+            //e.g. someone who subscribed again clicks the link in an older issue
+            | (PendingConfirmation, Unsubscribed)
+            //subscribing again starts over with a fresh confirmation link
+            | (Unsubscribed, PendingConfirmation)
+            //the email provider has the last word
+            | (_, Bounced)
+            | (_, Complained) => Ok(next),
+            (from, to) => Err(IllegalStatusTransition { from, to }),
+        }
+    }
+
+    //a new address has to confirm on its own. Bounces and complaints are
+    //about the person as much as the address, they stay as they are
+    pub fn reconfirm_new_email(self) -> Result<Self, IllegalStatusTransition> {
+        use SubscriptionStatus::*;
+        match self {
+            PendingConfirmation | Confirmed | Unsubscribed => Ok(PendingConfirmation),
+            from @ (Bounced | Complained) => Err(IllegalStatusTransition {
+                from,
+                to: PendingConfirmation,
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for SubscriptionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("A subscription cannot go from {from} to {to}.")]
+pub struct IllegalStatusTransition {
+    pub from: SubscriptionStatus,
+    pub to: SubscriptionStatus,
+}
+
+//lets queries read the column straight into the enum with `status AS "status: SubscriptionStatus"`
+impl Type<Postgres> for SubscriptionStatus {
+    fn type_info() -> PgTypeInfo {
+        <&str as Type<Postgres>>::type_info()
+    }
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <&str as Type<Postgres>>::compatible(ty)
+    }
+}
+impl<'r> Decode<'r, Postgres> for SubscriptionStatus {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let status = <&str as Decode<'r, Postgres>>::decode(value)?;
+        Ok(Self::parse(status)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionStatus::{self, *};
+    use claims::{assert_err, assert_ok};
+
+    const ALL: [SubscriptionStatus; 5] =
+        [PendingConfirmation, Confirmed, Unsubscribed, Bounced, Complained];
+
+    #[test]
+    fn every_status_round_trips_through_its_string_form() {
+        for status in ALL {
+            assert_eq!(SubscriptionStatus::parse(status.as_str()), Ok(status));
+        }
+    }
+
+    #[test]
+    fn unknown_statuses_are_rejected() {
+        assert_err!(SubscriptionStatus::parse("Confirmed"));
+        assert_err!(SubscriptionStatus::parse(""));
+    }
+
+    #[test]
+    fn the_subscription_lifecycle_is_allowed() {
+        assert_ok!(PendingConfirmation.transition_to(Confirmed));
+        assert_ok!(Confirmed.transition_to(Unsubscribed));
+        assert_ok!(Unsubscribed.transition_to(PendingConfirmation));
+        assert_ok!(PendingConfirmation.transition_to(Unsubscribed));
+    }
+
+    #[test]
+    fn any_status_can_bounce_or_complain() {
+        for status in ALL {
+            assert_ok!(status.transition_to(Bounced));
+            assert_ok!(status.transition_to(Complained));
+        }
+    }
+
+    #[test]
+    fn staying_in_the_same_status_is_allowed() {
+        for status in ALL {
+            assert_eq!(status.transition_to(status), Ok(status));
+        }
+    }
+
+    #[test]
+    fn a_new_email_is_confirmed_again_unless_the_subscriber_bounced_or_complained() {
+        for status in [PendingConfirmation, Confirmed, Unsubscribed] {
+            assert_eq!(status.reconfirm_new_email(), Ok(PendingConfirmation));
+        }
+        for status in [Bounced, Complained] {
+            assert_err!(status.reconfirm_new_email());
+        }
+    }
+
+    #[test]
+    fn skipping_or_reversing_steps_is_rejected() {
+        let illegal = [
+            (Confirmed, PendingConfirmation),
+            (Unsubscribed, Confirmed),
+            (Bounced, PendingConfirmation),
+            (Bounced, Confirmed),
+            (Complained, Unsubscribed),
+        ];
+        for (from, to) in illegal {
+            let error = from.transition_to(to).unwrap_err();
+            assert_eq!(error.from, from);
+            assert_eq!(error.to, to);
+        }
+    }
+}
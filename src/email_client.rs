@@ -1,47 +1,337 @@
-use std::time;
+use std::time::Duration;
 
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
 use crate::domain::SubscriberEmail;
+use crate::email_transport::{
+    BatchEmail, BatchFailure, BatchOutcome, EmailClientError, EmailHeader, EmailTransport,
+    SentEmail,
+};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, StatusCode};
 use secrecy::{ExposeSecret, Secret};
+use tracing::Instrument;
 pub struct EmailClient {
     sender: SubscriberEmail,
     http_client: reqwest::Client,
     base_url: String,
     authorization_token: Secret<String>,
+    retry_policy: RetryPolicy,
+    //stops us from tying up workers on timeouts while Postmark is down
+    circuit_breaker: CircuitBreaker,
 }
 
-impl EmailClient {
-    pub async fn send_email(
+//how hard send_email tries before giving up on a transient failure
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    //including the first one, 1 means no retries
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    //up to this much is added at random to each delay
+    pub jitter: Duration,
+}
+impl RetryPolicy {
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+        }
+    }
+    //base_delay * 2^(attempt - 1) capped at max_delay. A Retry-After from the
+    //server is waited out in full, None when it is longer than max_delay: better
+    //to give up and let the caller's queue come back later than to call early
+    pub fn delay_after(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        let delay = match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => return None,
+            Some(retry_after) => retry_after,
+            None => self
+                .base_delay
+                .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+                .min(self.max_delay),
+        };
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        };
+        Some(delay + jitter)
+    }
+}
+
+//either a number of seconds or an HTTP date, a date in the past means now
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+//Postmark error codes that are about the recipient rather than about us
+//300: invalid email request, 406: inactive recipient (bounced, complained or unsubscribed)
+const RECIPIENT_ERROR_CODES: [i64; 2] = [300, 406];
+
+//Postmark over http, the production transport
+#[async_trait::async_trait]
+impl EmailTransport for EmailClient {
+    async fn send_email_with_headers(
         &self,
         recipient_address: SubscriberEmail,
         subject_line: &str,
         html_email_content: &str,
         plain_text_content: &str,
-    ) -> Result<(), reqwest::Error> {
+        headers: &[EmailHeader<'_>],
+    ) -> Result<SentEmail, EmailClientError> {
         let url = format!("{}/email", self.base_url);
+        let headers: Vec<_> = headers.iter().map(PostmarkHeader::from).collect();
         let request_body = SendEmailRequest {
             from: self.sender.as_ref(),
             to: recipient_address.as_ref(),
             subject: subject_line,
             html_body: html_email_content,
             text_body: plain_text_content,
+            headers: &headers,
+        };
+        let response = self.post_with_retries(&url, &request_body).await?;
+        //the email is on its way, failing here would only get it sent twice on retry
+        match response.json::<PostmarkSendResult>().await {
+            Ok(result) => Ok(result.into_sent_email()),
+            Err(e) => {
+                tracing::warn!(error.message = %e, "Could not parse the Postmark response.");
+                Ok(SentEmail {
+                    message_id: None,
+                    submitted_at: Utc::now(),
+                })
+            }
+        }
+    }
+    //chunks the emails into /email/batch calls, a failed chunk does not stop
+    //the following ones, callers retry whatever did not come back Ok
+    #[tracing::instrument(name = "Send a batch of emails", skip_all, fields(emails = emails.len()))]
+    async fn send_batch(&self, emails: &[BatchEmail<'_>]) -> Vec<BatchOutcome> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for chunk in emails.chunks(POSTMARK_BATCH_LIMIT) {
+            outcomes.extend(self.send_batch_chunk(chunk).await);
+        }
+        outcomes
+    }
+    fn circuit_state(&self) -> Option<CircuitState> {
+        Some(self.circuit_breaker.state())
+    }
+}
+
+//Postmark accepts at most this many messages per /email/batch call
+pub const POSTMARK_BATCH_LIMIT: usize = 500;
+
+//the body Postmark answers with, for a single send and for each element of a batch
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkSendResult {
+    error_code: i64,
+    message: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+    submitted_at: Option<String>,
+}
+impl PostmarkSendResult {
+    fn into_sent_email(self) -> SentEmail {
+        let submitted_at = self
+            .submitted_at
+            .as_deref()
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            .map(|t| t.with_timezone(&Utc))
+            //only there for successful sends, fall back to our own clock
+            .unwrap_or_else(Utc::now);
+        SentEmail {
+            message_id: self.message_id,
+            submitted_at,
+        }
+    }
+}
+
+impl EmailClient {
+    async fn send_batch_chunk(&self, chunk: &[BatchEmail<'_>]) -> Vec<BatchOutcome> {
+        let url = format!("{}/email/batch", self.base_url);
+        let headers: Vec<Vec<_>> = chunk
+            .iter()
+            .map(|email| email.headers.iter().map(PostmarkHeader::from).collect())
+            .collect();
+        let request_body: Vec<_> = chunk
+            .iter()
+            .zip(&headers)
+            .map(|(email, headers)| SendEmailRequest {
+                from: self.sender.as_ref(),
+                to: email.recipient.as_ref(),
+                subject: email.subject,
+                html_body: email.html_content,
+                text_body: email.text_content,
+                headers,
+            })
+            .collect();
+        let results = match self.post_with_retries(&url, &request_body).await {
+            Ok(response) => response
+                .json::<Vec<PostmarkSendResult>>()
+                .await
+                .map_err(|e| EmailClientError::Unexpected(e.into())),
+            Err(e) => Err(e),
         };
+        let results = match results {
+            Ok(results) if results.len() == chunk.len() => results,
+            Ok(results) => {
+                let error = format!(
+                    "Postmark answered {} results for {} messages",
+                    results.len(),
+                    chunk.len()
+                );
+                return Self::all_failed(chunk, BatchFailure::RequestFailed(error));
+            }
+            Err(e) => return Self::all_failed(chunk, e.into()),
+        };
+        chunk
+            .iter()
+            .zip(results)
+            .map(|(email, result)| BatchOutcome {
+                recipient: email.recipient.as_ref().to_owned(),
+                result: if result.error_code == 0 {
+                    Ok(result.into_sent_email())
+                } else {
+                    Err(BatchFailure::Rejected {
+                        error_code: result.error_code,
+                        message: result.message,
+                    })
+                },
+            })
+            .collect()
+    }
+    fn all_failed(chunk: &[BatchEmail<'_>], failure: BatchFailure) -> Vec<BatchOutcome> {
+        chunk
+            .iter()
+            .map(|email| BatchOutcome {
+                recipient: email.recipient.as_ref().to_owned(),
+                result: Err(failure.clone()),
+            })
+            .collect()
+    }
+    //goes through the circuit breaker and the retry policy
+    async fn post_with_retries<Body>(
+        &self,
+        url: &str,
+        request_body: &Body,
+    ) -> Result<reqwest::Response, EmailClientError>
+    where
+        Body: serde::Serialize + ?Sized,
+    {
+        let permit = self.circuit_breaker.try_acquire()?;
+        let mut attempt = 1;
+        loop {
+            let span = tracing::info_span!("Email delivery attempt", attempt);
+            let error = match self.attempt_send(url, request_body).instrument(span).await {
+                Ok(response) => {
+                    permit.succeeded();
+                    return Ok(response);
+                }
+                Err(error) => error,
+            };
+            let transient = error.is_transient();
+            let delay = match self.retry_policy.delay_after(attempt, error.retry_after()) {
+                Some(delay) if transient && attempt < self.retry_policy.max_attempts => Some(delay),
+                _ => None,
+            };
+            let Some(delay) = delay else {
+                //a 4xx means Postmark is up and answering, only the transient kind counts
+                if transient {
+                    permit.failed();
+                } else {
+                    permit.succeeded();
+                }
+                return Err(error);
+            };
+            tracing::warn!(
+                error.message = %error,
+                attempt,
+                delay_milliseconds = delay.as_millis() as u64,
+                "Email delivery attempt failed, retrying.",
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+    async fn attempt_send<Body>(
+        &self,
+        url: &str,
+        request_body: &Body,
+    ) -> Result<reqwest::Response, EmailClientError>
+    where
+        Body: serde::Serialize + ?Sized,
+    {
         let builder = self
             .http_client
-            .post(&url)
+            .post(url)
             .header(
                 "X-Postmark-Server-Token",
                 self.authorization_token.expose_secret(),
             )
-            .json(&request_body);
+            .json(request_body);
         //executing req
-        builder.send().await?.error_for_status()?;
-        Ok(())
+        let response = builder.send().await.map_err(|e| {
+            if e.is_timeout() {
+                EmailClientError::Timeout(e.into())
+            } else {
+                EmailClientError::Transport(e.into())
+            }
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now()));
+        //Postmark explains 4xx answers with an ErrorCode and a Message
+        let body: Option<PostmarkSendResult> = response.json().await.ok();
+        Err(match status {
+            StatusCode::UNAUTHORIZED => EmailClientError::Unauthorized(
+                body.map(|b| b.message).unwrap_or_default(),
+            ),
+            StatusCode::TOO_MANY_REQUESTS => EmailClientError::RateLimited { retry_after },
+            status if status.is_server_error() => EmailClientError::ServerError {
+                status: status.as_u16(),
+                retry_after,
+            },
+            status => match body {
+                Some(body) if RECIPIENT_ERROR_CODES.contains(&body.error_code) => {
+                    EmailClientError::RecipientRejected {
+                        error_code: body.error_code,
+                        message: body.message,
+                    }
+                }
+                Some(body) => EmailClientError::Unexpected(anyhow::anyhow!(
+                    "Postmark answered {} with ErrorCode {}: {}",
+                    status,
+                    body.error_code,
+                    body.message
+                )),
+                None => EmailClientError::Unexpected(anyhow::anyhow!("Postmark answered {}", status)),
+            },
+        })
     }
     pub fn new(
         base_url: String,
         sender: SubscriberEmail,
         authorization_token: Secret<String>,
         timeout: std::time::Duration,
+        retry_policy: RetryPolicy,
+        circuit_breaker: CircuitBreaker,
     ) -> Self {
         Self {
             base_url,
@@ -52,10 +342,12 @@ impl EmailClient {
             //we chose instance wide tiemout.
             http_client: reqwest::Client::builder().timeout(timeout).build().unwrap(),
             authorization_token,
+            retry_policy,
+            circuit_breaker,
         }
     }
 }
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize)]
 #[serde(rename_all = "PascalCase")]
 // Lifetime parameters always start with an apostrophe, `'`
 struct SendEmailRequest<'a> {
@@ -64,16 +356,39 @@ struct SendEmailRequest<'a> {
     subject: &'a str,
     html_body: &'a str,
     text_body: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    headers: &'a [PostmarkHeader<'a>],
+}
+//Postmark wants custom headers as a list of name/value objects
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkHeader<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+impl<'a> From<&EmailHeader<'a>> for PostmarkHeader<'a> {
+    fn from(header: &EmailHeader<'a>) -> Self {
+        Self {
+            name: header.name,
+            value: header.value,
+        }
+    }
 }
 #[cfg(test)]
 mod test {
     use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
+    use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+    use crate::email_client::{parse_retry_after, EmailClient, RetryPolicy};
+    use crate::email_transport::{
+        BatchEmail, BatchFailure, EmailClientError, EmailHeader, EmailTransport,
+    };
+    use std::time::Duration;
+    use chrono::{DateTime, Utc};
     use claims::{assert_err, assert_ok};
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::{Fake, Faker};
-    use secrecy::{ExposeSecret, Secret};
+    use secrecy::Secret;
     use wiremock::matchers::{any, header, header_exists, method, path};
     use wiremock::Request;
     use wiremock::{Mock, MockServer, ResponseTemplate};
@@ -99,6 +414,20 @@ mod test {
             }
         }
     }
+    //checks the custom headers end up in the Headers array
+    struct HeadersMatcher;
+    impl wiremock::Match for HeadersMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let body: serde_json::Value = match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(_) => return false,
+            };
+            body["Headers"]
+                == serde_json::json!([
+                    {"Name": "List-Unsubscribe", "Value": "<https://example.com/unsubscribe>"}
+                ])
+        }
+    }
     fn subject() -> String {
         Sentence(1..2).fake()
     }
@@ -115,6 +444,26 @@ mod test {
             email(),
             Secret::new(Faker.fake()),
             std::time::Duration::from_millis(200),
+            RetryPolicy::no_retries(),
+            circuit_breaker(),
+        )
+    }
+    fn circuit_breaker() -> CircuitBreaker {
+        CircuitBreaker::new("postmark", 5, Duration::from_secs(60))
+    }
+    fn retrying_email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            base_url,
+            email(),
+            Secret::new(Faker.fake()),
+            std::time::Duration::from_millis(200),
+            RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                jitter: Duration::ZERO,
+            },
+            circuit_breaker(),
         )
     }
 
@@ -122,8 +471,6 @@ mod test {
     async fn send_email_sends_the_expected_request() {
         //Mock Server creation
         let mock_server = MockServer::start().await;
-        //Fake Sender email
-        let sender = email();
         //Instantiate our email client instance with mocker server url, fake sender email and fake token using faker
         // let fake_auth = Secret::new(Faker.fake());
         let email_client = email_client(mock_server.uri());
@@ -169,8 +516,6 @@ mod test {
     async fn send_email_fails_if_the_server_returns_500() {
         //Mock Server creation
         let mock_server = MockServer::start().await;
-        //Fake Sender email
-        let sender = email();
         //Instantiate our email client instance with mocker server url, fake sender email and fake token using faker
         // let fake_auth = Secret::new(Faker.fake());
         let email_client = email_client(mock_server.uri());
@@ -211,4 +556,502 @@ mod test {
         //Assert
         // Mock expectations are checked on drop
     }
+    #[tokio::test]
+    async fn send_email_with_headers_includes_them_in_the_payload() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(HeadersMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let output = email_client
+            .send_email_with_headers(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+                &[EmailHeader {
+                    name: "List-Unsubscribe",
+                    value: "<https://example.com/unsubscribe>",
+                }],
+            )
+            .await;
+
+        assert_ok!(output);
+    }
+    #[test]
+    fn retry_delays_grow_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            jitter: Duration::ZERO,
+        };
+        assert_eq!(
+            policy.delay_after(1, None),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            policy.delay_after(2, None),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            policy.delay_after(3, None),
+            Some(Duration::from_millis(400))
+        );
+        assert_eq!(
+            policy.delay_after(4, None),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            policy.delay_after(40, None),
+            Some(Duration::from_millis(500))
+        );
+    }
+    #[test]
+    fn retry_after_overrides_the_backoff_and_is_never_cut_short() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: Duration::ZERO,
+        };
+        assert_eq!(
+            policy.delay_after(1, Some(Duration::from_secs(2))),
+            Some(Duration::from_secs(2))
+        );
+        //longer than we are willing to wait, we give up instead of retrying early
+        assert_eq!(policy.delay_after(1, Some(Duration::from_secs(60))), None);
+    }
+    #[test]
+    fn retry_after_is_read_as_seconds_or_as_an_http_date() {
+        let now = DateTime::parse_from_rfc2822("Sun, 06 Nov 1994 08:49:37 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:37 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: Duration::from_millis(50),
+        };
+        for _ in 0..100 {
+            let delay = policy.delay_after(1, None).unwrap();
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(150));
+        }
+    }
+    #[tokio::test]
+    async fn send_email_retries_on_5xx_and_then_succeeds() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+        //mocks are matched in mount order, the first one runs out after a single use
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_ok!(output);
+    }
+    #[tokio::test]
+    async fn send_email_gives_up_after_max_attempts() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_err!(output);
+    }
+    #[tokio::test]
+    async fn send_email_does_not_retry_client_errors() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_err!(output);
+    }
+    #[tokio::test]
+    async fn send_email_retries_timeouts() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_ok!(output);
+    }
+    #[tokio::test]
+    async fn send_email_honours_retry_after_on_429() {
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            email(),
+            Secret::new(Faker.fake()),
+            Duration::from_millis(200),
+            RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_secs(5),
+                jitter: Duration::ZERO,
+            },
+            circuit_breaker(),
+        );
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let started = std::time::Instant::now();
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_ok!(output);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+    #[tokio::test]
+    async fn send_email_gives_up_when_retry_after_exceeds_the_budget() {
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            email(),
+            Secret::new(Faker.fake()),
+            Duration::from_millis(200),
+            RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_secs(5),
+                jitter: Duration::ZERO,
+            },
+            circuit_breaker(),
+        );
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        let error = assert_err!(output);
+        assert!(matches!(error, EmailClientError::RateLimited { .. }));
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(60)));
+    }
+    #[tokio::test]
+    async fn send_email_fails_fast_once_the_circuit_is_open() {
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            email(),
+            Secret::new(Faker.fake()),
+            Duration::from_millis(200),
+            RetryPolicy::no_retries(),
+            CircuitBreaker::new("postmark", 2, Duration::from_secs(60)),
+        );
+        //the third call never reaches the server
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        for _ in 0..2 {
+            let output = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await;
+            assert_err!(output);
+        }
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert!(matches!(output, Err(EmailClientError::CircuitOpen(_))));
+        assert_eq!(email_client.circuit_state(), Some(CircuitState::Open));
+    }
+    #[tokio::test]
+    async fn client_errors_do_not_open_the_circuit() {
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            email(),
+            Secret::new(Faker.fake()),
+            Duration::from_millis(200),
+            RetryPolicy::no_retries(),
+            CircuitBreaker::new("postmark", 1, Duration::from_secs(60)),
+        );
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        for _ in 0..2 {
+            let output = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await;
+            assert_err!(output);
+        }
+
+        assert_eq!(email_client.circuit_state(), Some(CircuitState::Closed));
+    }
+    #[tokio::test]
+    async fn send_batch_reports_the_outcome_of_each_message() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK", "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817", "SubmittedAt": "2010-11-26T12:01:05.1794748-05:00", "To": "a@example.com"},
+                {"ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive."}
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let recipients = [email(), email()];
+        let (subject, content) = (subject(), content());
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                headers: &[],
+            })
+            .collect();
+
+        let outcomes = email_client.send_batch(&emails).await;
+
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].recipient, recipients[0].as_ref());
+        assert_ok!(&outcomes[0].result);
+        assert!(matches!(
+            outcomes[1].result,
+            Err(BatchFailure::Rejected { error_code: 406, .. })
+        ));
+    }
+    #[tokio::test]
+    async fn send_batch_splits_large_batches_into_chunks() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        //each element of the request gets an Ok back
+        struct EchoOk;
+        impl wiremock::Respond for EchoOk {
+            fn respond(&self, request: &Request) -> ResponseTemplate {
+                let body: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+                assert!(body.len() <= crate::email_client::POSTMARK_BATCH_LIMIT);
+                let results: Vec<_> = body
+                    .iter()
+                    .map(|_| serde_json::json!({"ErrorCode": 0, "Message": "OK"}))
+                    .collect();
+                ResponseTemplate::new(200).set_body_json(results)
+            }
+        }
+        Mock::given(path("/email/batch"))
+            .respond_with(EchoOk)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        let recipients: Vec<_> = (0..501).map(|_| email()).collect();
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: "subject",
+                html_content: "html",
+                text_content: "text",
+                headers: &[],
+            })
+            .collect();
+
+        let outcomes = email_client.send_batch(&emails).await;
+
+        assert_eq!(outcomes.len(), 501);
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+    }
+    #[tokio::test]
+    async fn a_failed_batch_request_fails_every_message_in_it() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let recipients = [email(), email()];
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: "subject",
+                html_content: "html",
+                text_content: "text",
+                headers: &[],
+            })
+            .collect();
+
+        let outcomes = email_client.send_batch(&emails).await;
+
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome.result, Err(BatchFailure::RequestFailed(_)))));
+    }
+    #[tokio::test]
+    async fn send_email_returns_the_provider_message_id() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "To": "receiver@example.com",
+                "SubmittedAt": "2014-02-17T07:25:01.4178645-05:00",
+                "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+                "ErrorCode": 0,
+                "Message": "OK"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let sent = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            sent.message_id.as_deref(),
+            Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+        );
+        assert_eq!(sent.submitted_at.to_rfc3339(), "2014-02-17T12:25:01.417864500+00:00");
+    }
+    #[tokio::test]
+    async fn provider_failures_are_mapped_to_typed_errors() {
+        let cases = [
+            (
+                ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                    "ErrorCode": 10, "Message": "No Account or Server API tokens were supplied."
+                })),
+                "unauthorized",
+            ),
+            (
+                ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                    "ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive."
+                })),
+                "recipient rejected",
+            ),
+            (ResponseTemplate::new(429), "rate limited"),
+            (ResponseTemplate::new(503), "server error"),
+            (
+                ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                    "ErrorCode": 400, "Message": "Sender signature not defined for From address."
+                })),
+                "unexpected",
+            ),
+        ];
+        for (response, kind) in cases {
+            let mock_server = MockServer::start().await;
+            let email_client = email_client(mock_server.uri());
+            Mock::given(any())
+                .respond_with(response)
+                .mount(&mock_server)
+                .await;
+
+            let error = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await
+                .unwrap_err();
+
+            let matched = match kind {
+                "unauthorized" => matches!(error, EmailClientError::Unauthorized(_)),
+                "recipient rejected" => matches!(
+                    error,
+                    EmailClientError::RecipientRejected { error_code: 406, .. }
+                ),
+                "rate limited" => matches!(error, EmailClientError::RateLimited { .. }),
+                "server error" => matches!(error, EmailClientError::ServerError { status: 503, .. }),
+                _ => matches!(error, EmailClientError::Unexpected(_)),
+            };
+            assert!(matched, "expected {}, got {:?}", kind, error);
+        }
+    }
+    #[tokio::test]
+    async fn slow_responses_are_reported_as_timeouts() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .mount(&mock_server)
+            .await;
+
+        let output = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert!(matches!(output, Err(EmailClientError::Timeout(_))));
+    }
 }
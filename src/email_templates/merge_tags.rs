@@ -0,0 +1,131 @@
+use super::{Escape, Template};
+
+//what an issue can personalize with, e.g. `Hi {{ name|there }}`
+pub const MERGE_TAGS: [&str; 4] = ["name", "email", "unsubscribe_link", "preferences_link"];
+//`{{ custom.<field> }}` reads from the subscriber's custom_fields
+pub const CUSTOM_FIELD_PREFIX: &str = "custom.";
+
+pub struct MergeFields<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+    pub unsubscribe_link: &'a str,
+    pub preferences_link: &'a str,
+    //a JSON object, fields that are not strings are rendered as JSON
+    pub custom_fields: &'a serde_json::Value,
+}
+
+//run at publish time, so an issue with a typo is rejected before
+//a single subscriber receives it
+pub fn parse_issue_content(content: &str) -> Result<Template, String> {
+    let template = Template::parse(content)?;
+    if let Some(unknown) = template
+        .variables()
+        .find(|tag| !MERGE_TAGS.contains(tag) && !tag.starts_with(CUSTOM_FIELD_PREFIX))
+    {
+        return Err(format!(
+            "unknown merge tag `{{{{ {} }}}}`, expected one of {:?} or `{}<field>`",
+            unknown, MERGE_TAGS, CUSTOM_FIELD_PREFIX
+        ));
+    }
+    Ok(template)
+}
+
+pub fn render_issue_content(
+    template: &Template,
+    fields: &MergeFields<'_>,
+    escape: Escape,
+) -> String {
+    let custom_values: Vec<(String, String)> = template
+        .variables()
+        .filter_map(|tag| {
+            tag.strip_prefix(CUSTOM_FIELD_PREFIX)
+                .map(|field| (tag, field))
+        })
+        .filter_map(|(tag, field)| {
+            let value = match fields.custom_fields.get(field)? {
+                serde_json::Value::Null => return None,
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            Some((tag.to_owned(), value))
+        })
+        .collect();
+    let mut variables = vec![
+        ("name", fields.name),
+        ("email", fields.email),
+        ("unsubscribe_link", fields.unsubscribe_link),
+        ("preferences_link", fields.preferences_link),
+    ];
+    variables.extend(
+        custom_values
+            .iter()
+            .map(|(tag, value)| (tag.as_str(), value.as_str())),
+    );
+    template.render(&variables, &[], escape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_issue_content, render_issue_content, MergeFields};
+    use crate::email_templates::Escape;
+    use claims::{assert_err, assert_ok};
+
+    fn fields(custom_fields: &serde_json::Value) -> MergeFields<'_> {
+        MergeFields {
+            name: "Ursula <3",
+            email: "ursula@example.com",
+            unsubscribe_link: "http://x/unsubscribe?token=abc",
+            preferences_link: "http://x/preferences?token=abc",
+            custom_fields,
+        }
+    }
+
+    #[test]
+    fn known_tags_and_custom_fields_are_accepted() {
+        assert_ok!(parse_issue_content(
+            "Hi {{ name|there }} ({{ email }}), {{ custom.company|friend }} {{ unsubscribe_link }}"
+        ));
+    }
+
+    #[test]
+    fn links_are_rendered_for_each_subscriber() {
+        let template =
+            parse_issue_content(r#"<a href="{{ preferences_link }}">Preferences</a>"#).unwrap();
+        let custom_fields = serde_json::json!({});
+        assert_eq!(
+            render_issue_content(&template, &fields(&custom_fields), Escape::Html),
+            r#"<a href="http://x/preferences?token=abc">Preferences</a>"#
+        );
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        let error = assert_err!(parse_issue_content("Hi {{ first_name }}"));
+        assert!(error.contains("first_name"));
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_but_not_in_text() {
+        let template = parse_issue_content("Hi {{ name }}").unwrap();
+        let custom_fields = serde_json::json!({});
+        assert_eq!(
+            render_issue_content(&template, &fields(&custom_fields), Escape::Html),
+            "Hi Ursula &lt;3"
+        );
+        assert_eq!(
+            render_issue_content(&template, &fields(&custom_fields), Escape::None),
+            "Hi Ursula <3"
+        );
+    }
+
+    #[test]
+    fn custom_fields_fall_back_to_the_default_when_missing_or_null() {
+        let template =
+            parse_issue_content("{{ custom.company|your company }}, {{ custom.seats }}").unwrap();
+        let custom_fields = serde_json::json!({"company": null, "seats": 12});
+        assert_eq!(
+            render_issue_content(&template, &fields(&custom_fields), Escape::Html),
+            "your company, 12"
+        );
+    }
+}
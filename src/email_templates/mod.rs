@@ -0,0 +1,312 @@
+mod merge_tags;
+mod template;
+
+pub use merge_tags::{
+    parse_issue_content, render_issue_content, MergeFields, CUSTOM_FIELD_PREFIX, MERGE_TAGS,
+};
+pub use template::{escape_html, Escape, Template};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::routes::error_chain_fmt;
+
+//the emails we send, each one has an .html and a .txt template
+//rendered into the shared layout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EmailTemplateName {
+    Confirmation,
+    PasswordReset,
+    Newsletter,
+}
+impl EmailTemplateName {
+    pub const ALL: [Self; 3] = [Self::Confirmation, Self::PasswordReset, Self::Newsletter];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Confirmation => "confirmation",
+            Self::PasswordReset => "password_reset",
+            Self::Newsletter => "newsletter",
+        }
+    }
+    //every variable the template may use
+    fn allowed_variables(&self) -> &'static [&'static str] {
+        match self {
+            Self::Confirmation => &["name", "confirmation_link"],
+            Self::PasswordReset => &["reset_link"],
+            Self::Newsletter => &["title", "content", "unsubscribe_link"],
+        }
+    }
+    //an override without these would send an email that is useless or unlawful
+    fn required_variables(&self) -> &'static [&'static str] {
+        match self {
+            Self::Confirmation => &["confirmation_link"],
+            Self::PasswordReset => &["reset_link"],
+            Self::Newsletter => &["content", "unsubscribe_link"],
+        }
+    }
+    //already markup, the issue body is written by an admin
+    fn raw_variables(&self) -> &'static [&'static str] {
+        match self {
+            Self::Newsletter => &["content"],
+            _ => &[],
+        }
+    }
+}
+
+const LAYOUT: &str = "layout";
+const LAYOUT_VARIABLES: [&str; 2] = ["subject", "content"];
+
+//compiled in so the binary works without a templates/ directory next to it
+const DEFAULT_TEMPLATES: [(&str, &str); 8] = [
+    ("layout.html", include_str!("../../templates/layout.html")),
+    ("layout.txt", include_str!("../../templates/layout.txt")),
+    (
+        "confirmation.html",
+        include_str!("../../templates/confirmation.html"),
+    ),
+    (
+        "confirmation.txt",
+        include_str!("../../templates/confirmation.txt"),
+    ),
+    (
+        "password_reset.html",
+        include_str!("../../templates/password_reset.html"),
+    ),
+    (
+        "password_reset.txt",
+        include_str!("../../templates/password_reset.txt"),
+    ),
+    (
+        "newsletter.html",
+        include_str!("../../templates/newsletter.html"),
+    ),
+    (
+        "newsletter.txt",
+        include_str!("../../templates/newsletter.txt"),
+    ),
+];
+
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String,
+}
+
+#[derive(Debug)]
+struct TemplatePair {
+    html: Template,
+    text: Template,
+}
+
+//parsed and validated once at startup, a broken override stops the
+//application from booting instead of failing a live request
+#[derive(Debug)]
+pub struct EmailTemplates {
+    layout: TemplatePair,
+    templates: HashMap<EmailTemplateName, TemplatePair>,
+}
+impl EmailTemplates {
+    //files found in `directory` replace the compiled-in default of the same name
+    pub fn load(directory: Option<&Path>) -> Result<Self, TemplateError> {
+        if let Some(directory) = directory {
+            if !directory.is_dir() {
+                return Err(TemplateError::MissingDirectory(directory.to_owned()));
+            }
+        }
+        let load_pair = |stem: &str, allowed: &[&str], required: &[&str]| {
+            Ok::<_, TemplateError>(TemplatePair {
+                html: load_template(directory, &format!("{}.html", stem), allowed, required)?,
+                text: load_template(directory, &format!("{}.txt", stem), allowed, required)?,
+            })
+        };
+        let layout = load_pair(LAYOUT, &LAYOUT_VARIABLES, &["content"])?;
+        let mut templates = HashMap::new();
+        for name in EmailTemplateName::ALL {
+            let pair = load_pair(
+                name.as_str(),
+                name.allowed_variables(),
+                name.required_variables(),
+            )?;
+            templates.insert(name, pair);
+        }
+        Ok(Self { layout, templates })
+    }
+
+    pub fn render(
+        &self,
+        name: EmailTemplateName,
+        subject: &str,
+        variables: &[(&str, &str)],
+    ) -> RenderedEmail {
+        RenderedEmail {
+            html: self.render_html(name, subject, variables),
+            text: self.render_text(name, subject, variables),
+        }
+    }
+
+    pub fn render_html(
+        &self,
+        name: EmailTemplateName,
+        subject: &str,
+        variables: &[(&str, &str)],
+    ) -> String {
+        let content =
+            self.templates[&name]
+                .html
+                .render(variables, name.raw_variables(), Escape::Html);
+        self.layout.html.render(
+            &[("subject", subject), ("content", &content)],
+            &["content"],
+            Escape::Html,
+        )
+    }
+
+    pub fn render_text(
+        &self,
+        name: EmailTemplateName,
+        subject: &str,
+        variables: &[(&str, &str)],
+    ) -> String {
+        let content = self.templates[&name]
+            .text
+            .render(variables, &[], Escape::None);
+        self.layout.text.render(
+            &[("subject", subject), ("content", &content)],
+            &[],
+            Escape::None,
+        )
+    }
+}
+
+fn load_template(
+    directory: Option<&Path>,
+    file_name: &str,
+    allowed: &[&str],
+    required: &[&str],
+) -> Result<Template, TemplateError> {
+    let override_path = directory
+        .map(|directory| directory.join(file_name))
+        .filter(|path| path.is_file());
+    let source = match &override_path {
+        Some(path) => {
+            std::fs::read_to_string(path).map_err(|e| TemplateError::Read(path.to_owned(), e))?
+        }
+        None => DEFAULT_TEMPLATES
+            .iter()
+            .find(|(name, _)| *name == file_name)
+            .map(|(_, source)| source.to_string())
+            .expect("every template has a compiled-in default"),
+    };
+    let invalid = |message: String| TemplateError::Invalid {
+        file: file_name.to_owned(),
+        message,
+    };
+    let template = Template::parse(&source).map_err(invalid)?;
+    if let Some(unknown) = template.variables().find(|v| !allowed.contains(v)) {
+        return Err(invalid(format!(
+            "unknown variable `{}`, expected one of {:?}",
+            unknown, allowed
+        )));
+    }
+    if let Some(missing) = required
+        .iter()
+        .find(|r| !template.variables().any(|v| v == **r))
+    {
+        return Err(invalid(format!("the `{}` variable is required", missing)));
+    }
+    Ok(template)
+}
+
+#[derive(thiserror::Error)]
+pub enum TemplateError {
+    #[error("The email template directory {0:?} does not exist.")]
+    MissingDirectory(PathBuf),
+    #[error("Failed to read the email template {0:?}.")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("The email template {file} is invalid: {message}.")]
+    Invalid { file: String, message: String },
+}
+impl std::fmt::Debug for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailTemplateName, EmailTemplates, TemplateError};
+    use claims::{assert_err, assert_ok};
+    use uuid::Uuid;
+
+    fn template_directory(files: &[(&str, &str)]) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+        for (name, content) in files {
+            std::fs::write(directory.join(name), content).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn the_compiled_in_defaults_are_valid() {
+        assert_ok!(EmailTemplates::load(None));
+    }
+
+    #[test]
+    fn the_layout_wraps_the_rendered_template() {
+        let templates = EmailTemplates::load(None).unwrap();
+        let email = templates.render(
+            EmailTemplateName::Confirmation,
+            "Welcome",
+            &[
+                ("name", "<Ursula>"),
+                ("confirmation_link", "http://x/confirm"),
+            ],
+        );
+        assert!(email.html.contains("<title>Welcome</title>"));
+        assert!(email.html.contains("&lt;Ursula&gt;"));
+        assert!(email.html.contains("href=\"http://x/confirm\""));
+        assert!(email.text.contains("Welcome to our newsletter, <Ursula>!"));
+    }
+
+    #[test]
+    fn files_in_the_directory_override_the_defaults() {
+        let directory = template_directory(&[(
+            "confirmation.txt",
+            "Hello {{ name }}, confirm at {{ confirmation_link }}",
+        )]);
+        let templates = EmailTemplates::load(Some(&directory)).unwrap();
+        let email = templates.render(
+            EmailTemplateName::Confirmation,
+            "Welcome",
+            &[("name", "Ursula"), ("confirmation_link", "http://x")],
+        );
+        assert_eq!(email.text.trim(), "Hello Ursula, confirm at http://x");
+        //the html version still comes from the defaults
+        assert!(email.html.contains("Welcome to our newsletter, Ursula!"));
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        let directory = template_directory(&[(
+            "confirmation.html",
+            "{{ confirmation_link }} {{ confirmaton_link }}",
+        )]);
+        let error = assert_err!(EmailTemplates::load(Some(&directory)));
+        assert!(
+            matches!(error, TemplateError::Invalid { ref file, .. } if file == "confirmation.html")
+        );
+    }
+
+    #[test]
+    fn overrides_missing_a_required_variable_are_rejected() {
+        let directory = template_directory(&[("newsletter.txt", "{{ content }}")]);
+        assert_err!(EmailTemplates::load(Some(&directory)));
+    }
+
+    #[test]
+    fn a_missing_directory_is_rejected() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        assert_err!(EmailTemplates::load(Some(&directory)));
+    }
+}
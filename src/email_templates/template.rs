@@ -0,0 +1,200 @@
+use std::fmt::Write;
+
+//a parsed template, literal text interleaved with `{{ variable }}` slots,
+//`{{ variable|fallback }}` renders the fallback when the value is missing or empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Variable {
+        name: String,
+        default: Option<String>,
+    },
+}
+
+//how substituted values are written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    Html,
+    None,
+}
+
+impl Template {
+    //fails on an unclosed `{{` or a slot whose name is not an identifier,
+    //dots are allowed to namespace names, e.g. `custom.company`
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_owned()));
+            }
+            let offset = source.len() - rest.len() + start;
+            let after_open = &rest[start + 2..];
+            let end = after_open
+                .find("}}")
+                .ok_or_else(|| format!("unclosed `{{{{` at byte {}", offset))?;
+            let (name, default) = match after_open[..end].split_once('|') {
+                Some((name, default)) => (name.trim(), Some(default.trim().to_owned())),
+                None => (after_open[..end].trim(), None),
+            };
+            if !name.split('.').all(is_identifier) {
+                return Err(format!(
+                    "`{}` at byte {} is not a valid variable name",
+                    name, offset
+                ));
+            }
+            segments.push(Segment::Variable {
+                name: name.to_owned(),
+                default,
+            });
+            rest = &after_open[end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_owned()));
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Variable { name, .. } => Some(name.as_str()),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    //variables without a value render as nothing, `raw_variables` are
+    //trusted markup and skip escaping
+    pub fn render(
+        &self,
+        variables: &[(&str, &str)],
+        raw_variables: &[&str],
+        escape: Escape,
+    ) -> String {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => rendered.push_str(text),
+                Segment::Variable { name, default } => {
+                    let value = variables
+                        .iter()
+                        .find(|(key, value)| key == name && !value.is_empty())
+                        .map(|(_, value)| *value)
+                        .or(default.as_deref())
+                        .unwrap_or_default();
+                    if escape == Escape::Html && !raw_variables.contains(&name.as_str()) {
+                        escape_html(&mut rendered, value);
+                    } else {
+                        rendered.push_str(value);
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+pub fn escape_html(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#x27;"),
+            c => out.write_char(c).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Escape, Template};
+    use claims::assert_err;
+
+    #[test]
+    fn variables_are_substituted_and_whitespace_in_slots_is_ignored() {
+        let template = Template::parse("Hi {{name}}, visit {{ link }}.").unwrap();
+        let rendered = template.render(
+            &[("name", "Ursula"), ("link", "http://x")],
+            &[],
+            Escape::None,
+        );
+        assert_eq!(rendered, "Hi Ursula, visit http://x.");
+    }
+
+    #[test]
+    fn html_rendering_escapes_values_but_not_raw_variables() {
+        let template = Template::parse("<p>{{ name }}</p>{{ content }}").unwrap();
+        let rendered = template.render(
+            &[("name", "<script>\"&'"), ("content", "<b>bold</b>")],
+            &["content"],
+            Escape::Html,
+        );
+        assert_eq!(
+            rendered,
+            "<p>&lt;script&gt;&quot;&amp;&#x27;</p><b>bold</b>"
+        );
+    }
+
+    #[test]
+    fn missing_values_render_as_nothing() {
+        let template = Template::parse("[{{ name }}]").unwrap();
+        assert_eq!(template.render(&[], &[], Escape::Html), "[]");
+    }
+
+    #[test]
+    fn defaults_fill_in_missing_and_empty_values() {
+        let template = Template::parse("Hi {{ name|there }}, {{ company | <none> }}").unwrap();
+        assert_eq!(
+            template.render(&[("name", "")], &[], Escape::Html),
+            "Hi there, &lt;none&gt;"
+        );
+        assert_eq!(
+            template.render(
+                &[("name", "Ursula"), ("company", "ACME")],
+                &[],
+                Escape::Html
+            ),
+            "Hi Ursula, ACME"
+        );
+    }
+
+    #[test]
+    fn dotted_names_are_accepted() {
+        let template = Template::parse("{{ custom.company }}").unwrap();
+        assert_eq!(
+            template.variables().collect::<Vec<_>>(),
+            vec!["custom.company"]
+        );
+        assert_err!(Template::parse("{{ custom. }}"));
+        assert_err!(Template::parse("{{ .company }}"));
+    }
+
+    #[test]
+    fn malformed_slots_are_rejected() {
+        assert_err!(Template::parse("Hi {{ name"));
+        assert_err!(Template::parse("Hi {{ }}"));
+        assert_err!(Template::parse("Hi {{ first name }}"));
+        assert_err!(Template::parse("Hi {{ Name }}"));
+    }
+
+    #[test]
+    fn variables_lists_every_slot_in_order() {
+        let template = Template::parse("{{ a }} and {{ b }} and {{ a }}").unwrap();
+        assert_eq!(
+            template.variables().collect::<Vec<_>>(),
+            vec!["a", "b", "a"]
+        );
+    }
+}
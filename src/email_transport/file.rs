@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use chrono::Utc;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+
+use super::{build_message, EmailClientError, EmailHeader, EmailTransport, SentEmail};
+use crate::domain::SubscriberEmail;
+
+//writes every email as a <uuid>.eml file, nothing leaves the machine
+pub struct FileEmailTransport {
+    sender: SubscriberEmail,
+    directory: PathBuf,
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileEmailTransport {
+    //creates the directory so a fresh checkout works without any setup
+    pub fn new(sender: SubscriberEmail, directory: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        let transport = AsyncFileTransport::new(&directory);
+        Ok(Self {
+            sender,
+            directory,
+            transport,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for FileEmailTransport {
+    async fn send_email_with_headers(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[EmailHeader<'_>],
+    ) -> Result<SentEmail, EmailClientError> {
+        let message = build_message(
+            &self.sender,
+            &recipient,
+            subject,
+            html_content,
+            text_content,
+            headers,
+        )?;
+        let id = self
+            .transport
+            .send(message)
+            .await
+            .with_context(|| format!("Failed to write an email to {}", self.directory.display()))?;
+        tracing::info!(file = %self.directory.join(format!("{}.eml", id)).display(), "Email written to disk.");
+        Ok(SentEmail {
+            message_id: Some(id.to_string()),
+            submitted_at: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileEmailTransport;
+    use crate::domain::SubscriberEmail;
+    use crate::email_transport::EmailTransport;
+    use claims::assert_ok;
+
+    #[tokio::test]
+    async fn emails_are_written_as_eml_files() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let sender = SubscriberEmail::parse("sender@example.com".into()).unwrap();
+        let transport = FileEmailTransport::new(sender, &directory).unwrap();
+        let recipient = SubscriberEmail::parse("recipient@example.com".into()).unwrap();
+
+        let output = transport
+            .send_email(recipient, "Subject line", "<p>html</p>", "text")
+            .await;
+
+        assert_ok!(output);
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let content = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(content.contains("To: recipient@example.com"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+mod file;
+mod smtp;
+
+pub use file::FileEmailTransport;
+pub use smtp::SmtpEmailTransport;
+
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+
+use crate::circuit_breaker::{CircuitOpen, CircuitState};
+use crate::domain::SubscriberEmail;
+use crate::routes::error_chain_fmt;
+
+//an extra header on the outgoing message, e.g. List-Unsubscribe
+#[derive(Debug)]
+pub struct EmailHeader<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
+}
+
+//what the provider told us about an accepted email
+#[derive(Debug, Clone)]
+pub struct SentEmail {
+    //Postmark's MessageID, the Message-ID header for SMTP, the file name for .eml files
+    pub message_id: Option<String>,
+    pub submitted_at: DateTime<Utc>,
+}
+
+//one message of a batch, each recipient gets their own headers
+pub struct BatchEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub headers: &'a [EmailHeader<'a>],
+}
+
+//in the same order as the emails handed to send_batch
+#[derive(Debug)]
+pub struct BatchOutcome {
+    pub recipient: String,
+    pub result: Result<SentEmail, BatchFailure>,
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum BatchFailure {
+    //the provider looked at this message and refused it, e.g. an inactive recipient
+    #[error("The email provider rejected the message ({error_code}): {message}")]
+    Rejected { error_code: i64, message: String },
+    //nothing was attempted, the provider is known to be down
+    #[error(transparent)]
+    CircuitOpen(#[from] CircuitOpen),
+    //the whole request went wrong, every message in it shares the error
+    #[error("The batch request failed: {0}")]
+    RequestFailed(String),
+}
+impl From<EmailClientError> for BatchFailure {
+    fn from(error: EmailClientError) -> Self {
+        match error {
+            EmailClientError::RecipientRejected {
+                error_code,
+                message,
+            } => Self::Rejected {
+                error_code,
+                message,
+            },
+            EmailClientError::CircuitOpen(e) => Self::CircuitOpen(e),
+            e => Self::RequestFailed(e.to_string()),
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum EmailClientError {
+    #[error("The email provider did not answer in time.")]
+    Timeout(#[source] anyhow::Error),
+    #[error("Failed to reach the email provider.")]
+    Transport(#[source] anyhow::Error),
+    #[error("The email provider rejected our credentials: {0}")]
+    Unauthorized(String),
+    #[error("The email provider is rate limiting us.")]
+    RateLimited { retry_after: Option<Duration> },
+    //not worth retrying, the address itself is the problem
+    #[error("The email provider rejected the recipient ({error_code}): {message}")]
+    RecipientRejected { error_code: i64, message: String },
+    #[error("The email provider failed with status {status}.")]
+    ServerError {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    #[error(transparent)]
+    CircuitOpen(#[from] CircuitOpen),
+    #[error(transparent)]
+    Unexpected(#[from] anyhow::Error),
+}
+impl EmailClientError {
+    //worth another attempt, and a sign the provider is struggling
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Timeout(_) | Self::Transport(_) | Self::RateLimited { .. } | Self::ServerError { .. }
+        )
+    }
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after } | Self::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+impl std::fmt::Debug for EmailClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+//everything that sends email goes through this, the backend is picked in
+//configuration (see EmailClientSettings::transport)
+#[async_trait::async_trait]
+pub trait EmailTransport: Send + Sync {
+    async fn send_email_with_headers(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[EmailHeader<'_>],
+    ) -> Result<SentEmail, EmailClientError>;
+
+    async fn send_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<SentEmail, EmailClientError> {
+        self.send_email_with_headers(recipient, subject, html_content, text_content, &[])
+            .await
+    }
+
+    //transports without a batch api send the emails one at a time
+    async fn send_batch(&self, emails: &[BatchEmail<'_>]) -> Vec<BatchOutcome> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for email in emails {
+            let result = self
+                .send_email_with_headers(
+                    email.recipient.clone(),
+                    email.subject,
+                    email.html_content,
+                    email.text_content,
+                    email.headers,
+                )
+                .await
+                .map_err(BatchFailure::from);
+            outcomes.push(BatchOutcome {
+                recipient: email.recipient.as_ref().to_owned(),
+                result,
+            });
+        }
+        outcomes
+    }
+
+    //None for transports without a circuit breaker
+    fn circuit_state(&self) -> Option<CircuitState> {
+        None
+    }
+}
+
+//a multipart/alternative MIME message, shared by the SMTP and file transports
+pub(crate) fn build_message(
+    sender: &SubscriberEmail,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+    headers: &[EmailHeader<'_>],
+) -> Result<Message, anyhow::Error> {
+    let from: Mailbox = sender.as_ref().parse().context("Invalid sender address.")?;
+    let to: Mailbox = recipient
+        .as_ref()
+        .parse()
+        .context("Invalid recipient address.")?;
+    let mut message = Message::builder()
+        //generated here so the transports can report it back
+        .message_id(None)
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .multipart(MultiPart::alternative_plain_html(
+            text_content.to_owned(),
+            html_content.to_owned(),
+        ))
+        .context("Failed to build the email message.")?;
+    for header in headers {
+        let name = HeaderName::new_from_ascii(header.name.to_owned())
+            .with_context(|| format!("Invalid email header name: {}", header.name))?;
+        message
+            .headers_mut()
+            .insert_raw(HeaderValue::new(name, header.value.to_owned()));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_message, EmailHeader};
+    use crate::domain::SubscriberEmail;
+
+    #[test]
+    fn built_messages_carry_both_bodies_and_the_extra_headers() {
+        let sender = SubscriberEmail::parse("sender@example.com".into()).unwrap();
+        let recipient = SubscriberEmail::parse("recipient@example.com".into()).unwrap();
+        let message = build_message(
+            &sender,
+            &recipient,
+            "Subject line",
+            "<p>html body</p>",
+            "text body",
+            &[EmailHeader {
+                name: "List-Unsubscribe",
+                value: "<https://example.com/unsubscribe>",
+            }],
+        )
+        .unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("Subject: Subject line"));
+        assert!(formatted.contains("List-Unsubscribe: <https://example.com/unsubscribe>"));
+        assert!(formatted.contains("<p>html body</p>"));
+        assert!(formatted.contains("text body"));
+    }
+}
@@ -0,0 +1,86 @@
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+
+use super::{build_message, EmailClientError, EmailHeader, EmailTransport, SentEmail};
+use crate::domain::SubscriberEmail;
+
+//any SMTP relay, e.g. a local mailhog/mailpit for development
+pub struct SmtpEmailTransport {
+    sender: SubscriberEmail,
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpEmailTransport {
+    pub fn new(
+        sender: SubscriberEmail,
+        host: &str,
+        port: u16,
+        username: &str,
+        password: Secret<String>,
+        starttls: bool,
+        timeout: std::time::Duration,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let tls = if starttls {
+            Tls::Required(TlsParameters::new(host.to_owned())?)
+        } else {
+            Tls::None
+        };
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(port)
+            .tls(tls)
+            .timeout(Some(timeout));
+        //an empty username means the relay does not want authentication
+        if !username.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                username.to_owned(),
+                password.expose_secret().to_owned(),
+            ));
+        }
+        Ok(Self {
+            sender,
+            mailer: builder.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for SmtpEmailTransport {
+    async fn send_email_with_headers(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[EmailHeader<'_>],
+    ) -> Result<SentEmail, EmailClientError> {
+        let message = build_message(
+            &self.sender,
+            &recipient,
+            subject,
+            html_content,
+            text_content,
+            headers,
+        )?;
+        let message_id = message.headers().get_raw("Message-ID").map(str::to_owned);
+        self.mailer.send(message).await.map_err(|e| {
+            if e.is_timeout() {
+                EmailClientError::Timeout(e.into())
+            } else if e.is_permanent() {
+                //5xx from the relay, e.g. 550 mailbox unavailable
+                EmailClientError::RecipientRejected {
+                    error_code: e.status().map(u16::from).unwrap_or_default().into(),
+                    message: e.to_string(),
+                }
+            } else {
+                EmailClientError::Transport(e.into())
+            }
+        })?;
+        Ok(SentEmail {
+            message_id,
+            submitted_at: Utc::now(),
+        })
+    }
+}
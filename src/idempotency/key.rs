@@ -0,0 +1,65 @@
+use actix_web::HttpRequest;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    //the header is optional, a missing header means the request is processed as usual
+    pub fn from_request(request: &HttpRequest) -> Result<Option<Self>, anyhow::Error> {
+        match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+            None => Ok(None),
+            Some(value) => {
+                let key = value
+                    .to_str()
+                    .map_err(|_| anyhow::anyhow!("The idempotency key is not valid ASCII."))?
+                    .to_owned();
+                Ok(Some(key.try_into()?))
+            }
+        }
+    }
+}
+impl TryFrom<String> for IdempotencyKey {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if s.is_empty() {
+            anyhow::bail!("The idempotency key cannot be empty");
+        }
+        //keys are stored in the db, so keep their size in check
+        let max_length = 50;
+        if s.len() >= max_length {
+            anyhow::bail!("The idempotency key must be shorter than {max_length} characters");
+        }
+        Ok(Self(s))
+    }
+}
+impl From<IdempotencyKey> for String {
+    fn from(k: IdempotencyKey) -> Self {
+        k.0
+    }
+}
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::idempotency::IdempotencyKey;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn empty_key_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("".to_string()));
+    }
+    #[test]
+    fn a_50_characters_long_key_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("a".repeat(50)));
+    }
+    #[test]
+    fn a_short_key_is_accepted() {
+        assert_ok!(IdempotencyKey::try_from(uuid::Uuid::new_v4().to_string()));
+    }
+}
@@ -0,0 +1,45 @@
+mod key;
+mod persistence;
+pub use key::IdempotencyKey;
+pub use persistence::{save_response, try_processing, NextAction};
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+//callers that are not logged in (e.g. the public subscription form) are scoped
+//by the address they submit, so clients that pick the same key for different
+//addresses never get each other's responses
+pub fn anonymous_scope(email: &str) -> Uuid {
+    let digest = Sha256::digest(email.trim().to_lowercase().as_bytes());
+    Uuid::from_slice(&digest[..16]).expect("a sha256 digest is longer than 16 bytes")
+}
+
+//the fields that make up a request, length prefixed so they cannot run into each other
+pub fn request_fingerprint(fields: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for field in fields {
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{anonymous_scope, request_fingerprint};
+
+    #[test]
+    fn anonymous_scopes_ignore_case_and_surrounding_whitespace() {
+        assert_eq!(
+            anonymous_scope("ursula@gmail.com"),
+            anonymous_scope(" Ursula@Gmail.com ")
+        );
+        assert_ne!(anonymous_scope("ursula@gmail.com"), anonymous_scope("octavia@gmail.com"));
+    }
+
+    #[test]
+    fn fingerprints_tell_where_one_field_ends() {
+        assert_eq!(request_fingerprint(&["ab", "c"]), request_fingerprint(&["ab", "c"]));
+        assert_ne!(request_fingerprint(&["ab", "c"]), request_fingerprint(&["a", "bc"]));
+    }
+}
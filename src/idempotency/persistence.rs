@@ -0,0 +1,153 @@
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use sqlx::postgres::PgHasArrayType;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use super::IdempotencyKey;
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>,
+}
+//sqlx does not derive the array type for composite types
+impl PgHasArrayType for HeaderPairRecord {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_header_pair")
+    }
+}
+
+pub enum NextAction {
+    StartProcessing,
+    ReturnSavedResponse(HttpResponse),
+    //the key was first used for a different request, most likely a client bug
+    RejectKeyReuse,
+}
+
+//claims the key inside the caller's transaction, a concurrent request with the
+//same key blocks on the insert until the first one commits or rolls back
+#[tracing::instrument(
+    name = "Claim an idempotency key",
+    skip(transaction, idempotency_key, request_fingerprint)
+)]
+pub async fn try_processing(
+    transaction: &mut Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+    request_fingerprint: &str,
+) -> Result<NextAction, anyhow::Error> {
+    let n_inserted_rows = sqlx::query!(
+        r#"
+        INSERT INTO idempotency (
+            user_id,
+            idempotency_key,
+            request_fingerprint,
+            created_at
+        )
+        VALUES ($1, $2, $3, now())
+        ON CONFLICT DO NOTHING
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        request_fingerprint
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    if n_inserted_rows > 0 {
+        return Ok(NextAction::StartProcessing);
+    }
+    let (saved_fingerprint, saved_response) =
+        get_saved_response(transaction, idempotency_key, user_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("We expected a saved response, we didn't find it"))?;
+    match saved_fingerprint {
+        Some(saved) if saved != request_fingerprint => Ok(NextAction::RejectKeyReuse),
+        _ => Ok(NextAction::ReturnSavedResponse(saved_response)),
+    }
+}
+
+async fn get_saved_response(
+    transaction: &mut Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+) -> Result<Option<(Option<String>, HttpResponse)>, anyhow::Error> {
+    let saved_response = sqlx::query!(
+        r#"
+        SELECT
+            request_fingerprint,
+            response_status_code as "response_status_code!",
+            response_headers as "response_headers!: Vec<HeaderPairRecord>",
+            response_body as "response_body!"
+        FROM idempotency
+        WHERE
+            user_id = $1 AND
+            idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref()
+    )
+    .fetch_optional(transaction)
+    .await?;
+    if let Some(r) = saved_response {
+        let status_code = StatusCode::from_u16(r.response_status_code.try_into()?)?;
+        let mut response = HttpResponse::build(status_code);
+        for HeaderPairRecord { name, value } in r.response_headers {
+            response.append_header((name, value));
+        }
+        Ok(Some((r.request_fingerprint, response.body(r.response_body))))
+    } else {
+        Ok(None)
+    }
+}
+
+//stores the response next to the claimed key, it becomes visible to other
+//requests once the caller commits the transaction
+#[tracing::instrument(
+    name = "Save the response for an idempotency key",
+    skip(transaction, idempotency_key, http_response)
+)]
+pub async fn save_response(
+    transaction: &mut Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+    http_response: HttpResponse,
+) -> Result<HttpResponse, anyhow::Error> {
+    let (response_head, body) = http_response.into_parts();
+    //MessageBody::Error is not Send + Sync, so it does not play nice with anyhow
+    let body = to_bytes(body).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    let status_code = response_head.status().as_u16() as i16;
+    let headers = response_head
+        .headers()
+        .iter()
+        .map(|(name, value)| HeaderPairRecord {
+            name: name.as_str().to_owned(),
+            value: value.as_bytes().to_owned(),
+        })
+        .collect::<Vec<_>>();
+    //query_unchecked!, the macros cannot check custom composite types yet
+    sqlx::query_unchecked!(
+        r#"
+        UPDATE idempotency
+        SET
+            response_status_code = $3,
+            response_headers = $4,
+            response_body = $5
+        WHERE
+            user_id = $1 AND
+            idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        status_code,
+        headers,
+        body.as_ref()
+    )
+    .execute(transaction)
+    .await?;
+    let http_response = response_head.set_body(body).map_into_boxed_body();
+    Ok(http_response)
+}
@@ -0,0 +1,420 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::{
+    dead_letter::{store_dead_letter, DeadLetterPayload},
+    domain::SubscriberEmail,
+    email_templates::{
+        parse_issue_content, render_issue_content, EmailTemplateName, EmailTemplates, Escape,
+        MergeFields,
+    },
+    email_client::POSTMARK_BATCH_LIMIT,
+    email_transport::{BatchEmail, BatchFailure, EmailHeader, EmailTransport},
+};
+
+//a task is moved to the dead-letter queue once it has failed this many times
+const MAX_DELIVERY_ATTEMPTS: i32 = 5;
+//how long the worker sleeps when there is nothing to deliver
+const EMPTY_QUEUE_BACKOFF: Duration = Duration::from_secs(10);
+//how long the worker sleeps after failing to talk to the database
+const UNEXPECTED_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+struct Task {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    attempts: i32,
+}
+struct Recipient {
+    email: String,
+    name: String,
+    unsubscribe_token: String,
+    custom_fields: serde_json::Value,
+}
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+//loops forever, the only way out is the runtime shutting down
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailTransport>,
+    email_templates: Arc<EmailTemplates>,
+    base_url: String,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_execute_task(&pool, email_client.as_ref(), &email_templates, &base_url).await {
+            Ok(ExecutionOutcome::EmptyQueue) => tokio::time::sleep(EMPTY_QUEUE_BACKOFF).await,
+            Err(_) => tokio::time::sleep(UNEXPECTED_ERROR_BACKOFF).await,
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(tasks = tracing::field::Empty), err)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailTransport,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let Some((mut transaction, tasks)) = dequeue_tasks(pool).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    Span::current().record("tasks", tasks.len());
+    //they may have unsubscribed, bounced or complained since the issue was queued
+    let recipients = get_recipients(pool, &tasks).await?;
+    let mut issues = HashMap::new();
+    //deleted together once the batch is sent
+    let mut completed = Vec::new();
+    let mut emails = Vec::new();
+    for task in &tasks {
+        //retrying will not fix a stored email that no longer parses
+        let email = match SubscriberEmail::parse(task.subscriber_email.clone()) {
+            Ok(email) => email,
+            Err(e) => {
+                tracing::warn!(
+                    error.message = %e,
+                    subscriber_email = %task.subscriber_email,
+                    "Skipping a confirmed subscriber. Their stored contact details are invalid",
+                );
+                completed.push(task);
+                continue;
+            }
+        };
+        let Some(recipient) = recipients.get(&task.subscriber_email) else {
+            tracing::info!(
+                subscriber_email = %task.subscriber_email,
+                "Skipping a subscriber who is no longer confirmed or is suppressed.",
+            );
+            completed.push(task);
+            continue;
+        };
+        let issue = match issues.entry(task.newsletter_issue_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(get_issue(pool, task.newsletter_issue_id).await?),
+        };
+        emails.push((
+            task,
+            render_issue(issue, email, recipient, email_templates, base_url),
+        ));
+    }
+    let headers: Vec<_> = emails.iter().map(|(_, email)| email.headers()).collect();
+    let batch: Vec<_> = emails
+        .iter()
+        .zip(&headers)
+        .map(|((_, email), headers)| BatchEmail {
+            recipient: &email.recipient,
+            subject: &email.subject,
+            html_content: &email.html_content,
+            text_content: &email.text_content,
+            headers,
+        })
+        .collect();
+    let outcomes = if batch.is_empty() {
+        Vec::new()
+    } else {
+        email_client.send_batch(&batch).await
+    };
+    for ((task, _), outcome) in emails.iter().zip(outcomes) {
+        match outcome.result {
+            Ok(sent) => {
+                tracing::info!(
+                    message_id = sent.message_id.as_deref().unwrap_or_default(),
+                    subscriber_email = %task.subscriber_email,
+                    "Delivered issue to a confirmed subscriber.",
+                );
+                completed.push(task);
+            }
+            //the provider is known to be down, this attempt does not count
+            Err(BatchFailure::CircuitOpen(_)) => {
+                postpone_task(&mut transaction, task, EMPTY_QUEUE_BACKOFF).await?;
+            }
+            //retrying will not change the provider's mind about this address, it
+            //goes straight to the dead-letter queue so an admin can look into it
+            Err(e @ BatchFailure::Rejected { .. }) => {
+                tracing::warn!(
+                    error.message = %e,
+                    subscriber_email = %task.subscriber_email,
+                    "Skipping a confirmed subscriber. The email provider rejected them",
+                );
+                dead_letter_task(&mut transaction, task, task.attempts + 1, &e.to_string()).await?;
+            }
+            Err(e @ BatchFailure::RequestFailed(_)) => {
+                tracing::error!(
+                    error.message = %e,
+                    subscriber_email = %task.subscriber_email,
+                    attempts = task.attempts + 1,
+                    "Failed to deliver issue to a confirmed subscriber.",
+                );
+                reschedule_task(&mut transaction, task, &e.to_string()).await?;
+            }
+        }
+    }
+    delete_tasks(&mut transaction, &completed).await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+//everything a single recipient's copy of the issue needs
+struct PersonalizedIssue {
+    recipient: SubscriberEmail,
+    subject: String,
+    html_content: String,
+    text_content: String,
+    list_unsubscribe: String,
+}
+
+impl PersonalizedIssue {
+    fn headers(&self) -> [EmailHeader<'_>; 2] {
+        [
+            EmailHeader {
+                name: "List-Unsubscribe",
+                value: &self.list_unsubscribe,
+            },
+            EmailHeader {
+                name: "List-Unsubscribe-Post",
+                value: "List-Unsubscribe=One-Click",
+            },
+        ]
+    }
+}
+
+fn render_issue(
+    issue: &NewsletterIssue,
+    email: SubscriberEmail,
+    recipient: &Recipient,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+) -> PersonalizedIssue {
+    let unsubscribe_link = format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        base_url, recipient.unsubscribe_token
+    );
+    let preferences_link = format!(
+        "{}/subscriptions/preferences?token={}",
+        base_url, recipient.unsubscribe_token
+    );
+    let fields = MergeFields {
+        name: &recipient.name,
+        email: email.as_ref(),
+        unsubscribe_link: &unsubscribe_link,
+        preferences_link: &preferences_link,
+        custom_fields: &recipient.custom_fields,
+    };
+    let issue_html = personalize(&issue.html_content, &fields, Escape::Html);
+    let issue_text = personalize(&issue.text_content, &fields, Escape::None);
+    //the issue has a separate html and text body, so the two are rendered apart
+    let html_content = email_templates.render_html(
+        EmailTemplateName::Newsletter,
+        &issue.title,
+        &[
+            ("title", &issue.title),
+            ("content", &issue_html),
+            ("unsubscribe_link", &unsubscribe_link),
+        ],
+    );
+    let text_content = email_templates.render_text(
+        EmailTemplateName::Newsletter,
+        &issue.title,
+        &[
+            ("title", &issue.title),
+            ("content", &issue_text),
+            ("unsubscribe_link", &unsubscribe_link),
+        ],
+    );
+    PersonalizedIssue {
+        recipient: email,
+        subject: issue.title.clone(),
+        html_content,
+        text_content,
+        list_unsubscribe: format!("<{}>", unsubscribe_link),
+    }
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+//as many rows as fit in one provider batch, they stay locked until the
+//transaction ends and SKIP LOCKED lets concurrent workers pick up others
+#[tracing::instrument(skip_all)]
+async fn dequeue_tasks(pool: &PgPool) -> Result<Option<(PgTransaction, Vec<Task>)>, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let tasks = sqlx::query_as!(
+        Task,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, attempts
+        FROM issue_delivery_queue
+        WHERE next_attempt_at <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT $1
+        "#,
+        POSTMARK_BATCH_LIMIT as i64
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to dequeue issue delivery tasks.")?;
+    if tasks.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((transaction, tasks)))
+}
+
+#[tracing::instrument(skip_all, fields(tasks = tasks.len()))]
+async fn delete_tasks(transaction: &mut PgTransaction, tasks: &[&Task]) -> Result<(), anyhow::Error> {
+    let issue_ids: Vec<Uuid> = tasks.iter().map(|task| task.newsletter_issue_id).collect();
+    let emails: Vec<&str> = tasks.iter().map(|task| task.subscriber_email.as_str()).collect();
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT * FROM UNNEST($1::uuid[], $2::text[])
+        )
+        "#,
+        &issue_ids[..],
+        &emails as &[&str]
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to delete completed issue delivery tasks.")?;
+    Ok(())
+}
+
+//exponential backoff, 2s, 4s, 8s... before the next attempt
+#[tracing::instrument(skip_all)]
+async fn reschedule_task(
+    transaction: &mut PgTransaction,
+    task: &Task,
+    error: &str,
+) -> Result<(), anyhow::Error> {
+    let attempts = task.attempts + 1;
+    if attempts >= MAX_DELIVERY_ATTEMPTS {
+        return dead_letter_task(transaction, task, attempts, error).await;
+    }
+    let backoff_seconds = 2_f64.powi(attempts);
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            attempts = $3,
+            next_attempt_at = now() + make_interval(secs => $4)
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        attempts,
+        backoff_seconds
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to reschedule a failed issue delivery task.")?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn postpone_task(
+    transaction: &mut PgTransaction,
+    task: &Task,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET next_attempt_at = now() + make_interval(secs => $3)
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        delay.as_secs_f64()
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to postpone an issue delivery task.")?;
+    Ok(())
+}
+
+//keyed by email, leaves out whoever is no longer confirmed or is suppressed
+#[tracing::instrument(skip_all)]
+async fn get_recipients(
+    pool: &PgPool,
+    tasks: &[Task],
+) -> Result<HashMap<String, Recipient>, anyhow::Error> {
+    let emails: Vec<&str> = tasks.iter().map(|task| task.subscriber_email.as_str()).collect();
+    let recipients = sqlx::query_as!(
+        Recipient,
+        r#"
+        SELECT email, name, unsubscribe_token, custom_fields
+        FROM subscriptions
+        WHERE
+            email = ANY($1) AND
+            status = 'confirmed' AND
+            lower(email) NOT IN (SELECT email FROM suppressed_emails)
+        "#,
+        &emails as &[&str]
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the details of the subscribers.")?;
+    Ok(recipients
+        .into_iter()
+        .map(|recipient| (recipient.email.clone(), recipient))
+        .collect())
+}
+
+//issues are validated at publish time, content that still does not parse
+//predates merge tags and goes out as written
+fn personalize(content: &str, fields: &MergeFields<'_>, escape: Escape) -> String {
+    match parse_issue_content(content) {
+        Ok(template) => render_issue_content(&template, fields, escape),
+        Err(_) => content.to_owned(),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, anyhow::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE
+            newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to fetch a newsletter issue.")?;
+    Ok(issue)
+}
+
+#[tracing::instrument(skip_all)]
+async fn dead_letter_task(
+    transaction: &mut PgTransaction,
+    task: &Task,
+    attempts: i32,
+    error: &str,
+) -> Result<(), anyhow::Error> {
+    let payload = DeadLetterPayload::NewsletterIssue {
+        newsletter_issue_id: task.newsletter_issue_id,
+    };
+    store_dead_letter(transaction, &task.subscriber_email, &payload, attempts, error).await?;
+    delete_tasks(transaction, &[task]).await
+}
@@ -1,8 +1,25 @@
 #![warn(rust_2018_idioms)]
+pub mod authentication;
+pub mod bot_protection;
+pub mod circuit_breaker;
+pub mod cleanup_worker;
+pub mod csv_stream;
 pub mod configuration;
+pub mod dead_letter;
 pub mod domain;
 pub mod greet;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod outbox;
+pub mod rate_limit;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
 pub mod startup;
+pub mod subscribers;
+pub mod suppression;
 pub mod telemetry;
-pub mod email_client;
\ No newline at end of file
+pub mod utils;
+pub mod email_client;
+pub mod email_templates;
+pub mod email_transport;
\ No newline at end of file
@@ -1,14 +1,7 @@
 #![warn(rust_2018_idioms)]
-use zero2prod::configuration::{get_configuration, self};
-use zero2prod::email_client::EmailClient;
-use zero2prod::startup::{run};
+use zero2prod::configuration::get_configuration;
 use zero2prod::telemetry::{get_subscriber, init_global_logger};
 
-// use secrecy::ExposeSecret;
-use sqlx::postgres::PgPoolOptions;
-// use sqlx::PgPool;
-use std::net::TcpListener;
-
 // Compose multiple layers into a `tracing`'s subscriber.
 ///
 /// # Implementation Notes
@@ -21,7 +14,7 @@ use std::net::TcpListener;
 /// later on.
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> anyhow::Result<()> {
     //set_boxed_logger convenience wrapper, calls set_logger with a function/closure that calls box::leak on
     //on the boxed dyn log
     //used to configure the log crate logger, facade pattern
@@ -0,0 +1,305 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+use crate::{
+    dead_letter::{store_dead_letter, DeadLetterPayload},
+    domain::SubscriberEmail,
+    email_transport::{EmailClientError, EmailTransport},
+    issue_delivery_worker::ExecutionOutcome,
+    suppression::is_suppressed,
+};
+
+//an email is moved to the dead-letter queue once it has failed this many times
+const MAX_SEND_ATTEMPTS: i32 = 5;
+//confirmation emails are waited on by a human, poll often
+const EMPTY_OUTBOX_BACKOFF: Duration = Duration::from_secs(1);
+//how long the dispatcher sleeps after failing to talk to the database
+const UNEXPECTED_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+//the provider is known to be down, try again once the breaker may have closed
+const CIRCUIT_OPEN_BACKOFF: Duration = Duration::from_secs(10);
+
+pub const SUBSCRIPTION_CONFIRMATION: &str = "subscription_confirmation";
+pub const PASSWORD_RESET: &str = "password_reset";
+
+pub struct OutboxEmail<'a> {
+    pub kind: &'a str,
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+}
+
+//only becomes visible to the dispatcher if the caller's transaction commits,
+//so nobody gets an email about a change that was rolled back
+#[tracing::instrument(name = "Add an email to the outbox", skip_all, fields(kind = email.kind))]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: OutboxEmail<'_>,
+) -> Result<Uuid, sqlx::Error> {
+    let outbox_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO outbox (
+            outbox_id,
+            kind,
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        outbox_id,
+        email.kind,
+        email.recipient.as_ref(),
+        email.subject,
+        email.html_content,
+        email.text_content,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(outbox_id)
+}
+
+struct PendingEmail {
+    outbox_id: Uuid,
+    kind: String,
+    recipient: String,
+    subject: String,
+    html_content: String,
+    text_content: String,
+    attempts: i32,
+}
+
+//loops forever, the only way out is the runtime shutting down
+pub async fn run_outbox_dispatcher_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailTransport>,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_dispatch_email(&pool, email_client.as_ref()).await {
+            Ok(ExecutionOutcome::EmptyQueue) => tokio::time::sleep(EMPTY_OUTBOX_BACKOFF).await,
+            Err(_) => tokio::time::sleep(UNEXPECTED_ERROR_BACKOFF).await,
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+//at least once: a crash between the provider accepting the email and the
+//commit below sends it again on the next attempt
+#[tracing::instrument(
+    skip_all,
+    fields(outbox_id = tracing::field::Empty, kind = tracing::field::Empty),
+    err
+)]
+pub async fn try_dispatch_email(
+    pool: &PgPool,
+    email_client: &dyn EmailTransport,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let Some((mut transaction, email)) = claim_pending_email(pool).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    Span::current()
+        .record("outbox_id", display(email.outbox_id))
+        .record("kind", display(&email.kind));
+    let recipient = match SubscriberEmail::parse(email.recipient.clone()) {
+        Ok(recipient) => recipient,
+        //retrying will not fix an address that no longer parses
+        Err(e) => {
+            tracing::warn!(error.message = %e, "Dropping an outbox email with an invalid recipient.");
+            return drop_email(transaction, &email).await;
+        }
+    };
+    //the address may have bounced or complained since the email was enqueued
+    if is_suppressed(&mut transaction, &email.recipient)
+        .await
+        .context("Failed to check the suppression list.")?
+    {
+        tracing::info!("Dropping an outbox email to a suppressed recipient.");
+        return drop_email(transaction, &email).await;
+    }
+    match email_client
+        .send_email(
+            recipient,
+            &email.subject,
+            &email.html_content,
+            &email.text_content,
+        )
+        .await
+    {
+        Ok(sent) => {
+            mark_as_sent(transaction, &email, sent.message_id.as_deref()).await?;
+        }
+        //not the email's fault, this attempt does not count
+        Err(EmailClientError::CircuitOpen(_)) => {
+            postpone_email(transaction, &email, CIRCUIT_OPEN_BACKOFF).await?;
+        }
+        //retrying cannot help, keep it for an admin to look at
+        Err(e @ EmailClientError::RecipientRejected { .. }) => {
+            tracing::warn!(
+                error.message = %e,
+                "Dead-lettering an outbox email. The email provider rejected the recipient",
+            );
+            dead_letter_email(transaction, &email, email.attempts + 1, &e.to_string()).await?;
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                attempts = email.attempts + 1,
+                "Failed to send an outbox email.",
+            );
+            reschedule_email(transaction, &email, &e.to_string(), e.retry_after()).await?;
+        }
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+//same locking as the issue delivery queue, concurrent dispatchers skip
+//rows another one is working on
+#[tracing::instrument(skip_all)]
+async fn claim_pending_email(
+    pool: &PgPool,
+) -> Result<Option<(PgTransaction, PendingEmail)>, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let email = sqlx::query_as!(
+        PendingEmail,
+        r#"
+        SELECT outbox_id, kind, recipient, subject, html_content, text_content, attempts
+        FROM outbox
+        WHERE sent_at IS NULL AND next_attempt_at <= now()
+        ORDER BY next_attempt_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to claim a pending outbox email.")?;
+    Ok(email.map(|email| (transaction, email)))
+}
+
+#[tracing::instrument(skip_all)]
+async fn mark_as_sent(
+    mut transaction: PgTransaction,
+    email: &PendingEmail,
+    message_id: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE outbox
+        SET sent_at = now(), message_id = $2, attempts = attempts + 1
+        WHERE outbox_id = $1
+        "#,
+        email.outbox_id,
+        message_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to mark an outbox email as sent.")?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+//exponential backoff, 2s, 4s, 8s... before the next attempt
+#[tracing::instrument(skip_all)]
+async fn reschedule_email(
+    mut transaction: PgTransaction,
+    email: &PendingEmail,
+    error: &str,
+    retry_after: Option<Duration>,
+) -> Result<(), anyhow::Error> {
+    let attempts = email.attempts + 1;
+    //never come back before the provider told us to
+    let backoff = retry_after
+        .map_or(0_f64, |d| d.as_secs_f64())
+        .max(2_f64.powi(attempts));
+    if attempts >= MAX_SEND_ATTEMPTS {
+        return dead_letter_email(transaction, email, attempts, error).await;
+    }
+    sqlx::query!(
+        r#"
+        UPDATE outbox
+        SET
+            attempts = $2,
+            last_error = $3,
+            next_attempt_at = now() + make_interval(secs => $4)
+        WHERE outbox_id = $1
+        "#,
+        email.outbox_id,
+        attempts,
+        error,
+        backoff
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to reschedule an outbox email.")?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn postpone_email(
+    mut transaction: PgTransaction,
+    email: &PendingEmail,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE outbox
+        SET next_attempt_at = now() + make_interval(secs => $2)
+        WHERE outbox_id = $1
+        "#,
+        email.outbox_id,
+        delay.as_secs_f64()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to postpone an outbox email.")?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn dead_letter_email(
+    mut transaction: PgTransaction,
+    email: &PendingEmail,
+    attempts: i32,
+    error: &str,
+) -> Result<(), anyhow::Error> {
+    let payload = DeadLetterPayload::Outbox {
+        kind: email.kind.clone(),
+        subject: email.subject.clone(),
+        html_content: email.html_content.clone(),
+        text_content: email.text_content.clone(),
+    };
+    store_dead_letter(&mut transaction, &email.recipient, &payload, attempts, error).await?;
+    drop_email(transaction, email).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn drop_email(
+    mut transaction: PgTransaction,
+    email: &PendingEmail,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    sqlx::query!(
+        r#"DELETE FROM outbox WHERE outbox_id = $1"#,
+        email.outbox_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to drop an outbox email.")?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{take_token, RateLimitDecision, RateLimiter, TokenBucket};
+
+//buckets untouched for this long are full again for any sensible limit
+const IDLE_BUCKET_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+//hard cap, past it the least recently used bucket is dropped. A flood of
+//new keys can push out a limited one, which then starts from a full bucket:
+//use the postgres backend where that matters
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+//per process, every instance behind a load balancer counts on its own
+pub struct InMemoryRateLimiter {
+    state: Mutex<Buckets>,
+    max_keys: usize,
+}
+
+#[derive(Default)]
+struct Buckets {
+    //key -> (tokens, last update, position in by_last_use)
+    by_key: HashMap<String, (f64, Instant, u64)>,
+    //least recently used first, so evicting is a pop instead of a scan
+    by_last_use: BTreeMap<u64, String>,
+    next_position: u64,
+}
+
+impl Default for InMemoryRateLimiter {
+    fn default() -> Self {
+        Self::with_max_keys(MAX_TRACKED_KEYS)
+    }
+}
+
+impl InMemoryRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_keys(max_keys: usize) -> Self {
+        Self {
+            state: Mutex::new(Buckets::default()),
+            max_keys,
+        }
+    }
+}
+
+impl Buckets {
+    fn evict(&mut self, now: Instant, max_keys: usize) {
+        while let Some(entry) = self.by_last_use.first_entry() {
+            let (_, updated_at, _) = self.by_key[entry.get()];
+            if self.by_key.len() <= max_keys && now - updated_at < IDLE_BUCKET_EXPIRY {
+                break;
+            }
+            let key = entry.remove();
+            self.by_key.remove(&key);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimiter for InMemoryRateLimiter {
+    async fn check(
+        &self,
+        key: &str,
+        bucket: &TokenBucket,
+    ) -> Result<RateLimitDecision, anyhow::Error> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let (tokens, updated_at) = match state.by_key.get(key) {
+            Some(&(tokens, updated_at, position)) => {
+                state.by_last_use.remove(&position);
+                (tokens, updated_at)
+            }
+            None => (bucket.capacity as f64, now),
+        };
+        let (tokens, decision) = take_token(tokens, now - updated_at, bucket);
+        let position = state.next_position;
+        state.next_position += 1;
+        state.by_last_use.insert(position, key.to_owned());
+        state.by_key.insert(key.to_owned(), (tokens, now, position));
+        state.evict(now, self.max_keys);
+        Ok(decision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InMemoryRateLimiter;
+    use crate::rate_limit::{RateLimitDecision, RateLimiter, TokenBucket};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn keys_are_limited_independently() {
+        let limiter = InMemoryRateLimiter::new();
+        let bucket = TokenBucket {
+            capacity: 2,
+            refill_interval: Duration::from_secs(60),
+        };
+        for _ in 0..2 {
+            assert_eq!(
+                limiter.check("a", &bucket).await.unwrap(),
+                RateLimitDecision::Allowed
+            );
+        }
+        assert!(matches!(
+            limiter.check("a", &bucket).await.unwrap(),
+            RateLimitDecision::Limited { .. }
+        ));
+        assert_eq!(
+            limiter.check("b", &bucket).await.unwrap(),
+            RateLimitDecision::Allowed
+        );
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_key_is_dropped_at_the_cap() {
+        let limiter = InMemoryRateLimiter::with_max_keys(2);
+        let bucket = TokenBucket {
+            capacity: 1,
+            refill_interval: Duration::from_secs(60),
+        };
+        for key in ["a", "b", "c"] {
+            assert_eq!(
+                limiter.check(key, &bucket).await.unwrap(),
+                RateLimitDecision::Allowed
+            );
+        }
+        assert_eq!(limiter.state.lock().unwrap().by_key.len(), 2);
+        //"a" was forgotten and starts over, "c" is still tracked
+        assert_eq!(
+            limiter.check("a", &bucket).await.unwrap(),
+            RateLimitDecision::Allowed
+        );
+        assert!(matches!(
+            limiter.check("c", &bucket).await.unwrap(),
+            RateLimitDecision::Limited { .. }
+        ));
+    }
+}
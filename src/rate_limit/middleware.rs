@@ -0,0 +1,125 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::web;
+use actix_web_lab::middleware::Next;
+
+use super::{request_client_ip, too_many_requests, RateLimitDecision, RateLimiter, TokenBucket};
+
+//the limits guarding the public subscription form
+pub struct SubscribeRateLimits {
+    pub limiter: Arc<dyn RateLimiter>,
+    //form submissions per client ip
+    pub per_ip: TokenBucket,
+    //confirmation emails per target address, so the form cannot be
+    //used to flood someone else's inbox
+    pub per_address: TokenBucket,
+    //peers whose X-Forwarded-For we believe, e.g. our load balancer
+    pub trusted_proxies: Vec<IpAddr>,
+}
+impl SubscribeRateLimits {
+    //Some(retry_after) once the client ip is out of tokens
+    pub async fn check_ip(&self, ip: IpAddr) -> Option<Duration> {
+        self.check(&format!("subscribe:ip:{}", ip), &self.per_ip)
+            .await
+    }
+
+    pub async fn check_confirmation_email(&self, email: &str) -> Option<Duration> {
+        self.check(
+            &format!("subscribe:email:{}", email.to_lowercase()),
+            &self.per_address,
+        )
+        .await
+    }
+
+    async fn check(&self, key: &str, bucket: &TokenBucket) -> Option<Duration> {
+        check(self.limiter.as_ref(), key, bucket).await
+    }
+}
+
+//the limits guarding the public password reset form
+pub struct PasswordResetRateLimits {
+    pub limiter: Arc<dyn RateLimiter>,
+    //form submissions per client ip
+    pub per_ip: TokenBucket,
+    //reset emails per target address, answered like any other request once
+    //exceeded so the limit does not give away which addresses have an account
+    pub per_address: TokenBucket,
+    pub trusted_proxies: Vec<IpAddr>,
+}
+impl PasswordResetRateLimits {
+    pub async fn check_ip(&self, ip: IpAddr) -> Option<Duration> {
+        check(
+            self.limiter.as_ref(),
+            &format!("password_reset:ip:{}", ip),
+            &self.per_ip,
+        )
+        .await
+    }
+
+    pub async fn check_reset_email(&self, email: &str) -> Option<Duration> {
+        check(
+            self.limiter.as_ref(),
+            &format!("password_reset:email:{}", email.to_lowercase()),
+            &self.per_address,
+        )
+        .await
+    }
+}
+
+//fails open, a broken limiter must not take the public forms down with it
+async fn check(limiter: &dyn RateLimiter, key: &str, bucket: &TokenBucket) -> Option<Duration> {
+    match limiter.check(key, bucket).await {
+        Ok(RateLimitDecision::Allowed) => None,
+        Ok(RateLimitDecision::Limited { retry_after }) => {
+            tracing::warn!(key, ?retry_after, "Rate limit exceeded.");
+            Some(retry_after)
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to check a rate limit, letting the request through.",
+            );
+            None
+        }
+    }
+}
+
+pub async fn limit_subscriptions_per_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limits = req
+        .app_data::<web::Data<SubscribeRateLimits>>()
+        .expect("the subscribe rate limits are registered as app data")
+        .clone();
+    if let Some(ip) = request_client_ip(req.request(), &limits.trusted_proxies) {
+        if let Some(retry_after) = limits.check_ip(ip).await {
+            let e = anyhow::anyhow!("Too many subscription attempts from {}", ip);
+            return Err(InternalError::from_response(e, too_many_requests(retry_after)).into());
+        }
+    }
+    next.call(req).await
+}
+
+pub async fn limit_password_resets_per_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limits = req
+        .app_data::<web::Data<PasswordResetRateLimits>>()
+        .expect("the password reset rate limits are registered as app data")
+        .clone();
+    if let Some(ip) = request_client_ip(req.request(), &limits.trusted_proxies) {
+        if let Some(retry_after) = limits.check_ip(ip).await {
+            let e = anyhow::anyhow!("Too many password reset requests from {}", ip);
+            return Err(InternalError::from_response(e, too_many_requests(retry_after)).into());
+        }
+    }
+    next.call(req).await
+}
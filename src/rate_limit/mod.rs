@@ -0,0 +1,167 @@
+mod memory;
+mod middleware;
+mod postgres;
+
+pub use memory::InMemoryRateLimiter;
+pub use middleware::{
+    limit_password_resets_per_ip, limit_subscriptions_per_ip, PasswordResetRateLimits,
+    SubscribeRateLimits,
+};
+pub use postgres::PostgresRateLimiter;
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use actix_web::http::header::{HeaderName, RETRY_AFTER};
+use actix_web::{HttpRequest, HttpResponse};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+//holds up to `capacity` tokens, a new one drips in every `refill_interval`,
+//every request takes one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenBucket {
+    pub capacity: u32,
+    pub refill_interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+#[async_trait::async_trait]
+pub trait RateLimiter: Send + Sync {
+    async fn check(&self, key: &str, bucket: &TokenBucket)
+        -> Result<RateLimitDecision, anyhow::Error>;
+}
+
+//the bucket math shared by every backend, returns the tokens left after
+//refilling for `elapsed` and trying to take one
+pub(crate) fn take_token(
+    tokens: f64,
+    elapsed: Duration,
+    bucket: &TokenBucket,
+) -> (f64, RateLimitDecision) {
+    let refilled = (tokens + elapsed.as_secs_f64() / bucket.refill_interval.as_secs_f64())
+        .min(bucket.capacity as f64);
+    if refilled >= 1.0 {
+        (refilled - 1.0, RateLimitDecision::Allowed)
+    } else {
+        let retry_after = bucket.refill_interval.mul_f64(1.0 - refilled);
+        (refilled, RateLimitDecision::Limited { retry_after })
+    }
+}
+
+//the peer is the client unless it is one of our proxies, then X-Forwarded-For is
+//walked right to left and the first hop we do not trust is the client
+pub fn client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    if !trusted_proxies.contains(&client) {
+        return Some(client);
+    }
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !trusted_proxies.contains(&ip) {
+                    break;
+                }
+            }
+            //anything left of garbage was not written by our proxies
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+//client_ip for an incoming request, every X-Forwarded-For header counts
+pub fn request_client_ip(request: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let forwarded_for = request
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    client_ip(
+        request.peer_addr().map(|address| address.ip()),
+        Some(&forwarded_for),
+        trusted_proxies,
+    )
+}
+
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    //whole seconds, rounded up so a client honouring it is not limited again
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, seconds.max(1).to_string()))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, take_token, RateLimitDecision, TokenBucket};
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    const BUCKET: TokenBucket = TokenBucket {
+        capacity: 2,
+        refill_interval: Duration::from_secs(10),
+    };
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn an_empty_bucket_is_limited_until_a_token_drips_in() {
+        let (tokens, decision) = take_token(0.5, Duration::ZERO, &BUCKET);
+        assert_eq!(tokens, 0.5);
+        assert_eq!(
+            decision,
+            RateLimitDecision::Limited {
+                retry_after: Duration::from_secs(5)
+            }
+        );
+        let (tokens, decision) = take_token(0.5, Duration::from_secs(5), &BUCKET);
+        assert_eq!(tokens, 0.0);
+        assert_eq!(decision, RateLimitDecision::Allowed);
+    }
+
+    #[test]
+    fn refills_never_exceed_the_capacity() {
+        let (tokens, decision) = take_token(0.0, Duration::from_secs(3600), &BUCKET);
+        assert_eq!(tokens, 1.0);
+        assert_eq!(decision, RateLimitDecision::Allowed);
+    }
+
+    #[test]
+    fn the_peer_is_the_client_when_it_is_not_a_trusted_proxy() {
+        let client = client_ip(Some(ip("203.0.113.7")), Some("198.51.100.1"), &[ip("10.0.0.1")]);
+        assert_eq!(client, Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn forwarded_for_is_walked_past_trusted_proxies_only() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        //the left-most entry is client controlled and must be ignored
+        let client = client_ip(
+            Some(ip("10.0.0.1")),
+            Some("1.1.1.1, 203.0.113.7, 10.0.0.2"),
+            &trusted,
+        );
+        assert_eq!(client, Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn garbage_in_forwarded_for_stops_the_walk() {
+        let trusted = [ip("10.0.0.1")];
+        let client = client_ip(Some(ip("10.0.0.1")), Some("203.0.113.7, nonsense"), &trusted);
+        assert_eq!(client, Some(ip("10.0.0.1")));
+    }
+}
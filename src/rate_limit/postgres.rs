@@ -0,0 +1,70 @@
+use anyhow::Context;
+use sqlx::PgPool;
+
+use super::{take_token, RateLimitDecision, RateLimiter, TokenBucket};
+
+//shared by every instance pointing at the same database
+pub struct PostgresRateLimiter {
+    pool: PgPool,
+}
+impl PostgresRateLimiter {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimiter for PostgresRateLimiter {
+    #[tracing::instrument(name = "Check a rate limit", skip(self, bucket))]
+    async fn check(
+        &self,
+        key: &str,
+        bucket: &TokenBucket,
+    ) -> Result<RateLimitDecision, anyhow::Error> {
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool")?;
+        //a new key starts full, the row lock then serializes concurrent checks
+        sqlx::query!(
+            r#"
+            INSERT INTO rate_limit_buckets (key, tokens, updated_at)
+            VALUES ($1, $2, now())
+            ON CONFLICT (key) DO NOTHING
+            "#,
+            key,
+            bucket.capacity as f64
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to create a rate limit bucket.")?;
+        let row = sqlx::query!(
+            r#"
+            SELECT tokens, extract(epoch FROM now() - updated_at)::float8 AS "elapsed_seconds!"
+            FROM rate_limit_buckets
+            WHERE key = $1
+            FOR UPDATE
+            "#,
+            key
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .context("Failed to fetch a rate limit bucket.")?;
+        let elapsed = std::time::Duration::from_secs_f64(row.elapsed_seconds.max(0.0));
+        let (tokens, decision) = take_token(row.tokens, elapsed, bucket);
+        sqlx::query!(
+            r#"UPDATE rate_limit_buckets SET tokens = $2, updated_at = now() WHERE key = $1"#,
+            key,
+            tokens
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to update a rate limit bucket.")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to check a rate limit.")?;
+        Ok(decision)
+    }
+}
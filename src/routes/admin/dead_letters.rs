@@ -0,0 +1,96 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::dead_letter::{discard, fetch_dead_letter, fetch_dead_letters, requeue};
+use crate::routes::error_chain_fmt;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DeadLetterFilter {
+    //newsletter_issue or outbox
+    source: Option<String>,
+    limit: Option<i64>,
+}
+
+#[tracing::instrument(name = "List dead letters", skip(pool))]
+pub async fn list_dead_letters(
+    filter: web::Query<DeadLetterFilter>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let dead_letters = fetch_dead_letters(&pool, filter.source.as_deref(), limit)
+        .await
+        .context("Failed to list dead letters.")?;
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+#[tracing::instrument(name = "Inspect a dead letter", skip(pool))]
+pub async fn inspect_dead_letter(
+    dead_letter_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let dead_letter_id = dead_letter_id.into_inner();
+    let dead_letter = fetch_dead_letter(&pool, dead_letter_id)
+        .await
+        .context("Failed to fetch a dead letter.")?
+        .ok_or(DeadLetterError::NotFound(dead_letter_id))?;
+    Ok(HttpResponse::Ok().json(dead_letter))
+}
+
+#[tracing::instrument(name = "Requeue a dead letter", skip(pool))]
+pub async fn requeue_dead_letter(
+    dead_letter_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let dead_letter_id = dead_letter_id.into_inner();
+    if !requeue(&pool, dead_letter_id).await? {
+        return Err(DeadLetterError::NotFound(dead_letter_id));
+    }
+    tracing::info!("A dead letter was requeued by an admin.");
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[tracing::instrument(name = "Discard a dead letter", skip(pool))]
+pub async fn discard_dead_letter(
+    dead_letter_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let dead_letter_id = dead_letter_id.into_inner();
+    if !discard(&pool, dead_letter_id)
+        .await
+        .context("Failed to discard a dead letter.")?
+    {
+        return Err(DeadLetterError::NotFound(dead_letter_id));
+    }
+    tracing::info!("A dead letter was discarded by an admin.");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(thiserror::Error)]
+pub enum DeadLetterError {
+    #[error("There is no dead letter with id {0}.")]
+    NotFound(Uuid),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for DeadLetterError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DeadLetterError::NotFound(_) => StatusCode::NOT_FOUND,
+            DeadLetterError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+impl std::fmt::Debug for DeadLetterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
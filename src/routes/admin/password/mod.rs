@@ -0,0 +1,4 @@
+mod get;
+mod post;
+pub use get::change_password_form;
+pub use post::change_password;
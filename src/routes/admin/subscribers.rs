@@ -0,0 +1,433 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::StatusCode;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::domain::{
+    IllegalStatusTransition, NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus,
+};
+use crate::email_templates::EmailTemplates;
+use crate::routes::{
+    enqueue_confirmation_email, error_chain_fmt, generate_subscription_token, store_token,
+};
+use crate::startup::ApplicationBaseUrl;
+use crate::subscribers::{change_status, reconfirm_new_email, StatusChangeError};
+use crate::suppression::{is_suppressed, suppress, SuppressionReason};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(serde::Serialize)]
+pub struct Subscriber {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: SubscriptionStatus,
+    pub subscribed_at: DateTime<Utc>,
+    pub custom_fields: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    //newest first
+    #[default]
+    Desc,
+}
+
+//the cursor only makes sense with the filters and order it was issued for
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SubscriberFilter {
+    status: Option<SubscriptionStatus>,
+    subscribed_after: Option<DateTime<Utc>>,
+    subscribed_before: Option<DateTime<Utc>>,
+    //case insensitive substring match
+    email: Option<String>,
+    #[serde(default)]
+    order: SortOrder,
+    //ignored by the export, it always starts at the top and goes to the end
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SubscriberPage {
+    subscribers: Vec<Subscriber>,
+    //None on the last page
+    next_cursor: Option<String>,
+}
+
+//keyset pagination on (subscribed_at, id), id breaks ties between
+//subscribers who signed up in the same microsecond
+pub(super) struct Cursor {
+    pub(super) subscribed_at: DateTime<Utc>,
+    pub(super) id: Uuid,
+}
+
+impl Cursor {
+    pub(super) fn encode(&self) -> String {
+        let raw = format!(
+            "{}|{}",
+            self.subscribed_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.id
+        );
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    pub(super) fn decode(cursor: &str) -> Option<Self> {
+        let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()?;
+        let raw = String::from_utf8(raw).ok()?;
+        let (subscribed_at, id) = raw.split_once('|')?;
+        Some(Self {
+            subscribed_at: DateTime::parse_from_rfc3339(subscribed_at)
+                .ok()?
+                .with_timezone(&Utc),
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+#[tracing::instrument(name = "List subscribers", skip(pool))]
+pub async fn list_subscribers(
+    filter: web::Query<SubscriberFilter>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let cursor = filter
+        .cursor
+        .as_deref()
+        .map(|cursor| {
+            Cursor::decode(cursor)
+                .ok_or_else(|| SubscriberAdminError::ValidationError("Invalid cursor.".into()))
+        })
+        .transpose()?;
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    //one row more than asked for tells us whether there is a next page
+    let mut subscribers = fetch_subscriber_page(&pool, &filter, cursor.as_ref(), limit + 1)
+        .await
+        .context("Failed to list subscribers.")?;
+    let next_cursor = if subscribers.len() as i64 > limit {
+        subscribers.truncate(limit as usize);
+        subscribers.last().map(|last| {
+            Cursor {
+                subscribed_at: last.subscribed_at,
+                id: last.id,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(SubscriberPage {
+        subscribers,
+        next_cursor,
+    }))
+}
+
+//limit and cursor in the filter are left to the caller
+#[tracing::instrument(skip(pool, cursor))]
+pub(super) async fn fetch_subscriber_page(
+    pool: &PgPool,
+    filter: &SubscriberFilter,
+    cursor: Option<&Cursor>,
+    limit: i64,
+) -> Result<Vec<Subscriber>, sqlx::Error> {
+    //LIKE wildcards in the search term are matched literally
+    let email = filter.email.as_deref().map(|email| {
+        email
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    });
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT id, email, name, status AS "status: SubscriptionStatus", subscribed_at, custom_fields
+        FROM subscriptions
+        WHERE
+            ($1::text IS NULL OR status = $1) AND
+            ($2::timestamptz IS NULL OR subscribed_at >= $2) AND
+            ($3::timestamptz IS NULL OR subscribed_at < $3) AND
+            ($4::text IS NULL OR email ILIKE '%' || $4 || '%') AND
+            (
+                $5::timestamptz IS NULL OR
+                ($7::bool AND (subscribed_at, id) > ($5, $6::uuid)) OR
+                (NOT $7::bool AND (subscribed_at, id) < ($5, $6::uuid))
+            )
+        ORDER BY
+            CASE WHEN $7 THEN subscribed_at END ASC,
+            CASE WHEN $7 THEN id END ASC,
+            CASE WHEN NOT $7 THEN subscribed_at END DESC,
+            CASE WHEN NOT $7 THEN id END DESC
+        LIMIT $8
+        "#,
+        filter.status.map(|status| status.as_str()),
+        filter.subscribed_after,
+        filter.subscribed_before,
+        email,
+        cursor.map(|cursor| cursor.subscribed_at),
+        cursor.map(|cursor| cursor.id),
+        matches!(filter.order, SortOrder::Asc),
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[tracing::instrument(name = "Get a subscriber", skip(pool))]
+pub async fn inspect_subscriber(
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let subscriber_id = subscriber_id.into_inner();
+    let subscriber = fetch_subscriber(pool.get_ref(), subscriber_id)
+        .await
+        .context("Failed to fetch a subscriber.")?
+        .ok_or(SubscriberAdminError::NotFound(subscriber_id))?;
+    Ok(HttpResponse::Ok().json(subscriber))
+}
+
+//every field is optional, only the ones present are changed
+#[derive(Debug, serde::Deserialize)]
+pub struct SubscriberChanges {
+    name: Option<String>,
+    email: Option<String>,
+    status: Option<SubscriptionStatus>,
+}
+
+//a new email has to be confirmed again, like a new subscription.
+//marking someone bounced or complained suppresses the address, as the webhook does
+#[tracing::instrument(
+    name = "Update a subscriber",
+    skip(changes, pool, email_templates, base_url)
+)]
+pub async fn update_subscriber(
+    subscriber_id: web::Path<Uuid>,
+    changes: web::Json<SubscriberChanges>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let subscriber_id = subscriber_id.into_inner();
+    let changes = changes.into_inner();
+    //validate everything before touching the database
+    let name = changes
+        .name
+        .map(SubscriberName::parse)
+        .transpose()
+        .map_err(SubscriberAdminError::ValidationError)?;
+    let email = changes
+        .email
+        .map(SubscriberEmail::parse)
+        .transpose()
+        .map_err(SubscriberAdminError::ValidationError)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let current = fetch_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to fetch a subscriber.")?
+        .ok_or(SubscriberAdminError::NotFound(subscriber_id))?;
+    //fixing the case of an address does not make it a new one
+    let email = email.filter(|email| !email.as_ref().eq_ignore_ascii_case(&current.email));
+    if let Some(email) = &email {
+        if is_suppressed(&mut transaction, email.as_ref())
+            .await
+            .context("Failed to check the suppression list.")?
+        {
+            return Err(SubscriberAdminError::Suppressed);
+        }
+    }
+    if let Some(name) = name {
+        sqlx::query!(
+            r#"UPDATE subscriptions SET name = $2 WHERE id = $1"#,
+            subscriber_id,
+            name.as_ref()
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to update the name of a subscriber.")?;
+    }
+    if let Some(email) = &email {
+        reconfirm_new_email(&mut transaction, subscriber_id)
+            .await
+            .map_err(status_change_error)?;
+        sqlx::query!(
+            r#"UPDATE subscriptions SET email = $2 WHERE id = $1"#,
+            subscriber_id,
+            email.as_ref()
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|e| match e.as_database_error().and_then(|e| e.code()) {
+            //unique_violation
+            Some(code) if code == "23505" => SubscriberAdminError::EmailTaken,
+            _ => SubscriberAdminError::UnexpectedError(
+                anyhow::Error::new(e).context("Failed to update the email of a subscriber."),
+            ),
+        })?;
+        //links sent to the old address must not confirm the new one
+        sqlx::query!(
+            r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+            subscriber_id
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to delete the confirmation tokens of a subscriber.")?;
+    }
+    if let Some(status) = changes.status {
+        change_status(&mut transaction, subscriber_id, status)
+            .await
+            .map_err(status_change_error)?;
+    }
+    let subscriber = fetch_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to fetch an updated subscriber.")?
+        .ok_or(SubscriberAdminError::NotFound(subscriber_id))?;
+    let suppression = match changes.status {
+        Some(SubscriptionStatus::Bounced) => Some(SuppressionReason::HardBounce),
+        Some(SubscriptionStatus::Complained) => Some(SuppressionReason::SpamComplaint),
+        _ => None,
+    };
+    if let Some(reason) = suppression {
+        suppress(&mut transaction, &subscriber.email, reason)
+            .await
+            .context("Failed to suppress an email address.")?;
+    }
+    //unless the admin also confirmed them in the same request
+    if let (Some(email), SubscriptionStatus::PendingConfirmation) = (email, subscriber.status) {
+        let new_subscriber = NewSubscriber {
+            name: SubscriberName::parse(subscriber.name.clone())
+                .map_err(|e| anyhow::anyhow!(e))
+                .context("The stored name of a subscriber is not valid.")?,
+            email,
+        };
+        let subscription_token = generate_subscription_token();
+        store_token(&mut transaction, subscriber_id, &subscription_token)
+            .await
+            .context("Failed to store the confirmation token for a changed email.")?;
+        enqueue_confirmation_email(
+            &mut transaction,
+            &email_templates,
+            &new_subscriber,
+            &base_url.0,
+            &subscription_token,
+        )
+        .await
+        .context("Failed to add the confirmation email to the outbox.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update a subscriber.")?;
+    tracing::info!("A subscriber was updated by an admin.");
+    Ok(HttpResponse::Ok().json(subscriber))
+}
+
+fn status_change_error(e: StatusChangeError) -> SubscriberAdminError {
+    match e {
+        StatusChangeError::IllegalTransition(e) => SubscriberAdminError::IllegalTransition(e),
+        e => SubscriberAdminError::UnexpectedError(
+            anyhow::Error::new(e).context("Failed to change the status of a subscriber."),
+        ),
+    }
+}
+
+#[tracing::instrument(name = "Delete a subscriber", skip(pool))]
+pub async fn delete_subscriber(
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let subscriber_id = subscriber_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    //outstanding confirmation links point at the subscriber
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete the confirmation tokens of a subscriber.")?;
+    let deleted = sqlx::query!(
+        r#"DELETE FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete a subscriber.")?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(SubscriberAdminError::NotFound(subscriber_id));
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to delete a subscriber.")?;
+    tracing::info!("A subscriber was deleted by an admin.");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+//locks the row when run in a transaction, updates check it before writing
+#[tracing::instrument(skip(executor))]
+async fn fetch_subscriber<'e>(
+    executor: impl PgExecutor<'e>,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT id, email, name, status AS "status: SubscriptionStatus", subscribed_at, custom_fields
+        FROM subscriptions
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscriberAdminError {
+    #[error("There is no subscriber with id {0}.")]
+    NotFound(Uuid),
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("Another subscriber already uses this email.")]
+    EmailTaken,
+    #[error("The email is on the suppression list.")]
+    Suppressed,
+    #[error(transparent)]
+    IllegalTransition(IllegalStatusTransition),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for SubscriberAdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscriberAdminError::NotFound(_) => StatusCode::NOT_FOUND,
+            SubscriberAdminError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscriberAdminError::EmailTaken => StatusCode::CONFLICT,
+            SubscriberAdminError::Suppressed => StatusCode::CONFLICT,
+            SubscriberAdminError::IllegalTransition(_) => StatusCode::CONFLICT,
+            SubscriberAdminError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+impl std::fmt::Debug for SubscriberAdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
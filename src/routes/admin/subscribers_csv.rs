@@ -0,0 +1,513 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use futures_util::{stream, StreamExt};
+use reqwest::StatusCode;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::subscribers::{fetch_subscriber_page, Cursor, Subscriber, SubscriberFilter};
+use crate::csv_stream::{CsvRecord, CsvRecordParser};
+use crate::domain::{NewSubscriber, SubscriptionStatus};
+use crate::email_templates::EmailTemplates;
+use crate::routes::{
+    enqueue_confirmation_email, error_chain_fmt, generate_subscription_token, store_token,
+};
+use crate::startup::ApplicationBaseUrl;
+use crate::utils::generate_random_token;
+
+//rows are written in batches, each in its own transaction
+const IMPORT_BATCH_SIZE: usize = 500;
+const EXPORT_PAGE_SIZE: i64 = 1000;
+//columns of our own export that the import decides for itself
+const IGNORED_COLUMNS: [&str; 3] = ["id", "status", "subscribed_at"];
+const CUSTOM_FIELDS_COLUMN: &str = "custom_fields";
+//spreadsheets run cells starting with these as formulas
+const FORMULA_PREFIXES: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    //subscribers confirm through the usual email
+    #[default]
+    SendConfirmation,
+    //they already confirmed with the tool we are migrating from
+    Confirmed,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ImportParameters {
+    #[serde(default)]
+    mode: ImportMode,
+}
+
+#[derive(Default, serde::Serialize)]
+pub struct ImportReport {
+    imported: u64,
+    rejected: u64,
+    errors: Vec<RowError>,
+}
+
+//rows are numbered like a spreadsheet would, the header is row 1
+#[derive(serde::Serialize)]
+struct RowError {
+    row: u64,
+    email: Option<String>,
+    error: String,
+}
+
+struct Header {
+    email: usize,
+    name: usize,
+    //every other column becomes a custom field, see email_templates::merge_tags
+    custom_fields: Vec<(usize, String)>,
+    custom_fields_json: Option<usize>,
+    len: usize,
+}
+
+struct ImportRow {
+    row: u64,
+    subscriber: NewSubscriber,
+    custom_fields: serde_json::Value,
+}
+
+//a CSV with `email` and `name` columns, parsed as it is uploaded.
+//batches are committed as they fill up: if the import fails part way,
+//running it again skips the rows that made it in as duplicates
+#[tracing::instrument(
+    name = "Import subscribers",
+    skip(payload, pool, email_templates, base_url),
+    fields(imported = tracing::field::Empty, rejected = tracing::field::Empty)
+)]
+pub async fn import_subscribers(
+    parameters: web::Query<ImportParameters>,
+    mut payload: web::Payload,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, ImportError> {
+    let mut importer = Importer {
+        mode: parameters.mode,
+        pool: &pool,
+        email_templates: &email_templates,
+        base_url: &base_url.0,
+        header: None,
+        row: 0,
+        seen: HashSet::new(),
+        batch: Vec::with_capacity(IMPORT_BATCH_SIZE),
+        report: ImportReport::default(),
+    };
+    let mut parser = CsvRecordParser::default();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| ImportError::InvalidUpload(e.to_string()))?;
+        let records = parser
+            .feed(&chunk)
+            .map_err(|e| ImportError::InvalidUpload(e.to_string()))?;
+        for record in records {
+            importer.push(record).await?;
+        }
+    }
+    let last = parser
+        .finish()
+        .map_err(|e| ImportError::InvalidUpload(e.to_string()))?;
+    if let Some(record) = last {
+        importer.push(record).await?;
+    }
+    if importer.header.is_none() {
+        return Err(ImportError::InvalidUpload("The CSV file is empty.".into()));
+    }
+    importer.flush().await?;
+    let report = importer.report;
+    tracing::Span::current()
+        .record("imported", report.imported)
+        .record("rejected", report.rejected);
+    Ok(HttpResponse::Ok().json(report))
+}
+
+struct Importer<'a> {
+    mode: ImportMode,
+    pool: &'a PgPool,
+    email_templates: &'a EmailTemplates,
+    base_url: &'a str,
+    header: Option<Header>,
+    row: u64,
+    //duplicates within the file, the database catches the existing ones
+    seen: HashSet<String>,
+    batch: Vec<ImportRow>,
+    report: ImportReport,
+}
+
+impl Importer<'_> {
+    async fn push(&mut self, record: CsvRecord) -> Result<(), ImportError> {
+        self.row += 1;
+        let Some(header) = &self.header else {
+            let columns = record
+                .map_err(|_| ImportError::InvalidUpload("The header is not valid UTF-8.".into()))?;
+            self.header = Some(parse_header(columns)?);
+            return Ok(());
+        };
+        match parse_row(header, self.row, record) {
+            Ok(row) if !self.seen.insert(row.subscriber.email.as_ref().to_owned()) => {
+                let email = row.subscriber.email.as_ref().to_owned();
+                self.reject(Some(email), "Duplicate of an earlier row.".into());
+            }
+            Ok(row) => {
+                self.batch.push(row);
+                if self.batch.len() >= IMPORT_BATCH_SIZE {
+                    self.flush().await?;
+                }
+            }
+            Err((email, error)) => self.reject(email, error),
+        }
+        Ok(())
+    }
+
+    fn reject(&mut self, email: Option<String>, error: String) {
+        self.reject_row(self.row, email, error);
+    }
+
+    fn reject_row(&mut self, row: u64, email: Option<String>, error: String) {
+        self.report.rejected += 1;
+        self.report.errors.push(RowError { row, email, error });
+    }
+
+    #[tracing::instrument(skip_all, fields(rows = self.batch.len()))]
+    async fn flush(&mut self) -> Result<(), ImportError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool")?;
+        let suppressed = suppressed_emails(&mut transaction, &batch)
+            .await
+            .context("Failed to check the suppression list.")?;
+        let (batch, suppressed): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .partition(|row| !suppressed.contains(row.subscriber.email.as_ref()));
+        for row in suppressed {
+            let email = row.subscriber.email.as_ref().to_owned();
+            self.reject_row(row.row, Some(email), "The address is on the suppression list.".into());
+        }
+        let status = match self.mode {
+            ImportMode::SendConfirmation => SubscriptionStatus::PendingConfirmation,
+            ImportMode::Confirmed => SubscriptionStatus::Confirmed,
+        };
+        let inserted = insert_subscribers(&mut transaction, &batch, status)
+            .await
+            .context("Failed to insert a batch of imported subscribers.")?;
+        for (row, subscriber_id) in batch.iter().zip(inserted) {
+            let Some(subscriber_id) = subscriber_id else {
+                let email = row.subscriber.email.as_ref().to_owned();
+                self.reject_row(row.row, Some(email), "Already subscribed.".into());
+                continue;
+            };
+            self.report.imported += 1;
+            if let ImportMode::SendConfirmation = self.mode {
+                let subscription_token = generate_subscription_token();
+                store_token(&mut transaction, subscriber_id, &subscription_token)
+                    .await
+                    .context("Failed to store the confirmation token for an imported subscriber.")?;
+                enqueue_confirmation_email(
+                    &mut transaction,
+                    self.email_templates,
+                    &row.subscriber,
+                    self.base_url,
+                    &subscription_token,
+                )
+                .await
+                .context("Failed to add the confirmation email to the outbox.")?;
+            }
+        }
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to import subscribers.")?;
+        Ok(())
+    }
+}
+
+fn parse_header(columns: Vec<String>) -> Result<Header, ImportError> {
+    let columns: Vec<String> = columns
+        .into_iter()
+        .enumerate()
+        .map(|(i, column)| {
+            //Excel starts its UTF-8 exports with a byte order mark
+            let column = match i {
+                0 => column.trim_start_matches('\u{feff}'),
+                _ => &column,
+            };
+            column.trim().to_lowercase()
+        })
+        .collect();
+    let position = |name: &str| columns.iter().position(|column| column == name);
+    let (Some(email), Some(name)) = (position("email"), position("name")) else {
+        return Err(ImportError::InvalidUpload(
+            "The header must have an `email` and a `name` column.".into(),
+        ));
+    };
+    let custom_fields_json = position(CUSTOM_FIELDS_COLUMN);
+    let custom_fields = columns
+        .iter()
+        .enumerate()
+        .filter(|(i, column)| {
+            ![Some(email), Some(name), custom_fields_json].contains(&Some(*i))
+                && !IGNORED_COLUMNS.contains(&column.as_str())
+        })
+        .map(|(i, column)| (i, column.clone()))
+        .collect();
+    Ok(Header {
+        email,
+        name,
+        custom_fields,
+        custom_fields_json,
+        len: columns.len(),
+    })
+}
+
+//the error comes with the email when there is one, to make the report readable
+fn parse_row(
+    header: &Header,
+    row: u64,
+    record: CsvRecord,
+) -> Result<ImportRow, (Option<String>, String)> {
+    let fields = record.map_err(|_| (None, "The row is not valid UTF-8.".to_owned()))?;
+    if fields.len() != header.len {
+        return Err((
+            None,
+            format!("Expected {} fields, found {}.", header.len, fields.len()),
+        ));
+    }
+    //lowercased like the suppression list, so duplicates and suppressed
+    //addresses are caught whatever their case
+    let email = unescape_formula(fields[header.email].trim()).to_lowercase();
+    let name = unescape_formula(fields[header.name].trim()).to_owned();
+    let subscriber =
+        NewSubscriber::parse(name, email.clone()).map_err(|e| (Some(email.clone()), e))?;
+    let mut custom_fields = match header.custom_fields_json {
+        Some(i) if !fields[i].trim().is_empty() => {
+            match serde_json::from_str::<serde_json::Value>(&fields[i]) {
+                Ok(value @ serde_json::Value::Object(_)) => value,
+                _ => {
+                    return Err((
+                        Some(email),
+                        format!("`{}` must be a JSON object.", CUSTOM_FIELDS_COLUMN),
+                    ))
+                }
+            }
+        }
+        _ => serde_json::json!({}),
+    };
+    //empty cells are left out, so merge tag defaults apply
+    for (i, column) in &header.custom_fields {
+        let value = fields[*i].trim();
+        if !value.is_empty() {
+            custom_fields[column] = serde_json::Value::String(value.to_owned());
+        }
+    }
+    Ok(ImportRow {
+        row,
+        subscriber,
+        custom_fields,
+    })
+}
+
+#[tracing::instrument(skip_all)]
+async fn suppressed_emails(
+    transaction: &mut Transaction<'_, Postgres>,
+    batch: &[ImportRow],
+) -> Result<HashSet<String>, sqlx::Error> {
+    let emails: Vec<&str> = batch.iter().map(|row| row.subscriber.email.as_ref()).collect();
+    let suppressed = sqlx::query!(
+        r#"SELECT email FROM suppressed_emails WHERE email = ANY($1)"#,
+        &emails as &[&str]
+    )
+    .fetch_all(transaction)
+    .await?;
+    Ok(suppressed.into_iter().map(|row| row.email).collect())
+}
+
+//one statement per batch, the ids line up with the batch and are None
+//for emails that were already subscribed
+#[tracing::instrument(skip_all)]
+async fn insert_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    batch: &[ImportRow],
+    status: SubscriptionStatus,
+) -> Result<Vec<Option<Uuid>>, sqlx::Error> {
+    let ids: Vec<Uuid> = batch.iter().map(|_| Uuid::new_v4()).collect();
+    let emails: Vec<&str> = batch.iter().map(|row| row.subscriber.email.as_ref()).collect();
+    let names: Vec<&str> = batch.iter().map(|row| row.subscriber.name.as_ref()).collect();
+    let custom_fields: Vec<String> = batch
+        .iter()
+        .map(|row| row.custom_fields.to_string())
+        .collect();
+    let unsubscribe_tokens: Vec<String> = batch.iter().map(|_| generate_random_token(25)).collect();
+    let inserted: HashSet<Uuid> = sqlx::query!(
+        r#"
+        INSERT INTO subscriptions(id, email, name, subscribed_at, status, unsubscribe_token, custom_fields)
+        SELECT id, email, name, now(), $6, unsubscribe_token, custom_fields::jsonb
+        FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[], $5::text[])
+            AS t(id, email, name, unsubscribe_token, custom_fields)
+        ON CONFLICT ((lower(email))) DO NOTHING
+        RETURNING id
+        "#,
+        &ids[..],
+        &emails as &[&str],
+        &names as &[&str],
+        &unsubscribe_tokens[..],
+        &custom_fields[..],
+        status.as_str()
+    )
+    .fetch_all(transaction)
+    .await?
+    .into_iter()
+    .map(|row| row.id)
+    .collect();
+    Ok(ids
+        .into_iter()
+        .map(|id| inserted.contains(&id).then_some(id))
+        .collect())
+}
+
+enum ExportPage {
+    First,
+    After(Cursor),
+    Done,
+}
+
+//takes the same filters as the list, one page at a time so the whole set
+//never sits in memory
+#[tracing::instrument(name = "Export subscribers", skip(pool))]
+pub async fn export_subscribers(
+    filter: web::Query<SubscriberFilter>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let pool = pool.get_ref().clone();
+    let filter = filter.into_inner();
+    let header = stream::once(async { Ok(export_header()) });
+    let pages = stream::try_unfold(ExportPage::First, move |page| {
+        let pool = pool.clone();
+        let filter = filter.clone();
+        async move {
+            let cursor = match page {
+                ExportPage::First => None,
+                ExportPage::After(cursor) => Some(cursor),
+                ExportPage::Done => return Ok::<_, anyhow::Error>(None),
+            };
+            let subscribers =
+                fetch_subscriber_page(&pool, &filter, cursor.as_ref(), EXPORT_PAGE_SIZE)
+                    .await
+                    .context("Failed to fetch a page of subscribers to export.")?;
+            let next = match subscribers.last() {
+                Some(last) if subscribers.len() as i64 == EXPORT_PAGE_SIZE => {
+                    ExportPage::After(Cursor {
+                        subscribed_at: last.subscribed_at,
+                        id: last.id,
+                    })
+                }
+                _ => ExportPage::Done,
+            };
+            Ok(Some((export_page(&subscribers), next)))
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("subscribers.csv".into())],
+        })
+        .streaming(header.chain(pages))
+}
+
+//the import reads this back, id, status and subscribed_at are ignored
+fn export_header() -> Bytes {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer
+        .write_record(["id", "email", "name", "status", "subscribed_at", CUSTOM_FIELDS_COLUMN])
+        .expect("writing to a Vec cannot fail");
+    Bytes::from(writer.into_inner().expect("flushing a Vec cannot fail"))
+}
+
+//csv::Writer takes care of quoting, escape_formula of cells that a
+//spreadsheet would otherwise evaluate
+fn export_page(subscribers: &[Subscriber]) -> Bytes {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for subscriber in subscribers {
+        writer
+            .write_record([
+                subscriber.id.to_string().as_str(),
+                &escape_formula(&subscriber.email),
+                &escape_formula(&subscriber.name),
+                subscriber.status.as_str(),
+                &subscriber.subscribed_at.to_rfc3339(),
+                &subscriber.custom_fields.to_string(),
+            ])
+            .expect("writing to a Vec cannot fail");
+    }
+    Bytes::from(writer.into_inner().expect("flushing a Vec cannot fail"))
+}
+
+//the usual defence against CSV injection, a leading quote makes
+//spreadsheets show the cell as text
+fn escape_formula(cell: &str) -> Cow<'_, str> {
+    if cell.starts_with(FORMULA_PREFIXES) {
+        Cow::Owned(format!("'{}", cell))
+    } else {
+        Cow::Borrowed(cell)
+    }
+}
+
+//undoes escape_formula, so that our exports import back unchanged
+fn unescape_formula(cell: &str) -> &str {
+    match cell.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(FORMULA_PREFIXES) => rest,
+        _ => cell,
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ImportError {
+    #[error("{0}")]
+    InvalidUpload(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for ImportError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ImportError::InvalidUpload(_) => StatusCode::BAD_REQUEST,
+            ImportError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+impl std::fmt::Debug for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_formula, unescape_formula};
+
+    #[test]
+    fn cells_that_look_like_formulas_are_escaped_and_read_back() {
+        for cell in ["=HYPERLINK(\"http://evil\")", "+1", "-1", "@SUM(A1)", "\tx"] {
+            let escaped = escape_formula(cell);
+            assert_eq!(escaped, format!("'{}", cell));
+            assert_eq!(unescape_formula(&escaped), cell);
+        }
+        for cell in ["Ursula", "'quoted", "a=b"] {
+            assert_eq!(escape_formula(cell), cell);
+            assert_eq!(unescape_formula(cell), cell);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use secrecy::ExposeSecret;
+use sqlx::{PgPool, Postgres, Transaction};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::domain::SubscriptionStatus;
+use crate::routes::error_chain_fmt;
+use crate::startup::EmailWebhookSecret;
+use crate::subscribers::{change_status, StatusChangeError};
+use crate::suppression::{suppress, SuppressionReason};
+
+//configured as a custom header on the provider's webhook
+const WEBHOOK_SECRET_HEADER: &str = "X-Webhook-Secret";
+
+//the fields we act on, the full payload is stored as received
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailEvent {
+    record_type: String,
+    //bounces only: HardBounce, SoftBounce, Transient...
+    #[serde(rename = "Type")]
+    bounce_type: Option<String>,
+    //bounces and spam complaints
+    email: Option<String>,
+    //deliveries
+    recipient: Option<String>,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+//Postmark-style bounce, spam complaint and delivery notifications
+#[tracing::instrument(
+    name = "Receive an email event",
+    skip_all,
+    fields(record_type = tracing::field::Empty, email = tracing::field::Empty)
+)]
+pub async fn receive_email_event(
+    request: HttpRequest,
+    payload: web::Json<serde_json::Value>,
+    pool: web::Data<PgPool>,
+    webhook_secret: web::Data<EmailWebhookSecret>,
+) -> Result<HttpResponse, WebhookError> {
+    let provided = request
+        .headers()
+        .get(WEBHOOK_SECRET_HEADER)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    let expected = webhook_secret.0.expose_secret().as_bytes();
+    if !bool::from(provided.ct_eq(expected)) {
+        return Err(WebhookError::Unauthorized);
+    }
+    let payload = payload.into_inner();
+    let event: EmailEvent = serde_json::from_value(payload.clone())
+        .map_err(|e| WebhookError::InvalidPayload(e.to_string()))?;
+    let email = event
+        .email
+        .as_deref()
+        .or(event.recipient.as_deref())
+        .map(str::to_lowercase);
+    tracing::Span::current()
+        .record("record_type", tracing::field::display(&event.record_type))
+        .record("email", tracing::field::debug(&email));
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    store_event(&mut transaction, &event, email.as_deref(), &payload)
+        .await
+        .context("Failed to store an email event.")?;
+    let outcome = match (event.record_type.as_str(), event.bounce_type.as_deref()) {
+        ("Bounce", Some("HardBounce")) => {
+            Some((SuppressionReason::HardBounce, SubscriptionStatus::Bounced))
+        }
+        ("SpamComplaint", _) => {
+            Some((SuppressionReason::SpamComplaint, SubscriptionStatus::Complained))
+        }
+        //soft bounces and deliveries are only recorded
+        _ => None,
+    };
+    if let (Some((reason, status)), Some(email)) = (outcome, email.as_deref()) {
+        suppress(&mut transaction, email, reason)
+            .await
+            .context("Failed to suppress an email address.")?;
+        mark_subscriber(&mut transaction, email, status)
+            .await
+            .context("Failed to update the status of a suppressed subscriber.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store an email event.")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(skip_all)]
+async fn store_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    event: &EmailEvent,
+    email: Option<&str>,
+    payload: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_events (email_event_id, record_type, email, message_id, payload, received_at)
+        VALUES ($1, $2, $3, $4, $5, now())
+        "#,
+        Uuid::new_v4(),
+        event.record_type,
+        email,
+        event.message_id,
+        payload
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(transaction))]
+async fn mark_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+    status: SubscriptionStatus,
+) -> Result<(), StatusChangeError> {
+    let subscribers = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE lower(email) = $1"#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    for subscriber in subscribers {
+        change_status(transaction, subscriber.id, status).await?;
+    }
+    Ok(())
+}
+
+#[derive(thiserror::Error)]
+pub enum WebhookError {
+    #[error("Missing or invalid webhook secret.")]
+    Unauthorized,
+    #[error("{0}")]
+    InvalidPayload(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::Unauthorized => StatusCode::UNAUTHORIZED,
+            WebhookError::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            WebhookError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+impl std::fmt::Debug for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
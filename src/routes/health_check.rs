@@ -1,5 +1,24 @@
-use actix_web::{Responder,HttpResponse,HttpRequest};
-pub async fn check_health(_: HttpRequest) -> impl Responder {
+use actix_web::{web, HttpResponse};
+
+use crate::circuit_breaker::CircuitState;
+use crate::email_transport::EmailTransport;
+
+#[derive(serde::Serialize)]
+struct HealthReport {
+    //"degraded" while the email provider circuit is open, we still serve requests
+    status: &'static str,
+    email_circuit: Option<CircuitState>,
+}
+
+pub async fn check_health(email_client: web::Data<dyn EmailTransport>) -> HttpResponse {
     dbg!("Here in health_check");
-    HttpResponse::Ok()
+    let email_circuit = email_client.circuit_state();
+    let status = match email_circuit {
+        Some(CircuitState::Open) => "degraded",
+        _ => "ok",
+    };
+    HttpResponse::Ok().json(HealthReport {
+        status,
+        email_circuit,
+    })
 }
@@ -1,7 +1,23 @@
+mod admin;
+mod email_webhooks;
 mod health_check;
+mod login;
+mod newsletters;
+mod password_reset;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_form;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
 //rexporting
+pub use admin::*;
+pub use email_webhooks::*;
 pub use health_check::*;
+pub use login::*;
+pub use newsletters::*;
+pub use password_reset::*;
 pub use subscriptions::*;
-pub use subscriptions_confirm::*;
\ No newline at end of file
+pub use subscriptions_confirm::*;
+pub use subscriptions_form::*;
+pub use subscriptions_preferences::*;
+pub use subscriptions_unsubscribe::*;
\ No newline at end of file
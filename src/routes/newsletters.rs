@@ -0,0 +1,174 @@
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use reqwest::StatusCode;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    authentication::{basic_authentication, must_change_password, validate_credentials, AuthError},
+    email_templates::parse_issue_content,
+    idempotency::{request_fingerprint, save_response, try_processing, IdempotencyKey, NextAction},
+    routes::error_chain_fmt,
+};
+
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+}
+#[derive(serde::Deserialize)]
+pub struct Content {
+    html: String,
+    text: String,
+}
+
+//the issue is only persisted and queued here, delivery happens in the
+//issue_delivery_worker so a crash halfway through does not lose track of who got it
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(request, body, pool),
+    fields(title = %body.title, username = tracing::field::Empty, user_id = tracing::field::Empty)
+)]
+pub async fn publish_newsletter(
+    request: HttpRequest,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, PublishError> {
+    let credentials =
+        basic_authentication(request.headers()).map_err(AuthError::InvalidCredentials)?;
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    let user_id = validate_credentials(credentials, &pool).await?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    //a password someone else knows only gets you to the change password page
+    if must_change_password(user_id, &pool).await? {
+        return Err(AuthError::InvalidCredentials(anyhow::anyhow!(
+            "The password must be changed before it can be used."
+        ))
+        .into());
+    }
+    //merge tags are rendered per subscriber by the worker, catch typos now
+    parse_issue_content(&body.content.html)
+        .map_err(|e| PublishError::ValidationError(format!("Invalid html content: {}", e)))?;
+    parse_issue_content(&body.content.text)
+        .map_err(|e| PublishError::ValidationError(format!("Invalid text content: {}", e)))?;
+    let idempotency_key = IdempotencyKey::from_request(&request)
+        .map_err(|e| PublishError::ValidationError(e.to_string()))?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    //a retried publish must not queue the issue for every subscriber twice
+    if let Some(key) = &idempotency_key {
+        let fingerprint =
+            request_fingerprint(&[&body.title, &body.content.html, &body.content.text]);
+        match try_processing(&mut transaction, key, user_id, &fingerprint).await? {
+            NextAction::StartProcessing => {}
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+            NextAction::RejectKeyReuse => return Err(PublishError::IdempotencyKeyReused),
+        }
+    }
+    let issue_id = insert_newsletter_issue(&mut transaction, &body)
+        .await
+        .context("Failed to store newsletter issue details")?;
+    enqueue_delivery_tasks(&mut transaction, issue_id)
+        .await
+        .context("Failed to enqueue delivery tasks")?;
+    let mut response = HttpResponse::Accepted().finish();
+    if let Some(key) = &idempotency_key {
+        response = save_response(&mut transaction, key, user_id, response).await?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a newsletter issue.")?;
+    Ok(response)
+}
+
+#[tracing::instrument(skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    body: &BodyData,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            published_at
+        )
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        newsletter_issue_id,
+        body.title,
+        body.content.text,
+        body.content.html,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(newsletter_issue_id)
+}
+
+//one task per confirmed subscriber, snapshotted at publish time,
+//suppressed addresses are left out whatever their status says
+#[tracing::instrument(skip_all)]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email
+        )
+        SELECT $1, email
+        FROM subscriptions
+        WHERE
+            status = 'confirmed' AND
+            lower(email) NOT IN (SELECT email FROM suppressed_emails)
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[derive(thiserror::Error)]
+pub enum PublishError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    AuthError(#[from] AuthError),
+    #[error("The idempotency key was already used for a different request.")]
+    IdempotencyKeyReused,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for PublishError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            PublishError::AuthError(e) => e.status_code(),
+            PublishError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            //keeps the WWW-Authenticate header
+            PublishError::AuthError(e) => e.error_response(),
+            _ => HttpResponse::new(self.status_code()),
+        }
+    }
+}
+impl std::fmt::Debug for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
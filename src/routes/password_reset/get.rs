@@ -0,0 +1,93 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use std::fmt::Write;
+
+fn flash_html(flash_messages: &IncomingFlashMessages) -> String {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    }
+    msg_html
+}
+
+pub async fn password_reset_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+    let msg_html = flash_html(&flash_messages);
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Reset password</title>
+</head>
+<body>
+    {msg_html}
+    <form action="/password-reset" method="post">
+        <label>Email
+            <input
+                type="text"
+                placeholder="Enter the email of your account"
+                name="email"
+            >
+        </label>
+        <button type="submit">Send reset link</button>
+    </form>
+</body>
+</html>"#,
+        ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    token: String,
+}
+
+//the token is only checked on submit, rendering the form reveals nothing
+pub async fn password_reset_confirm_form(
+    web::Query(parameters): web::Query<Parameters>,
+    flash_messages: IncomingFlashMessages,
+) -> HttpResponse {
+    let msg_html = flash_html(&flash_messages);
+    //tokens are alphanumeric, anything else cannot be ours and must not end up in the page
+    let token = if parameters.token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        parameters.token
+    } else {
+        String::new()
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Choose a new password</title>
+</head>
+<body>
+    {msg_html}
+    <form action="/password-reset/confirm" method="post">
+        <input type="hidden" name="token" value="{token}">
+        <label>New password
+            <input
+                type="password"
+                placeholder="Enter new password"
+                name="new_password"
+            >
+        </label>
+        <br>
+        <label>Confirm new password
+            <input
+                type="password"
+                placeholder="Type the new password again"
+                name="new_password_check"
+            >
+        </label>
+        <br>
+        <button type="submit">Reset password</button>
+    </form>
+</body>
+</html>"#,
+        ))
+}
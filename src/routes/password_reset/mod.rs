@@ -0,0 +1,4 @@
+mod get;
+mod post;
+pub use get::{password_reset_confirm_form, password_reset_form};
+pub use post::{request_password_reset, reset_password};
@@ -0,0 +1,254 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use chrono::{Duration, Utc};
+use secrecy::Secret;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::{change_password, validate_new_password};
+use crate::domain::SubscriberEmail;
+use crate::email_templates::{EmailTemplateName, EmailTemplates};
+use crate::outbox::{enqueue_email, OutboxEmail, PASSWORD_RESET};
+use crate::rate_limit::PasswordResetRateLimits;
+use crate::startup::ApplicationBaseUrl;
+use crate::suppression::is_suppressed;
+use crate::utils::{e500, generate_random_token, see_other};
+
+//reset links are only good for a short while
+const PASSWORD_RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+#[derive(serde::Deserialize)]
+pub struct RequestFormData {
+    email: String,
+}
+
+//always answers the same way and as fast, so the form cannot be used to
+//find out which emails belong to an admin account: the email goes through
+//the outbox and failures are logged rather than reported
+#[tracing::instrument(
+    name = "Request a password reset",
+    skip(form, pool, email_templates, base_url, rate_limits)
+)]
+pub async fn request_password_reset(
+    form: web::Form<RequestFormData>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    rate_limits: web::Data<PasswordResetRateLimits>,
+) -> HttpResponse {
+    if let Ok(email) = SubscriberEmail::parse(form.0.email) {
+        if let Err(e) =
+            queue_password_reset(&pool, &email_templates, &rate_limits, &email, &base_url.0).await
+        {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to queue a password reset email.",
+            );
+        }
+    }
+    FlashMessage::info("If an account with that email exists, we have sent it a reset link.")
+        .send();
+    see_other("/password-reset")
+}
+
+//the token and the email are committed together, or neither is
+async fn queue_password_reset(
+    pool: &PgPool,
+    email_templates: &EmailTemplates,
+    rate_limits: &PasswordResetRateLimits,
+    email: &SubscriberEmail,
+    base_url: &str,
+) -> Result<(), anyhow::Error> {
+    //checked for every address, known or not, so it takes as long either way
+    if rate_limits.check_reset_email(email.as_ref()).await.is_some() {
+        return Ok(());
+    }
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    //a bounced or complaining address gets nothing, not even account emails
+    if is_suppressed(&mut transaction, email.as_ref())
+        .await
+        .context("Failed to check the suppression list.")?
+    {
+        return Ok(());
+    }
+    let Some(user_id) = get_user_id_by_email(&mut transaction, email).await? else {
+        return Ok(());
+    };
+    let token = generate_random_token(25);
+    store_password_reset_token(&mut transaction, user_id, &token).await?;
+    enqueue_password_reset_email(&mut transaction, email_templates, email, base_url, &token)
+        .await
+        .context("Failed to add the password reset email to the outbox.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to request a password reset.")?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetFormData {
+    token: String,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(name = "Reset a password", skip(form, pool))]
+pub async fn reset_password(
+    form: web::Form<ResetFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.into_inner();
+    //tokens are alphanumeric, anything else cannot be ours and must not end up in a header
+    if !form.token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        FlashMessage::error("The password reset link is invalid or has expired.").send();
+        return Ok(see_other("/password-reset"));
+    }
+    if let Err(message) = validate_new_password(&form.new_password, &form.new_password_check) {
+        FlashMessage::error(message).send();
+        return Ok(see_other(&format!(
+            "/password-reset/confirm?token={}",
+            form.token
+        )));
+    }
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")
+        .map_err(e500)?;
+    let user_id = match redeem_password_reset_token(&mut transaction, &form.token)
+        .await
+        .map_err(e500)?
+    {
+        Some(user_id) => user_id,
+        None => {
+            FlashMessage::error("The password reset link is invalid or has expired.").send();
+            return Ok(see_other("/password-reset"));
+        }
+    };
+    change_password(user_id, form.new_password, &mut transaction)
+        .await
+        .map_err(e500)?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to reset a password.")
+        .map_err(e500)?;
+    FlashMessage::info("Your password has been reset, you can now log in.").send();
+    Ok(see_other("/login"))
+}
+
+#[tracing::instrument(name = "Get user id by email", skip(transaction, email))]
+async fn get_user_id_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<Uuid>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id FROM users WHERE email = $1"#,
+        email.as_ref()
+    )
+    .fetch_optional(transaction)
+    .await
+    .context("Failed to look up a user by email.")?;
+    Ok(row.map(|r| r.user_id))
+}
+
+#[tracing::instrument(name = "Store a password reset token", skip(transaction, token))]
+async fn store_password_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    token: &str,
+) -> Result<(), anyhow::Error> {
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO password_reset_tokens (password_reset_token, user_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        token,
+        user_id,
+        now,
+        now + Duration::minutes(PASSWORD_RESET_TOKEN_TTL_MINUTES)
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to store a password reset token.")?;
+    Ok(())
+}
+
+//locks the token row, so two concurrent submissions cannot both redeem it,
+//and burns every other outstanding token of the same user
+#[tracing::instrument(name = "Redeem a password reset token", skip(transaction, token))]
+async fn redeem_password_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    token: &str,
+) -> Result<Option<Uuid>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT user_id
+        FROM password_reset_tokens
+        WHERE
+            password_reset_token = $1 AND
+            used_at IS NULL AND
+            expires_at > now()
+        FOR UPDATE
+        "#,
+        token
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to fetch a password reset token.")?;
+    let user_id = match row {
+        Some(r) => r.user_id,
+        None => return Ok(None),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE password_reset_tokens
+        SET used_at = now()
+        WHERE user_id = $1 AND used_at IS NULL
+        "#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to mark password reset tokens as used.")?;
+    Ok(Some(user_id))
+}
+
+#[tracing::instrument(
+    name = "Add a password reset email to the outbox",
+    skip(transaction, email_templates, email, token)
+)]
+async fn enqueue_password_reset_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_templates: &EmailTemplates,
+    email: &SubscriberEmail,
+    base_url: &str,
+    token: &str,
+) -> Result<(), sqlx::Error> {
+    let reset_link = format!("{}/password-reset/confirm?token={}", base_url, token);
+    let subject = "Reset your password";
+    let content = email_templates.render(
+        EmailTemplateName::PasswordReset,
+        subject,
+        &[("reset_link", &reset_link)],
+    );
+    enqueue_email(
+        transaction,
+        OutboxEmail {
+            kind: PASSWORD_RESET,
+            recipient: email,
+            subject,
+            html_content: &content.html,
+            text_content: &content.text,
+        },
+    )
+    .await?;
+    Ok(())
+}
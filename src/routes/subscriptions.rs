@@ -1,9 +1,7 @@
 use std::fmt::Formatter;
 
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
 use chrono::Utc;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use reqwest::StatusCode;
 use sqlx::PgPool;
 use sqlx::Postgres;
@@ -13,27 +11,40 @@ use uuid::Uuid;
 use anyhow::Context;
 
 use crate::{
-    domain::{NewSubscriber, SubscriberEmail, SubscriberName},
-    email_client::EmailClient,
+    bot_protection::{BotProtection, BotRejection, BotSubmission},
+    domain::{NewSubscriber, SubscriptionStatus},
+    email_templates::{EmailTemplateName, EmailTemplates},
+    outbox::{enqueue_email, OutboxEmail, SUBSCRIPTION_CONFIRMATION},
+    idempotency::{
+        anonymous_scope, request_fingerprint, save_response, try_processing, IdempotencyKey,
+        NextAction,
+    },
+    rate_limit::{request_client_ip, too_many_requests, SubscribeRateLimits},
     startup::ApplicationBaseUrl,
+    subscribers::{change_status, StatusChangeError},
+    suppression::is_suppressed,
+    utils::generate_random_token,
 };
 #[derive(serde::Deserialize)]
 pub struct FormData {
     pub name: String,
     pub email: String,
+    //bot protection, see bot_protection::BotProtection
+    #[serde(default, rename = "website")]
+    pub honeypot: Option<String>,
+    #[serde(default)]
+    pub form_token: Option<String>,
+    #[serde(default, rename = "h-captcha-response")]
+    pub captcha_response: Option<String>,
 }
+//how long a confirmation link stays valid
+const CONFIRMATION_TOKEN_TTL_HOURS: i32 = 24;
 //Using 25 characters we get roughly ~10^45 possible tokens -
-fn generate_subscription_token() -> String {
-    // lazily seeded rng generator
-    let mut rng = thread_rng();
-    //iterate a randomly generated CSPRG and take the first 25
-    std::iter::repeat_with(|| rng.sample(Alphanumeric))
-        .map(char::from)
-        .take(25)
-        .collect()
+pub fn generate_subscription_token() -> String {
+    generate_random_token(25)
 }
 #[tracing::instrument(name="Adding a Subscriber",
-skip(form,_pool_connection,email_client,base_url),
+skip(request,form,_pool_connection,email_templates,rate_limits,bot_protection,base_url),
 fields(
     //in order to use the request_id passed from request id
         // request_id=%Uuid::new_v4(),
@@ -41,13 +52,15 @@ fields(
         subscriber_name=%form.name
 ))]
 pub async fn subscribe(
+    request: HttpRequest,
     form: web::Form<FormData>,
     //retrieves a connection from the application state
     //looks for the closest resource with the given type
     //PgPool is a type alias for Pool<POSTGRES>
     _pool_connection: web::Data<PgPool>,
-    //get email client from app context
-    email_client: web::Data<EmailClient>,
+    email_templates: web::Data<EmailTemplates>,
+    rate_limits: web::Data<SubscribeRateLimits>,
+    bot_protection: web::Data<BotProtection>,
     base_url: web::Data<ApplicationBaseUrl>,
 ) -> Result<HttpResponse, SubscribeError> {
     dbg!("Here in sub");
@@ -59,25 +72,105 @@ pub async fn subscribe(
     //     subscriber_name=%_form.name
     // );
     // let _request_span_guard = request_span.enter();
+    //before touching the database, bots should cost us as little as possible
+    let submission = BotSubmission {
+        honeypot: form.honeypot.as_deref(),
+        form_token: form.form_token.as_deref(),
+        captcha_response: form.captcha_response.as_deref(),
+        client_ip: request_client_ip(&request, &rate_limits.trusted_proxies),
+    };
+    match bot_protection.check(&submission).await {
+        Ok(()) => {}
+        //looks like a success, so the bot has no reason to adapt
+        Err(BotRejection::HoneypotFilled) => {
+            tracing::info!("Ignoring a subscription with the honeypot filled in.");
+            return Ok(HttpResponse::Ok().finish());
+        }
+        Err(e) => return Err(SubscribeError::BotRejected(e)),
+    }
     //query logic
+    let idempotency_key = IdempotencyKey::from_request(&request)
+        .map_err(|e| SubscribeError::ValidationError(e.to_string()))?;
     let mut transaction =  _pool_connection.begin().await.context("Failed to acquire a Postgres connection from the pool")?;
+    //a retried form submission gets the original response back instead of a second email
+    let idempotency_scope = anonymous_scope(&form.email);
+    if let Some(key) = &idempotency_key {
+        let fingerprint = request_fingerprint(&[&form.email, &form.name]);
+        match try_processing(&mut transaction, key, idempotency_scope, &fingerprint).await? {
+            NextAction::StartProcessing => {}
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+            NextAction::RejectKeyReuse => return Err(SubscribeError::IdempotencyKeyReused),
+        }
+    }
 
     let new_subscriber =
          NewSubscriber::try_from(form.0).map_err(SubscribeError::ValidationError)?;
-    let sub_id = insert_subscriber(&new_subscriber, &mut transaction).await.context("Failed to insert new subscriber in the database.")?;
-    //generate a token
-    let subscription_token = generate_subscription_token();
-    //store the token against subscriber id
-    store_token(&mut transaction, sub_id, &subscription_token)
-        .await.context("Failed to store the confirmation token for a new subscriber.")?;
-   send_confirmation_email(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
-        &subscription_token,).await.context("Failed to send a confirmation email.")?;
-    
+    //the provider told us this address bounces or complains, answer as usual but never email it
+    if is_suppressed(&mut transaction, new_subscriber.email.as_ref())
+        .await.context("Failed to check the suppression list.")?
+    {
+        tracing::info!("Ignoring a subscription request for a suppressed address.");
+        let mut response = HttpResponse::Ok().finish();
+        if let Some(key) = &idempotency_key {
+            response = save_response(&mut transaction, key, idempotency_scope, response).await?;
+        }
+        transaction.commit().await.context("Failed to commit SQL transaction to store a new subscriber.",)?;
+        return Ok(response);
+    }
+    let sub_id = match insert_subscriber(&new_subscriber, &mut transaction)
+        .await.context("Failed to insert new subscriber in the database.")?
+    {
+        Some(sub_id) => Some(sub_id),
+        //already subscribed, or a concurrent submission for the same email got there first
+        None => {
+            let existing = get_subscriber_by_email(&mut transaction, &new_subscriber)
+                .await.context("Failed to look up an existing subscriber.")?
+                .context("The existing subscriber was deleted while subscribing again.")?;
+            //confirmed, bounced or complained, nothing to do,
+            //the answer looks the same as for a new subscriber
+            if existing
+                .status
+                .transition_to(SubscriptionStatus::PendingConfirmation)
+                .is_err()
+            {
+                None
+            } else {
+                //pending or unsubscribed, start over with a fresh confirmation link
+                restart_confirmation(&mut transaction, existing.id)
+                    .await.context("Failed to reset the confirmation of an existing subscriber.")?;
+                Some(existing.id)
+            }
+        }
+    };
+    if let Some(sub_id) = sub_id {
+        if let Some(retry_after) = rate_limits
+            .check_confirmation_email(new_subscriber.email.as_ref())
+            .await
+        {
+            return Err(SubscribeError::TooManyConfirmationEmails { retry_after });
+        }
+        //generate a token
+        let subscription_token = generate_subscription_token();
+        //store the token against subscriber id
+        store_token(&mut transaction, sub_id, &subscription_token)
+            .await.context("Failed to store the confirmation token for a new subscriber.")?;
+        //sent by the outbox dispatcher once this transaction has committed
+        enqueue_confirmation_email(
+            &mut transaction,
+            &email_templates,
+            &new_subscriber,
+            &base_url.0,
+            &subscription_token,
+        )
+        .await
+        .context("Failed to add the confirmation email to the outbox.")?;
+    }
+    let mut response = HttpResponse::Ok().finish();
+    if let Some(key) = &idempotency_key {
+        response = save_response(&mut transaction, key, idempotency_scope, response).await?;
+    }
      transaction.commit().await.context("Failed to commit SQL transaction to store a new subscriber.",)?;
-    Ok(HttpResponse::Ok().finish())
+    Ok(response)
 }
 #[tracing::instrument(name = "Storing a newly generated token", skip(pool, sub_id, token))]
 pub async fn store_token(
@@ -87,9 +180,11 @@ pub async fn store_token(
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"INSERT INTO subscription_tokens(
-        subscription_token,subscriber_id) VALUES($1,$2)"#,
+        subscription_token,subscriber_id,created_at,expires_at)
+        VALUES($1,$2,now(),now() + make_interval(hours => $3))"#,
         token,
-        sub_id
+        sub_id,
+        CONFIRMATION_TOKEN_TTL_HOURS
     )
     .execute(pool)
     .await
@@ -98,38 +193,44 @@ pub async fn store_token(
     Ok(())
 }
 #[tracing::instrument(
-    name = "Sends a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber, token)
+    name = "Queue a confirmation email for a new subscriber",
+    skip(transaction, email_templates, new_subscriber, token)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+pub async fn enqueue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_templates: &EmailTemplates,
+    new_subscriber: &NewSubscriber,
     base_url: &str,
     token: &str,
-) -> Result<(),reqwest::Error> {
+) -> Result<(), sqlx::Error> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, token
     );
-
-    email_client
-        .send_email(
-            new_subscriber.email,
-            "WELCOME",
-            &format!(
-                "Welcome to our newsletter!<br />\
-        Click <a href=\"{}\">here</a> to confirm your subscription.",
-                confirmation_link
-            ),
-            &format!(
-                "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-                confirmation_link
-            ),
-        )
-        .await?;
+    let subject = "WELCOME";
+    let email = email_templates.render(
+        EmailTemplateName::Confirmation,
+        subject,
+        &[
+            ("name", new_subscriber.name.as_ref()),
+            ("confirmation_link", &confirmation_link),
+        ],
+    );
+    enqueue_email(
+        transaction,
+        OutboxEmail {
+            kind: SUBSCRIPTION_CONFIRMATION,
+            recipient: &new_subscriber.email,
+            subject,
+            html_content: &email.html,
+            text_content: &email.text,
+        },
+    )
+    .await?;
     Ok(())
 }
-//if the build fails check if db_url env is set or there is an offline db build
+//None when the email is already taken, in any case. ON CONFLICT waits for a concurrent
+//submission of the same email to commit instead of failing on the unique key
 #[tracing::instrument(
     name = "Saving Details to the Database",
     skip(new_subscriber, pool_connection)
@@ -137,32 +238,78 @@ pub async fn send_confirmation_email(
 pub async fn insert_subscriber(
     new_subscriber: &NewSubscriber,
     pool_connection: &mut Transaction<'_, Postgres>,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
+    let inserted = sqlx::query!(
         r#"
-        INSERT INTO subscriptions(id,email,name,subscribed_at,status)
-        VALUES($1,$2,$3,$4,'pending_confirmation')
+        INSERT INTO subscriptions(id,email,name,subscribed_at,status,unsubscribe_token)
+        VALUES($1,$2,$3,$4,$5,$6)
+        ON CONFLICT ((lower(email))) DO NOTHING
+        RETURNING id
         "#,
         subscriber_id,
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        SubscriptionStatus::PendingConfirmation.as_str(),
+        //lives as long as the subscription, every issue links to it
+        generate_random_token(25)
     )
-    .execute(pool_connection)
+    .fetch_optional(pool_connection)
     //decorating it by adding a logger in case error is returned
     .await
     //remove error log spam
     ?;
 
-    Ok(subscriber_id)
+    Ok(inserted.map(|row| row.id))
+}
+
+pub struct ExistingSubscriber {
+    pub id: Uuid,
+    pub status: SubscriptionStatus,
+}
+//the row stays locked so a concurrent submission for the same email waits for us,
+//insert_subscriber covers the case where there is no row to lock yet
+#[tracing::instrument(name = "Looking up an existing subscriber", skip_all)]
+pub async fn get_subscriber_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    new_subscriber: &NewSubscriber,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"SELECT id, status AS "status: SubscriptionStatus" FROM subscriptions WHERE lower(email) = lower($1) FOR UPDATE"#,
+        new_subscriber.email.as_ref()
+    )
+    .fetch_optional(transaction)
+    .await
+}
+//old links stop working, only the one we are about to send is valid
+#[tracing::instrument(name = "Restarting the confirmation of a subscriber", skip(transaction))]
+pub async fn restart_confirmation(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), StatusChangeError> {
+    change_status(transaction, subscriber_id, SubscriptionStatus::PendingConfirmation).await?;
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
 }
 
 //#[from] #[source] means the same, from implies source
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
-    #[error{"0"}]
+    #[error("{0}")]
     ValidationError(String),
+    #[error(transparent)]
+    BotRejected(BotRejection),
+    #[error("Too many confirmation emails were sent to this address, please try again later.")]
+    TooManyConfirmationEmails { retry_after: std::time::Duration },
+    #[error("The idempotency key was already used for a different request.")]
+    IdempotencyKeyReused,
     // #[error("Failed to acquire a Postgres connection from the pool")]
     // PoolError(#[source] sqlx::Error),
     // #[error("Failed to insert new subscriber in the database.")]
@@ -183,9 +330,24 @@ impl ResponseError for SubscribeError {
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
             SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            SubscribeError::TooManyConfirmationEmails { .. } => StatusCode::TOO_MANY_REQUESTS,
+            SubscribeError::BotRejected(BotRejection::CaptchaUnavailable(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            SubscribeError::BotRejected(_) => StatusCode::BAD_REQUEST,
             SubscribeError::Unexpectederror(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            //tells well behaved clients when to come back
+            SubscribeError::TooManyConfirmationEmails { retry_after } => {
+                too_many_requests(*retry_after)
+            }
+            _ => HttpResponse::new(self.status_code()),
+        }
+    }
 }
 impl std::fmt::Debug for SubscribeError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -1,46 +1,113 @@
-use actix_web::HttpResponse;
-use actix_web::web;
-use sqlx::PgPool;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
+
+use crate::domain::{IllegalStatusTransition, SubscriptionStatus};
+use crate::routes::error_chain_fmt;
+use crate::subscribers::{change_status, StatusChangeError};
+
 #[derive(serde::Deserialize)]
 pub struct Parameters {
     subscription_token : String,
 }
+pub struct SubscriptionToken {
+    pub subscriber_id : Uuid,
+    pub expired : bool,
+    pub status : SubscriptionStatus,
+}
 
-#[tracing::instrument(name = "Conifrm a pending subscriber",skip(parameters))]
+#[tracing::instrument(name = "Conifrm a pending subscriber",skip(parameters, pool))]
 //type safe api structured destructing from wrapper struct
 pub async fn confirm(web::Query(parameters) : web::Query<Parameters>,
 pool : web::Data<PgPool>,
-) -> HttpResponse {
-    let id = match get_subscriber_id_from_token(&pool,parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match id {
-        //Non existing token
-        None => return HttpResponse::Unauthorized().finish(),
-        Some(id) => if confirm_subscriber(&pool,&id).await.is_err() {
-            return HttpResponse::InternalServerError().finish();
-        },
+) -> Result<HttpResponse, ConfirmError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let token = get_subscriber_id_from_token(&mut transaction,&parameters.subscription_token)
+        .await
+        .context("Failed to retrieve the subscriber id associated with the provided token.")?
+        //Non existing token, or one that has already been used
+        .ok_or(ConfirmError::UnknownToken)?;
+    if token.expired {
+        return Err(ConfirmError::ExpiredToken);
     }
-
-    HttpResponse::Ok().finish()
+    if token.status == SubscriptionStatus::Confirmed {
+        return Err(ConfirmError::AlreadyConfirmed);
+    }
+    confirm_subscriber(&mut transaction,&token.subscriber_id)
+        .await
+        .map_err(|e| match e {
+            //e.g. the address bounced while the link was waiting in the inbox
+            StatusChangeError::IllegalTransition(e) => ConfirmError::IllegalTransition(e),
+            e => ConfirmError::UnexpectedError(
+                anyhow::Error::new(e).context("Failed to update the subscriber status to `confirmed`."),
+            ),
+        })?;
+    delete_token(&mut transaction,&parameters.subscription_token)
+        .await
+        .context("Failed to delete a redeemed confirmation token.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to confirm a subscriber.")?;
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[tracing::instrument(name = "Get subscriber id from a token",skip(pool,token))]
-pub async fn get_subscriber_id_from_token(pool : &PgPool,token : String) -> Result<Option<Uuid>,sqlx::Error> {
-    let result = sqlx::query!(r#"SELECT subscriber_id from subscription_tokens where subscription_token = $1"#,token).fetch_optional(pool).await.map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e })?;
-        //maps from option<record> to option<uuid> this is what map does for option type
-    Ok(result.map(|r|r.subscriber_id) )
-}
-#[tracing::instrument(name = "Mark a subscriber as confirmed",skip(pool,subscriber_id))]
-pub async fn confirm_subscriber(pool : &PgPool,subscriber_id : &Uuid) -> Result<(),sqlx::Error> {
-    sqlx::query!(r#"UPDATE subscriptions SET status = 'confirmed' where id = $1"#,subscriber_id).execute(pool).await.map_err(|e| {
-        tracing::error!("Failed to execute query {:?}", e);
-        e
-    })?;
+//locks the row so two concurrent clicks cannot both redeem the token
+#[tracing::instrument(name = "Get subscriber id from a token",skip(transaction,token))]
+pub async fn get_subscriber_id_from_token(transaction : &mut Transaction<'_, Postgres>,token : &str) -> Result<Option<SubscriptionToken>,sqlx::Error> {
+    sqlx::query_as!(
+        SubscriptionToken,
+        r#"SELECT t.subscriber_id, t.expires_at <= now() AS "expired!", s.status AS "status: SubscriptionStatus"
+        FROM subscription_tokens t
+        JOIN subscriptions s ON s.id = t.subscriber_id
+        WHERE t.subscription_token = $1
+        FOR UPDATE OF t"#,
+        token
+    ).fetch_optional(transaction).await
+}
+#[tracing::instrument(name = "Mark a subscriber as confirmed",skip(transaction,subscriber_id))]
+pub async fn confirm_subscriber(transaction : &mut Transaction<'_, Postgres>,subscriber_id : &Uuid) -> Result<(),StatusChangeError> {
+    change_status(transaction, *subscriber_id, SubscriptionStatus::Confirmed).await?;
     Ok(())
+}
+//tokens are single use
+#[tracing::instrument(name = "Delete a redeemed confirmation token",skip(transaction,token))]
+pub async fn delete_token(transaction : &mut Transaction<'_, Postgres>,token : &str) -> Result<(),sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM subscription_tokens WHERE subscription_token = $1"#,token).execute(transaction).await?;
+    Ok(())
+}
 
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error("The confirmation link has expired, subscribe again to get a new one.")]
+    ExpiredToken,
+    #[error("The subscription has already been confirmed.")]
+    AlreadyConfirmed,
+    #[error(transparent)]
+    IllegalTransition(IllegalStatusTransition),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::ExpiredToken => StatusCode::GONE,
+            ConfirmError::AlreadyConfirmed => StatusCode::CONFLICT,
+            ConfirmError::IllegalTransition(_) => StatusCode::CONFLICT,
+            ConfirmError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
 }
@@ -0,0 +1,46 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+
+use crate::bot_protection::{BotProtection, HONEYPOT_FIELD};
+
+//the public subscription form, every render carries a fresh form token
+pub async fn subscribe_form(bot_protection: web::Data<BotProtection>) -> HttpResponse {
+    let form_token_html = bot_protection
+        .issue_form_token()
+        .map(|token| format!(r#"<input type="hidden" name="form_token" value="{}">"#, token))
+        .unwrap_or_default();
+    let captcha_html = bot_protection
+        .captcha
+        .as_ref()
+        .map(|captcha| captcha.widget_html())
+        .unwrap_or_default();
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscribe</title>
+</head>
+<body>
+    <form action="/subscriptions" method="post">
+        <label>Name
+            <input type="text" placeholder="Enter your name" name="name">
+        </label>
+        <label>Email
+            <input type="email" placeholder="Enter your email" name="email">
+        </label>
+        <div style="display:none" aria-hidden="true">
+            <label>Leave this empty
+                <input type="text" name="{HONEYPOT_FIELD}" tabindex="-1" autocomplete="off">
+            </label>
+        </div>
+        {form_token_html}
+        {captcha_html}
+        <button type="submit">Subscribe</button>
+    </form>
+</body>
+</html>"#,
+        ))
+}
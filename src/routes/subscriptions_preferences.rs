@@ -0,0 +1,129 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, ResponseError};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use reqwest::StatusCode;
+use sqlx::PgPool;
+
+use crate::domain::SubscriberName;
+use crate::email_templates::escape_html;
+use crate::routes::error_chain_fmt;
+use crate::utils::see_other;
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesParameters {
+    token: String,
+}
+
+//linked from every issue through `{{ preferences_link }}`, the unsubscribe
+//token doubles as the subscriber's credential like it does for unsubscribing
+#[tracing::instrument(name = "Show subscriber preferences", skip(parameters, pool, flash_messages))]
+pub async fn preferences_form(
+    web::Query(parameters): web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber = sqlx::query!(
+        r#"SELECT email, name FROM subscriptions WHERE unsubscribe_token = $1"#,
+        parameters.token
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to look up the subscriber of a preferences token.")?
+    .ok_or(PreferencesError::UnknownToken)?;
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        msg_html.push_str("<p><i>");
+        escape_html(&mut msg_html, m.content());
+        msg_html.push_str("</i></p>\n");
+    }
+    let (mut email, mut name) = (String::new(), String::new());
+    escape_html(&mut email, &subscriber.email);
+    escape_html(&mut name, &subscriber.name);
+    //the token matched a stored one, so it is alphanumeric and safe to embed
+    let token = parameters.token;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your preferences</title>
+</head>
+<body>
+    {msg_html}
+    <p>Issues are sent to {email}.</p>
+    <form action="/subscriptions/preferences" method="post">
+        <input type="hidden" name="token" value="{token}">
+        <label>Name
+            <input type="text" name="name" value="{name}">
+        </label>
+        <button type="submit">Save</button>
+    </form>
+    <p><a href="/subscriptions/unsubscribe?token={token}">Unsubscribe</a></p>
+</body>
+</html>"#,
+        )))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesFormData {
+    token: String,
+    name: String,
+}
+
+#[tracing::instrument(name = "Update subscriber preferences", skip(form, pool))]
+pub async fn update_preferences(
+    form: web::Form<PreferencesFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, PreferencesError> {
+    let form = form.into_inner();
+    //tokens are alphanumeric, anything else cannot be ours and must not end up in a header
+    if !form.token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(PreferencesError::UnknownToken);
+    }
+    let page = format!("/subscriptions/preferences?token={}", form.token);
+    let name = match SubscriberName::parse(form.name) {
+        Ok(name) => name,
+        //the parse error repeats the input, keep it out of the page
+        Err(_) => {
+            FlashMessage::error("Please enter a valid name.").send();
+            return Ok(see_other(&page));
+        }
+    };
+    let updated = sqlx::query!(
+        r#"UPDATE subscriptions SET name = $2 WHERE unsubscribe_token = $1"#,
+        form.token,
+        name.as_ref()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to update the preferences of a subscriber.")?;
+    if updated.rows_affected() == 0 {
+        return Err(PreferencesError::UnknownToken);
+    }
+    FlashMessage::info("Your preferences have been saved.").send();
+    Ok(see_other(&page))
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::UnknownToken => StatusCode::UNAUTHORIZED,
+            PreferencesError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
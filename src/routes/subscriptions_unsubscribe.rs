@@ -0,0 +1,107 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use sqlx::PgPool;
+
+use crate::domain::SubscriptionStatus;
+use crate::routes::error_chain_fmt;
+use crate::subscribers::change_status;
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    token: String,
+}
+
+//the link in the email footer, a plain GET from the browser
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(parameters, pool))]
+pub async fn unsubscribe(
+    web::Query(parameters): web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    unsubscribe_with_token(&pool, &parameters.token).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribed</title>
+</head>
+<body>
+    <p>You have been unsubscribed, you will not receive any more issues.</p>
+</body>
+</html>"#,
+        ))
+}
+
+//RFC 8058 one-click unsubscribe, mail clients POST `List-Unsubscribe=One-Click`
+//to the List-Unsubscribe url, the token travels in the query string
+#[tracing::instrument(name = "One-click unsubscribe a subscriber", skip(parameters, pool))]
+pub async fn unsubscribe_one_click(
+    web::Query(parameters): web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    unsubscribe_with_token(&pool, &parameters.token).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+//idempotent and never a conflict: mail providers treat anything but a 2xx
+//to the one-click POST as a failure. Bounced and complained subscribers
+//already get nothing, so their status is left alone
+async fn unsubscribe_with_token(pool: &PgPool, token: &str) -> Result<(), UnsubscribeError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    //locked, so a bounce cannot land between the check and the change
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT id, status AS "status: SubscriptionStatus"
+        FROM subscriptions
+        WHERE unsubscribe_token = $1
+        FOR UPDATE
+        "#,
+        token
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look up the subscriber of an unsubscribe token.")?
+    .ok_or(UnsubscribeError::UnknownToken)?;
+    match subscriber.status {
+        SubscriptionStatus::Unsubscribed
+        | SubscriptionStatus::Bounced
+        | SubscriptionStatus::Complained => return Ok(()),
+        SubscriptionStatus::PendingConfirmation | SubscriptionStatus::Confirmed => {}
+    }
+    change_status(&mut transaction, subscriber.id, SubscriptionStatus::Unsubscribed)
+        .await
+        .context("Failed to mark a subscriber as unsubscribed.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to unsubscribe a subscriber.")?;
+    Ok(())
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::UnknownToken => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
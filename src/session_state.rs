@@ -0,0 +1,44 @@
+use actix_session::{Session, SessionExt, SessionGetError, SessionInsertError};
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+use uuid::Uuid;
+
+//typed wrapper around the stringly typed actix Session,
+//keeps the keys in a single place
+pub struct TypedSession(Session);
+
+impl TypedSession {
+    const USER_ID_KEY: &'static str = "user_id";
+    const MUST_CHANGE_PASSWORD_KEY: &'static str = "must_change_password";
+
+    pub fn renew(&self) {
+        self.0.renew();
+    }
+    pub fn insert_user_id(&self, user_id: Uuid) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::USER_ID_KEY, user_id)
+    }
+    pub fn get_user_id(&self) -> Result<Option<Uuid>, SessionGetError> {
+        self.0.get(Self::USER_ID_KEY)
+    }
+    pub fn insert_must_change_password(&self, required: bool) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::MUST_CHANGE_PASSWORD_KEY, required)
+    }
+    pub fn get_must_change_password(&self) -> Result<bool, SessionGetError> {
+        Ok(self.0.get(Self::MUST_CHANGE_PASSWORD_KEY)?.unwrap_or(false))
+    }
+    pub fn log_out(self) {
+        self.0.purge()
+    }
+}
+
+impl FromRequest for TypedSession {
+    //same error as the Session extractor
+    type Error = <Session as FromRequest>::Error;
+    //no async work needed, Session is already in the request extensions
+    type Future = Ready<Result<TypedSession, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(TypedSession(req.get_session())))
+    }
+}
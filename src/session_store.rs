@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::utils::generate_random_token;
+
+type SessionState = HashMap<String, String>;
+
+//actix-session only ships redis and cookie backends, we do not run redis,
+//so sessions live in the `sessions` table next to everything else
+#[derive(Clone)]
+pub struct PostgresSessionStore {
+    pool: PgPool,
+}
+impl PostgresSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+fn generate_session_key() -> SessionKey {
+    generate_random_token(64)
+        .try_into()
+        .expect("a 64 characters key is always a valid session key")
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for PostgresSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        //expired rows are treated as missing
+        let row = sqlx::query!(
+            r#"
+            SELECT session_state
+            FROM sessions
+            WHERE session_key = $1 AND expires_at > now()
+            "#,
+            session_key.as_ref()
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Failed to load the session state")
+        .map_err(LoadError::Other)?;
+        row.map(|r| serde_json::from_value(r.session_state))
+            .transpose()
+            .context("Failed to deserialize the session state")
+            .map_err(LoadError::Deserialization)
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let session_state = serde_json::to_value(session_state)
+            .context("Failed to serialize the session state")
+            .map_err(SaveError::Serialization)?;
+        let session_key = generate_session_key();
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions (session_key, session_state, expires_at)
+            VALUES ($1, $2, now() + make_interval(secs => $3))
+            "#,
+            session_key.as_ref(),
+            session_state,
+            ttl.as_seconds_f64()
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to save the session state")
+        .map_err(SaveError::Other)?;
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let serialized_state = serde_json::to_value(&session_state)
+            .context("Failed to serialize the session state")
+            .map_err(UpdateError::Serialization)?;
+        let n_updated_rows = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET
+                session_state = $2,
+                expires_at = now() + make_interval(secs => $3)
+            WHERE session_key = $1
+            "#,
+            session_key.as_ref(),
+            serialized_state,
+            ttl.as_seconds_f64()
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to update the session state")
+        .map_err(UpdateError::Other)?
+        .rows_affected();
+        //the row was purged in the meantime, start a fresh session
+        if n_updated_rows == 0 {
+            return self
+                .save(session_state, ttl)
+                .await
+                .map_err(|e| match e {
+                    SaveError::Serialization(e) => UpdateError::Serialization(e),
+                    SaveError::Other(e) => UpdateError::Other(e),
+                });
+        }
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET expires_at = now() + make_interval(secs => $2)
+            WHERE session_key = $1
+            "#,
+            session_key.as_ref(),
+            ttl.as_seconds_f64()
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to update the session ttl")?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE session_key = $1"#,
+            session_key.as_ref()
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to delete the session")?;
+        Ok(())
+    }
+}
+
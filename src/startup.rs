@@ -1,13 +1,33 @@
 use crate::{
+    authentication::reject_anonymous_users,
+    cleanup_worker::run_cleanup_until_stopped,
     configuration::{DatabaseSettings, Settings},
-    email_client::EmailClient,
+    email_templates::EmailTemplates,
+    email_transport::EmailTransport,
     greet::greet,
-    routes::{check_health, subscribe,confirm},
+    issue_delivery_worker::run_worker_until_stopped,
+    outbox::run_outbox_dispatcher_until_stopped,
+    rate_limit::{limit_password_resets_per_ip, limit_subscriptions_per_ip},
+    routes::{
+        admin_dashboard, change_password, change_password_form, check_health, log_out, login,
+        login_form, password_reset_confirm_form, password_reset_form, request_password_reset,
+        reset_password, subscribe, confirm, publish_newsletter, unsubscribe, unsubscribe_one_click,
+        receive_email_event, subscribe_form, list_dead_letters, inspect_dead_letter,
+        requeue_dead_letter, discard_dead_letter, list_subscribers, inspect_subscriber,
+        update_subscriber, delete_subscriber, import_subscribers, export_subscribers,
+        preferences_form, update_preferences,
+    },
+    session_store::PostgresSessionStore,
 };
+use anyhow::Context;
+use actix_session::{config::CookieContentSecurity, SessionMiddleware};
 // use actix_web::{middleware::Logger, guard::Trace};
-use actix_web::{dev::Server, guard, web, App, HttpServer, Route};
+use actix_web::{cookie::Key, dev::Server, guard, web, App, HttpServer, Route};
+use actix_web_flash_messages::{storage::CookieMessageStore, FlashMessagesFramework};
+use actix_web_lab::middleware::from_fn;
+use secrecy::{ExposeSecret, Secret};
 use sqlx::{postgres::PgPoolOptions, PgPool};
-use std::net::TcpListener;
+use std::{net::TcpListener, sync::Arc};
 use tracing_actix_web::TracingLogger;
 
 // We need to define a wrapper type in order to retrieve the URL
@@ -15,6 +35,8 @@ use tracing_actix_web::TracingLogger;
 // Retrieval from the context, in actix-web, is type-based: using
 // a raw `String` would expose us to conflicts.
 pub struct ApplicationBaseUrl(pub String);
+//same reason, a raw Secret<String> would clash with any other secret
+pub struct EmailWebhookSecret(pub Secret<String>);
 ///server owns a dynamic owned boxFuture which when awiated returns a std::io::Result<()>
 /// that is why its return value is acceptable
 /// reason for future not being send and giving used across await error
@@ -25,54 +47,126 @@ pub struct ApplicationBaseUrl(pub String);
 ///
 ///
 /// 
+/// the email client and templates are shared with the workers, everything else
+/// the api needs is read from `settings`
 pub fn run(
     listner: TcpListener,
     connection: PgPool,
-    email_client: EmailClient,
-    base_url : String
+    email_client: Arc<dyn EmailTransport>,
+    email_templates: Arc<EmailTemplates>,
+    settings: &Settings,
 ) -> std::result::Result<Server, std::io::Error> {
+    //same key signs the session cookie and the flash message cookie
+    let secret_key = Key::from(settings.application.hmac_secret.expose_secret().as_bytes());
+    let message_store = CookieMessageStore::builder(secret_key.clone()).build();
+    let message_framework = FlashMessagesFramework::builder(message_store).build();
+    let session_store = PostgresSessionStore::new(connection.clone());
+    let subscribe_rate_limits = settings.rate_limit.subscribe_limits(connection.clone());
+    let password_reset_rate_limits = settings.rate_limit.password_reset_limits(connection.clone());
+    let bot_protection = settings
+        .bot_protection
+        .bot_protection(&settings.application.hmac_secret);
     let wrapped_connection = web::Data::new(connection);
-    let wrapped_email_client = web::Data::new(email_client);
-    let wrapped_base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let wrapped_email_client: web::Data<dyn EmailTransport> = web::Data::from(email_client);
+    let wrapped_email_templates = web::Data::from(email_templates);
+    let wrapped_subscribe_rate_limits = web::Data::new(subscribe_rate_limits);
+    let wrapped_password_reset_rate_limits = web::Data::new(password_reset_rate_limits);
+    let wrapped_bot_protection = web::Data::new(bot_protection);
+    let wrapped_base_url =
+        web::Data::new(ApplicationBaseUrl(settings.application.base_url.clone()));
+    let wrapped_webhook_secret =
+        web::Data::new(EmailWebhookSecret(settings.email_client.webhook_secret.clone()));
     let srv = HttpServer::new(move || {
         App::new()
             //logger is not tracing aware
             // .wrap(Logger::default())
             //solution use a tracing aware logger
+            .wrap(message_framework.clone())
+            .wrap(
+                SessionMiddleware::builder(session_store.clone(), secret_key.clone())
+                    //the state lives in postgres, the cookie only carries the signed key
+                    .cookie_content_security(CookieContentSecurity::Signed)
+                    .build(),
+            )
             .wrap(TracingLogger::default())
             .route("/", web::get().to(greet))
+            //has to be registered before the catch-all /{name}
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .route("/password-reset", web::get().to(password_reset_form))
+            .service(
+                web::resource("/password-reset")
+                    .guard(guard::Post())
+                    .wrap(from_fn(limit_password_resets_per_ip))
+                    .route(web::post().to(request_password_reset)),
+            )
+            .route("/password-reset/confirm", web::get().to(password_reset_confirm_form))
+            .route("/password-reset/confirm", web::post().to(reset_password))
+            .route("/subscriptions", web::get().to(subscribe_form))
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route("/dashboard", web::get().to(admin_dashboard))
+                    .route("/logout", web::post().to(log_out))
+                    .route("/password", web::get().to(change_password_form))
+                    .route("/password", web::post().to(change_password))
+                    .route("/dead-letters", web::get().to(list_dead_letters))
+                    .route("/dead-letters/{dead_letter_id}", web::get().to(inspect_dead_letter))
+                    .route("/dead-letters/{dead_letter_id}", web::delete().to(discard_dead_letter))
+                    .route(
+                        "/dead-letters/{dead_letter_id}/requeue",
+                        web::post().to(requeue_dead_letter),
+                    )
+                    .route("/subscribers", web::get().to(list_subscribers))
+                    //has to be registered before /subscribers/{subscriber_id}
+                    .route("/subscribers/import", web::post().to(import_subscribers))
+                    .route("/subscribers/export", web::get().to(export_subscribers))
+                    .route("/subscribers/{subscriber_id}", web::get().to(inspect_subscriber))
+                    .route("/subscribers/{subscriber_id}", web::patch().to(update_subscriber))
+                    .route("/subscribers/{subscriber_id}", web::delete().to(delete_subscriber)),
+            )
             .route(
                 "/health_check",
                 //web::get is a macro for the below verbose syntx
                 Route::new().guard(guard::Get()).to(check_health),
             )
             .route("/{name}", web::get().to(greet))
-            .route(
-                "/subscriptions",
-                Route::new().guard(guard::Post()).to(subscribe),
+            .service(
+                web::resource("/subscriptions")
+                    .guard(guard::Post())
+                    .wrap(from_fn(limit_subscriptions_per_ip))
+                    .route(web::post().to(subscribe)),
             )
             .route("/subscriptions/confirm",Route::new().guard(guard::Get()).to(confirm))
+            .route("/subscriptions/unsubscribe", web::get().to(unsubscribe))
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe_one_click))
+            .route("/subscriptions/preferences", web::get().to(preferences_form))
+            .route("/subscriptions/preferences", web::post().to(update_preferences))
+            .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/webhooks/email-events", web::post().to(receive_email_event))
             .app_data(wrapped_connection.clone())
             .app_data(wrapped_email_client.clone())
+            .app_data(wrapped_email_templates.clone())
+            .app_data(wrapped_subscribe_rate_limits.clone())
+            .app_data(wrapped_password_reset_rate_limits.clone())
+            .app_data(wrapped_bot_protection.clone())
             .app_data(wrapped_base_url.clone())
+            .app_data(wrapped_webhook_secret.clone())
     })
     .listen(listner)?
     .run();
     Ok(srv)
 }
 impl Application {
-    pub fn build(settings: Settings) -> Result<Application, std::io::Error> {
-        let sender_email = settings
-            .email_client
-            .sender()
-            .expect("invalid sender email");
-        let timeout = settings.email_client.timeout();
-        let email_client = EmailClient::new(
-            settings.email_client.base_url,
-            sender_email,
-            settings.email_client.authorization_token,
-            timeout,
+    pub fn build(settings: Settings) -> Result<Application, anyhow::Error> {
+        //a broken template override should stop us here, not fail a live request
+        let email_templates = Arc::new(
+            settings
+                .email_templates
+                .templates()
+                .context("Failed to load the email templates")?,
         );
+        let email_client = settings.email_client.clone().transport();
         let address = format!(
             "{}:{}",
             settings.application.host, settings.application.port
@@ -87,19 +181,59 @@ impl Application {
         //Build an email client using settings
         //fetch sender_email and parse it to SubScriber Email domain type (which encoded invariants aroudn email format in its name)
 
-        let server = run(new_listener, connection, email_client,
-            //confirmation email domain
-            settings.application.base_url)?;
+        let server = run(
+            new_listener,
+            connection.clone(),
+            email_client.clone(),
+            email_templates.clone(),
+            &settings,
+        )?;
         Ok(Self {
             server,
             port: port_num,
+            worker_pool: connection,
+            worker_email_client: email_client,
+            worker_email_templates: email_templates,
+            worker_base_url: settings.application.base_url,
         })
     }
     pub fn port(&self) -> u16 {
         self.port
     }
-    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+    //drives the http server, the issue delivery worker, the outbox dispatcher
+    //and the cleanup worker,
+    //returns as soon as any of them exits
+    pub async fn run_until_stopped(self) -> Result<(), anyhow::Error> {
+        let cleanup = tokio::spawn(run_cleanup_until_stopped(self.worker_pool.clone()));
+        let outbox_dispatcher = tokio::spawn(run_outbox_dispatcher_until_stopped(
+            self.worker_pool.clone(),
+            self.worker_email_client.clone(),
+        ));
+        let worker = tokio::spawn(run_worker_until_stopped(
+            self.worker_pool,
+            self.worker_email_client,
+            self.worker_email_templates,
+            self.worker_base_url,
+        ));
+        tokio::select! {
+            outcome = self.server => {
+                tracing::info!("API server has exited");
+                outcome?;
+            }
+            outcome = worker => {
+                tracing::error!("Issue delivery worker has exited");
+                outcome??;
+            }
+            outcome = outbox_dispatcher => {
+                tracing::error!("Outbox dispatcher has exited");
+                outcome??;
+            }
+            outcome = cleanup => {
+                tracing::error!("Cleanup worker has exited");
+                outcome??;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -107,13 +241,16 @@ pub fn get_pool_conn(settings: &DatabaseSettings) -> PgPool {
     let conn_string = settings.connection_string();
     //set connection acquite to 2 seconds using PgOptions
     //connect_lazy_with isnt async so no need to await it
-    let connection = PgPoolOptions::new()
+    PgPoolOptions::new()
         .acquire_timeout(std::time::Duration::from_secs(2))
-        .connect_lazy_with(conn_string);
-    connection
+        .connect_lazy_with(conn_string)
 }
 
 pub struct Application {
     server: Server,
     port: u16,
+    worker_pool: PgPool,
+    worker_email_client: Arc<dyn EmailTransport>,
+    worker_email_templates: Arc<EmailTemplates>,
+    worker_base_url: String,
 }
@@ -0,0 +1,64 @@
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::domain::{IllegalStatusTransition, SubscriptionStatus};
+use crate::routes::error_chain_fmt;
+
+//every status change goes through here, so SubscriptionStatus::transition_to
+//is checked against the stored status while the row is locked
+#[tracing::instrument(name = "Change the status of a subscriber", skip(transaction))]
+pub async fn change_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    next: SubscriptionStatus,
+) -> Result<SubscriptionStatus, StatusChangeError> {
+    apply_transition(transaction, subscriber_id, |current| current.transition_to(next)).await
+}
+
+//for the admin changing a subscriber's email, see SubscriptionStatus::reconfirm_new_email
+#[tracing::instrument(name = "Reconfirm the new email of a subscriber", skip(transaction))]
+pub async fn reconfirm_new_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<SubscriptionStatus, StatusChangeError> {
+    apply_transition(transaction, subscriber_id, SubscriptionStatus::reconfirm_new_email).await
+}
+
+//returns the status before the change
+async fn apply_transition(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    transition: impl FnOnce(SubscriptionStatus) -> Result<SubscriptionStatus, IllegalStatusTransition>,
+) -> Result<SubscriptionStatus, StatusChangeError> {
+    let current = sqlx::query!(
+        r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .status;
+    let next = transition(current)?;
+    if next != current {
+        sqlx::query!(
+            r#"UPDATE subscriptions SET status = $2 WHERE id = $1"#,
+            subscriber_id,
+            next.as_str()
+        )
+        .execute(transaction)
+        .await?;
+    }
+    Ok(current)
+}
+
+#[derive(thiserror::Error)]
+pub enum StatusChangeError {
+    #[error(transparent)]
+    IllegalTransition(#[from] IllegalStatusTransition),
+    #[error("Failed to change the status of a subscriber.")]
+    DatabaseError(#[from] sqlx::Error),
+}
+impl std::fmt::Debug for StatusChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
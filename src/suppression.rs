@@ -0,0 +1,52 @@
+use sqlx::PgExecutor;
+
+//why an address ended up on the suppression list
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuppressionReason {
+    HardBounce,
+    SpamComplaint,
+}
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HardBounce => "hard_bounce",
+            Self::SpamComplaint => "spam_complaint",
+        }
+    }
+}
+
+#[tracing::instrument(name = "Check the suppression list", skip(executor))]
+pub async fn is_suppressed<'e>(
+    executor: impl PgExecutor<'e>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT EXISTS(SELECT 1 FROM suppressed_emails WHERE email = lower($1)) AS "suppressed!""#,
+        email
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(row.suppressed)
+}
+
+//stored lowercased, providers do not preserve the case we sent with
+//the first reason wins, a complaint after a bounce does not change anything
+#[tracing::instrument(name = "Add an address to the suppression list", skip(executor))]
+pub async fn suppress<'e>(
+    executor: impl PgExecutor<'e>,
+    email: &str,
+    reason: SuppressionReason,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressed_emails (email, reason, suppressed_at)
+        VALUES (lower($1), $2, now())
+        ON CONFLICT (email) DO NOTHING
+        "#,
+        email,
+        reason.as_str()
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use tokio::task::JoinHandle;
 use tracing::subscriber::{set_global_default, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
@@ -27,3 +28,14 @@ pub fn init_global_logger(sub: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(sub).expect("failed to set a subscriber");
 }
+
+//tokio::spawn_blocking runs on a different thread, which does not inherit the
+//current span, so we carry it over explicitly
+pub fn spawn_blocking_with_tracing<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+}
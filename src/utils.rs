@@ -0,0 +1,27 @@
+use actix_web::http::header::LOCATION;
+use actix_web::HttpResponse;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+// Return an opaque 500 while preserving the error root's cause for logging.
+pub fn e500<T>(e: T) -> actix_web::Error
+where
+    T: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    actix_web::error::ErrorInternalServerError(e)
+}
+
+pub fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+//alphanumeric token drawn from a lazily seeded CSPRNG
+pub fn generate_random_token(length: usize) -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(length)
+        .collect()
+}
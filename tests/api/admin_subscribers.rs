@@ -0,0 +1,324 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+//bypasses the signup flow so tests control the status and signup date
+async fn insert_subscriber(app: &TestApp, email: &str, status: &str, days_ago: i32) -> String {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions(id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES($1, $2, 'le guin', now() - make_interval(days => $3), $4, $5)
+        "#,
+        id,
+        email,
+        days_ago,
+        status,
+        Uuid::new_v4().to_string()
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    id.to_string()
+}
+
+async fn list_emails(app: &TestApp, query: &str) -> Vec<String> {
+    let page: serde_json::Value = app.get_subscribers(query).await.json().await.unwrap();
+    page["subscribers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["email"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_subscribers() {
+    //Arrange
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "ursula@gmail.com", "confirmed", 0).await;
+    //Act
+    let list = app.get_subscribers("").await;
+    let delete = app.delete_subscriber(&id).await;
+    //Assert
+    assert_is_redirect_to(&list, "/login");
+    assert_is_redirect_to(&delete, "/login");
+}
+
+#[tokio::test]
+async fn subscribers_are_paginated_newest_first() {
+    //Arrange
+    let app = spawn_app().await;
+    for days_ago in 0..5 {
+        insert_subscriber(&app, &format!("ursula{}@gmail.com", days_ago), "confirmed", days_ago)
+            .await;
+    }
+    app.login_test_user().await;
+    //Act
+    let mut emails = Vec::new();
+    let mut pages = 0;
+    let mut query = "limit=2".to_owned();
+    loop {
+        let page: serde_json::Value = app.get_subscribers(&query).await.json().await.unwrap();
+        pages += 1;
+        for subscriber in page["subscribers"].as_array().unwrap() {
+            emails.push(subscriber["email"].as_str().unwrap().to_owned());
+        }
+        match page["next_cursor"].as_str() {
+            Some(cursor) => query = format!("limit=2&cursor={}", cursor),
+            None => break,
+        }
+    }
+    //Assert
+    assert_eq!(pages, 3);
+    let expected: Vec<String> = (0..5).map(|i| format!("ursula{}@gmail.com", i)).collect();
+    assert_eq!(emails, expected);
+}
+
+#[tokio::test]
+async fn subscribers_can_be_sorted_oldest_first() {
+    //Arrange
+    let app = spawn_app().await;
+    insert_subscriber(&app, "new@gmail.com", "confirmed", 0).await;
+    insert_subscriber(&app, "old@gmail.com", "confirmed", 10).await;
+    app.login_test_user().await;
+    //Act
+    let emails = list_emails(&app, "order=asc").await;
+    //Assert
+    assert_eq!(emails, ["old@gmail.com", "new@gmail.com"]);
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered() {
+    //Arrange
+    let app = spawn_app().await;
+    insert_subscriber(&app, "ursula@gmail.com", "confirmed", 1).await;
+    insert_subscriber(&app, "URSULA_LE_GUIN@gmail.com", "pending_confirmation", 1).await;
+    insert_subscriber(&app, "octavia@gmail.com", "confirmed", 30).await;
+    app.login_test_user().await;
+    //Act
+    let confirmed = list_emails(&app, "status=confirmed&order=asc").await;
+    let ursulas = list_emails(&app, "email=ursula&order=asc").await;
+    //the underscore is not a wildcard
+    let le_guins = list_emails(&app, "email=a_le").await;
+    let recent = list_emails(&app, "subscribed_after=2000-01-01T00:00:00Z&status=confirmed").await;
+    let old = list_emails(&app, "subscribed_before=2000-01-01T00:00:00Z").await;
+    //Assert
+    assert_eq!(confirmed, ["octavia@gmail.com", "ursula@gmail.com"]);
+    assert_eq!(ursulas.len(), 2);
+    assert_eq!(le_guins, ["URSULA_LE_GUIN@gmail.com"]);
+    assert_eq!(recent.len(), 2);
+    assert!(old.is_empty());
+}
+
+#[tokio::test]
+async fn invalid_filters_are_rejected_with_a_400() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    //Act
+    let status = app.get_subscribers("status=maybe").await;
+    let cursor = app.get_subscribers("cursor=not-a-cursor").await;
+    //Assert
+    assert_eq!(status.status().as_u16(), 400);
+    assert_eq!(cursor.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_subscriber_can_be_inspected() {
+    //Arrange
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "ursula@gmail.com", "confirmed", 0).await;
+    app.login_test_user().await;
+    //Act
+    let subscriber: serde_json::Value = app.get_subscriber(&id).await.json().await.unwrap();
+    let missing = app.get_subscriber(&Uuid::new_v4().to_string()).await;
+    //Assert
+    assert_eq!(subscriber["email"], "ursula@gmail.com");
+    assert_eq!(subscriber["status"], "confirmed");
+    assert_eq!(missing.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn a_subscriber_can_be_renamed_and_confirmed() {
+    //Arrange
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "ursula@gmail.com", "pending_confirmation", 0).await;
+    app.login_test_user().await;
+    //Act
+    let response = app
+        .patch_subscriber(
+            &id,
+            &serde_json::json!({"name": "Ursula K. Le Guin", "status": "confirmed"}),
+        )
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscriber: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(subscriber["name"], "Ursula K. Le Guin");
+    assert_eq!(subscriber["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn invalid_changes_are_rejected_and_nothing_is_saved() {
+    //Arrange
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "ursula@gmail.com", "pending_confirmation", 0).await;
+    let confirmed_id = insert_subscriber(&app, "octavia@gmail.com", "confirmed", 0).await;
+    sqlx::query!(
+        "INSERT INTO suppressed_emails(email, reason, suppressed_at) VALUES('bounced@gmail.com', 'hard_bounce', now())"
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    app.login_test_user().await;
+    let test_cases = [
+        (&id, serde_json::json!({"name": "<script>"}), 400, "an invalid name"),
+        (&id, serde_json::json!({"email": "not-an-email"}), 400, "an invalid email"),
+        (&id, serde_json::json!({"email": "octavia@gmail.com"}), 409, "a taken email"),
+        (&id, serde_json::json!({"email": "Bounced@gmail.com"}), 409, "a suppressed email"),
+        //confirmed subscribers cannot be sent back to pending
+        (
+            &confirmed_id,
+            serde_json::json!({"name": "Octavia", "status": "pending_confirmation"}),
+            409,
+            "an illegal transition",
+        ),
+    ];
+    for (id, body, expected, description) in test_cases {
+        //Act
+        let response = app.patch_subscriber(id, &body).await;
+        //Assert
+        assert_eq!(
+            response.status().as_u16(),
+            expected,
+            "The API did not reject {}.",
+            description
+        );
+    }
+    let subscriber: serde_json::Value = app.get_subscriber(&id).await.json().await.unwrap();
+    assert_eq!(subscriber["name"], "le guin");
+    assert_eq!(subscriber["email"], "ursula@gmail.com");
+    assert_eq!(subscriber["status"], "pending_confirmation");
+    let subscriber: serde_json::Value =
+        app.get_subscriber(&confirmed_id).await.json().await.unwrap();
+    assert_eq!(subscriber["name"], "le guin");
+    assert_eq!(subscriber["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn a_new_email_has_to_be_confirmed_again() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    let id = insert_subscriber(&app, "ursula@gmail.com", "confirmed", 0).await;
+    app.login_test_user().await;
+    //Act
+    let response = app
+        .patch_subscriber(&id, &serde_json::json!({"email": "le.guin@gmail.com"}))
+        .await;
+    app.dispatch_outbox().await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscriber: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(subscriber["email"], "le.guin@gmail.com");
+    assert_eq!(subscriber["status"], "pending_confirmation");
+    let request = &app.mock_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["To"], "le.guin@gmail.com");
+    let links = app.get_confirmation_links(request);
+    assert_eq!(reqwest::get(links.html).await.unwrap().status().as_u16(), 200);
+    let subscriber: serde_json::Value = app.get_subscriber(&id).await.json().await.unwrap();
+    assert_eq!(subscriber["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn the_email_of_a_bounced_or_complained_subscriber_cannot_be_changed() {
+    //Arrange
+    let app = spawn_app().await;
+    let bounced = insert_subscriber(&app, "ursula@gmail.com", "bounced", 0).await;
+    let complained = insert_subscriber(&app, "octavia@gmail.com", "complained", 0).await;
+    app.login_test_user().await;
+    for (id, status) in [(&bounced, "bounced"), (&complained, "complained")] {
+        //Act
+        let response = app
+            .patch_subscriber(id, &serde_json::json!({"email": "new.address@gmail.com"}))
+            .await;
+        //Assert
+        assert_eq!(response.status().as_u16(), 409);
+        let subscriber: serde_json::Value = app.get_subscriber(id).await.json().await.unwrap();
+        assert_eq!(subscriber["status"], status);
+        assert_ne!(subscriber["email"], "new.address@gmail.com");
+    }
+}
+
+#[tokio::test]
+async fn fixing_the_case_of_an_email_keeps_the_subscriber_confirmed() {
+    //Arrange
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "ursula@gmail.com", "confirmed", 0).await;
+    app.login_test_user().await;
+    //Act
+    let response = app
+        .patch_subscriber(&id, &serde_json::json!({"email": "Ursula@gmail.com"}))
+        .await;
+    //Assert
+    let subscriber: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(subscriber["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn marking_a_subscriber_bounced_or_complained_suppresses_the_address() {
+    //Arrange
+    let app = spawn_app().await;
+    let bounced = insert_subscriber(&app, "ursula@gmail.com", "confirmed", 0).await;
+    let complained = insert_subscriber(&app, "octavia@gmail.com", "confirmed", 0).await;
+    app.login_test_user().await;
+    //Act
+    app.patch_subscriber(&bounced, &serde_json::json!({"status": "bounced"}))
+        .await;
+    app.patch_subscriber(&complained, &serde_json::json!({"status": "complained"}))
+        .await;
+    //Assert
+    let suppressed: Vec<(String, String)> =
+        sqlx::query!("SELECT email, reason FROM suppressed_emails ORDER BY email")
+            .fetch_all(&app.pool_conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.email, row.reason))
+            .collect();
+    assert_eq!(
+        suppressed,
+        [
+            ("octavia@gmail.com".to_owned(), "spam_complaint".to_owned()),
+            ("ursula@gmail.com".to_owned(), "hard_bounce".to_owned())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_subscriber_can_be_deleted() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    let id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .id
+        .to_string();
+    app.login_test_user().await;
+    //Act
+    let response = app.delete_subscriber(&id).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(app.get_subscriber(&id).await.status().as_u16(), 404);
+    assert_eq!(app.delete_subscriber(&id).await.status().as_u16(), 404);
+}
@@ -0,0 +1,325 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn statuses(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!("SELECT email, status FROM subscriptions ORDER BY email")
+        .fetch_all(&app.pool_conn)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| (row.email, row.status))
+        .collect()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_import_or_export_subscribers() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let import = app
+        .post_subscribers_import("confirmed", "email,name\nursula@gmail.com,Ursula\n")
+        .await;
+    let export = app.get_subscribers_export("").await;
+    //Assert
+    assert_is_redirect_to(&import, "/login");
+    assert_is_redirect_to(&export, "/login");
+    assert!(statuses(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn confirmed_imports_do_not_send_emails() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    app.login_test_user().await;
+    //Act
+    let response = app
+        .post_subscribers_import(
+            "confirmed",
+            "email,name\nursula@gmail.com,\"Le Guin, Ursula\"\noctavia@gmail.com,Octavia",
+        )
+        .await;
+    app.dispatch_outbox().await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["imported"], 2);
+    assert_eq!(report["rejected"], 0);
+    assert_eq!(
+        statuses(&app).await,
+        [
+            ("octavia@gmail.com".to_owned(), "confirmed".to_owned()),
+            ("ursula@gmail.com".to_owned(), "confirmed".to_owned())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn imports_send_a_confirmation_email_by_default() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.mock_server)
+        .await;
+    app.login_test_user().await;
+    //Act
+    let response = app
+        .post_subscribers_import(
+            "send_confirmation",
+            "email,name\nursula@gmail.com,Ursula\noctavia@gmail.com,Octavia\n",
+        )
+        .await;
+    app.dispatch_outbox().await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    for (_, status) in statuses(&app).await {
+        assert_eq!(status, "pending_confirmation");
+    }
+    let requests = app.mock_server.received_requests().await.unwrap();
+    let links = app.get_confirmation_links(&requests[0]);
+    let confirm = reqwest::get(links.html).await.unwrap();
+    assert_eq!(confirm.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn rejected_rows_are_reported_and_the_rest_are_imported() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    let existing = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .email;
+    sqlx::query!(
+        "INSERT INTO suppressed_emails(email, reason, suppressed_at) VALUES('bounced@gmail.com', 'hard_bounce', now())"
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    app.login_test_user().await;
+    let csv = format!(
+        "email,name\n\
+         ursula@gmail.com,Ursula\n\
+         not-an-email,Octavia\n\
+         ursula@gmail.com,Ursula again\n\
+         {},Already here\n\
+         bounced@gmail.com,Bounced\n\
+         too,many,fields\n",
+        existing
+    );
+    //Act
+    let response = app.post_subscribers_import("confirmed", &csv).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["imported"], 1);
+    assert_eq!(report["rejected"], 5);
+    let mut rows: Vec<u64> = report["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["row"].as_u64().unwrap())
+        .collect();
+    rows.sort();
+    assert_eq!(rows, [3, 4, 5, 6, 7]);
+    assert_eq!(statuses(&app).await.len(), 2);
+}
+
+#[tokio::test]
+async fn an_import_without_email_and_name_columns_is_rejected_with_a_400() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    let test_cases = [
+        ("email\nursula@gmail.com\n", "a missing name column"),
+        ("", "an empty file"),
+    ];
+    for (csv, description) in test_cases {
+        //Act
+        let response = app.post_subscribers_import("confirmed", csv).await;
+        //Assert
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn extra_columns_become_custom_fields() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    //Act
+    app.post_subscribers_import(
+        "confirmed",
+        "Name,Email,City,custom_fields\nUrsula,ursula@gmail.com,Portland,\"{\"\"plan\"\": \"\"pro\"\"}\"\n",
+    )
+    .await;
+    //Assert
+    let custom_fields = sqlx::query!("SELECT custom_fields FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .custom_fields;
+    assert_eq!(
+        custom_fields,
+        serde_json::json!({"city": "Portland", "plan": "pro"})
+    );
+}
+
+#[tokio::test]
+async fn the_export_is_a_csv_of_the_filtered_subscribers_that_can_be_imported_back() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    app.post_subscribers_import(
+        "confirmed",
+        "email,name,city\nursula@gmail.com,\"Le Guin, Ursula\",Portland\noctavia@gmail.com,Octavia,\n",
+    )
+    .await;
+    app.create_unconfirmed_subscriber().await;
+    //Act
+    let response = app.get_subscribers_export("status=confirmed&order=asc").await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "text/csv; charset=utf-8");
+    let csv = response.text().await.unwrap();
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let records: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 2);
+    let ursula = records.iter().find(|r| &r[1] == "ursula@gmail.com").unwrap();
+    assert_eq!(&ursula[2], "Le Guin, Ursula");
+    assert_eq!(&ursula[3], "confirmed");
+    //importing the export again only finds duplicates
+    sqlx::query!("DELETE FROM subscriptions WHERE email = 'octavia@gmail.com'")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    let report: serde_json::Value = app
+        .post_subscribers_import("confirmed", &csv)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["imported"], 1);
+    assert_eq!(report["rejected"], 1);
+    let custom_fields = sqlx::query!(
+        "SELECT custom_fields FROM subscriptions WHERE email = 'ursula@gmail.com'"
+    )
+    .fetch_one(&app.pool_conn)
+    .await
+    .unwrap()
+    .custom_fields;
+    assert_eq!(custom_fields, serde_json::json!({"city": "Portland"}));
+}
+
+#[tokio::test]
+async fn emails_are_deduplicated_and_suppressed_whatever_their_case() {
+    //Arrange
+    let app = spawn_app().await;
+    sqlx::query!(
+        "INSERT INTO suppressed_emails(email, reason, suppressed_at) VALUES('bounced@gmail.com', 'hard_bounce', now())"
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    app.login_test_user().await;
+    //a byte order mark in front of the header, as Excel writes it
+    let csv = "\u{feff}email,name\n\
+               Ursula@Gmail.com,Ursula\n\
+               ursula@gmail.com,Ursula again\n\
+               Bounced@gmail.com,Bounced\n";
+    //Act
+    let response = app.post_subscribers_import("confirmed", csv).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["imported"], 1);
+    assert_eq!(report["rejected"], 2);
+    assert_eq!(
+        statuses(&app).await,
+        [("ursula@gmail.com".to_owned(), "confirmed".to_owned())]
+    );
+}
+
+#[tokio::test]
+async fn a_record_over_the_size_limit_is_rejected_with_a_400() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    let csv = format!("email,name\nursula@gmail.com,{}\n", "a".repeat(2 * 1024 * 1024));
+    //Act
+    let response = app.post_subscribers_import("confirmed", &csv).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(statuses(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn exported_cells_are_not_run_as_formulas_and_import_back_unchanged() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    let name = "=1+2";
+    app.post_subscribers_import("confirmed", &format!("email,name\nursula@gmail.com,{}\n", name))
+        .await;
+    //Act
+    let csv = app
+        .get_subscribers_export("")
+        .await
+        .text()
+        .await
+        .unwrap();
+    //Assert
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(&record[2], format!("'{}", name));
+    sqlx::query!("DELETE FROM subscriptions")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    app.post_subscribers_import("confirmed", &csv).await;
+    let stored = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .name;
+    assert_eq!(stored, name);
+}
+
+#[tokio::test]
+async fn existing_subscribers_are_found_whatever_the_case_of_their_email() {
+    //Arrange
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.login_test_user().await;
+    //Act
+    let response = app
+        .post_subscribers_import("confirmed", "email,name\nursula_le_guin@gmail.com,Ursula\n")
+        .await;
+    //Assert
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["imported"], 0);
+    assert_eq!(report["rejected"], 1);
+    assert_eq!(report["errors"][0]["error"], "Already subscribed.");
+    assert_eq!(
+        statuses(&app).await,
+        [("Ursula_Le_Guin@gmail.com".to_owned(), "pending_confirmation".to_owned())]
+    );
+}
@@ -0,0 +1,158 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::CaptchaProvider;
+
+//the hidden form_token input of GET /subscriptions
+async fn get_form_token(app: &TestApp) -> String {
+    let html = reqwest::get(format!("{}/subscriptions", app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let marker = r#"name="form_token" value=""#;
+    let start = html.find(marker).expect("no form token") + marker.len();
+    let end = start + html[start..].find('"').unwrap();
+    html[start..end].to_owned()
+}
+
+async fn mount_email_mock(app: &TestApp, expected: u64) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected)
+        .mount(&app.mock_server)
+        .await;
+}
+
+#[tokio::test]
+async fn a_filled_honeypot_looks_like_a_success_but_does_nothing() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&website=http%3A%2F%2Fspam.example.com"
+                .into(),
+        )
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscribers = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(subscribers, 0);
+}
+
+#[tokio::test]
+async fn the_subscription_form_carries_the_honeypot_and_a_form_token() {
+    //Arrange
+    let app = spawn_app_with(|c| c.bot_protection.form_token.enabled = true).await;
+    //Act
+    let html = reqwest::get(format!("{}/subscriptions", app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    //Assert
+    assert!(html.contains(r#"name="website""#));
+    assert!(html.contains(r#"name="form_token""#));
+}
+
+#[tokio::test]
+async fn submissions_without_a_valid_form_token_are_rejected() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.bot_protection.form_token.enabled = true;
+        c.bot_protection.form_token.min_fill_seconds = 0;
+    })
+    .await;
+    mount_email_mock(&app, 0).await;
+    let base = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    for body in [
+        base.to_owned(),
+        format!("{}&form_token=1700000000.bm90LWEtc2lnbmF0dXJl", base),
+    ] {
+        //Act
+        let response = app.post_subscriptions(body).await;
+        //Assert
+        assert_eq!(response.status().as_u16(), 400);
+    }
+}
+
+#[tokio::test]
+async fn submissions_with_a_form_token_from_the_form_are_accepted() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.bot_protection.form_token.enabled = true;
+        c.bot_protection.form_token.min_fill_seconds = 0;
+    })
+    .await;
+    mount_email_mock(&app, 1).await;
+    let token = get_form_token(&app).await;
+    //Act
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&form_token={}",
+            token
+        ))
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_outbox().await;
+}
+
+#[tokio::test]
+async fn submissions_arriving_too_fast_are_rejected() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.bot_protection.form_token.enabled = true;
+        c.bot_protection.form_token.min_fill_seconds = 60;
+    })
+    .await;
+    mount_email_mock(&app, 0).await;
+    let token = get_form_token(&app).await;
+    //Act
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&form_token={}",
+            token
+        ))
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn the_captcha_must_be_solved_when_configured() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.bot_protection.captcha.provider = CaptchaProvider::Fake;
+        c.bot_protection.captcha.fake_response = "pass".into();
+    })
+    .await;
+    mount_email_mock(&app, 1).await;
+    let base = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    //Act
+    let missing = app.post_subscriptions(base.into()).await;
+    let wrong = app
+        .post_subscriptions(format!("{}&h-captcha-response=fail", base))
+        .await;
+    let solved = app
+        .post_subscriptions(format!("{}&h-captcha-response=pass", base))
+        .await;
+    //Assert
+    assert_eq!(missing.status().as_u16(), 400);
+    assert_eq!(wrong.status().as_u16(), 400);
+    assert_eq!(solved.status().as_u16(), 200);
+    app.dispatch_outbox().await;
+}
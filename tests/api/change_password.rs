@@ -0,0 +1,175 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_the_change_password_form() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = app.get_change_password().await;
+    //Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_change_your_password() {
+    //Arrange
+    let app = spawn_app().await;
+    let new_password = Uuid::new_v4().to_string();
+    //Act
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": Uuid::new_v4().to_string(),
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    //Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn new_password_fields_must_match() {
+    //Arrange
+    let app = spawn_app().await;
+    let new_password = Uuid::new_v4().to_string();
+    let another_new_password = Uuid::new_v4().to_string();
+    app.login_test_user().await;
+    //Act - Part 1 - Try to change password
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &app.test_user.password,
+            "new_password": &new_password,
+            "new_password_check": &another_new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+    //Act - Part 2 - Follow the redirect
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains(
+        "<p><i>You entered two different new passwords - \
+        the field values must match.</i></p>"
+    ));
+}
+
+#[tokio::test]
+async fn new_password_must_be_long_enough() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    //Act - Part 1 - Try to change password
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &app.test_user.password,
+            "new_password": "short",
+            "new_password_check": "short",
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+    //Act - Part 2 - Follow the redirect
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains("The new password must be between 12 and 128 characters long."));
+}
+
+#[tokio::test]
+async fn current_password_must_be_valid() {
+    //Arrange
+    let app = spawn_app().await;
+    let new_password = Uuid::new_v4().to_string();
+    let wrong_password = Uuid::new_v4().to_string();
+    app.login_test_user().await;
+    //Act - Part 1 - Try to change password
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &wrong_password,
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+    //Act - Part 2 - Follow the redirect
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains("<p><i>The current password is incorrect.</i></p>"));
+}
+
+#[tokio::test]
+async fn changing_password_works() {
+    //Arrange
+    let app = spawn_app().await;
+    let new_password = Uuid::new_v4().to_string();
+    app.login_test_user().await;
+    //Act - Part 1 - Change password
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &app.test_user.password,
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+    //Act - Part 2 - Follow the redirect
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains("<p><i>Your password has been changed.</i></p>"));
+    //Act - Part 3 - Logout
+    let response = app.post_logout().await;
+    assert_is_redirect_to(&response, "/login");
+    //Act - Part 4 - Login using the new password
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &new_password
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn the_seeded_admin_must_change_their_password() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let admin = sqlx::query!("SELECT must_change_password FROM users WHERE username = 'admin'")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    //Assert
+    assert!(admin.must_change_password);
+}
+
+#[tokio::test]
+async fn users_who_must_change_their_password_can_do_nothing_else_until_they_do() {
+    //Arrange
+    let app = spawn_app().await;
+    sqlx::query!(
+        "UPDATE users SET must_change_password = true WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    let new_password = Uuid::new_v4().to_string();
+    //Act - Part 1 - Login
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &app.test_user.password
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains("<p><i>You must change your password before continuing.</i></p>"));
+    //Act - Part 2 - Everything else sends them back
+    assert_is_redirect_to(&app.get_admin_dashboard().await, "/admin/password");
+    assert_is_redirect_to(&app.get_subscribers("").await, "/admin/password");
+    //Act - Part 3 - Change password
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &app.test_user.password,
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+    //Assert
+    assert_eq!(app.get_admin_dashboard().await.status().as_u16(), 200);
+}
@@ -0,0 +1,310 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, BatchAccepted, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+//one short of the limit, so the next failure is the last one
+async fn exhaust_retries(app: &TestApp) {
+    sqlx::query!("UPDATE issue_delivery_queue SET attempts = 4, next_attempt_at = now()")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE outbox SET attempts = 4, next_attempt_at = now() WHERE sent_at IS NULL")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+}
+
+//leaves a single issue delivery in the dead-letter queue
+async fn dead_letter_an_issue(app: &TestApp) -> String {
+    app.create_confirmed_subscriber().await;
+    let _mock_guard = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.mock_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    exhaust_retries(app).await;
+    app.dispatch_all_pending_emails().await;
+    let dead_letter = sqlx::query!("SELECT dead_letter_id FROM dead_letters")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    dead_letter.dead_letter_id.to_string()
+}
+
+#[tokio::test]
+async fn an_issue_delivery_that_exhausts_its_retries_is_dead_lettered() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    dead_letter_an_issue(&app).await;
+    //Assert
+    let dead_letter = sqlx::query!("SELECT source, recipient, attempts, last_error FROM dead_letters")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(dead_letter.source, "newsletter_issue");
+    assert_eq!(dead_letter.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(dead_letter.attempts, 5);
+    assert!(!dead_letter.last_error.is_empty());
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
+
+#[tokio::test]
+async fn a_confirmation_email_that_exhausts_its_retries_is_dead_lettered() {
+    //Arrange
+    let app = spawn_app().await;
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.mock_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    //Act
+    exhaust_retries(&app).await;
+    app.dispatch_outbox().await;
+    //Assert
+    let dead_letter = sqlx::query!("SELECT source, payload FROM dead_letters")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(dead_letter.source, "outbox");
+    assert_eq!(dead_letter.payload["kind"], "subscription_confirmation");
+    assert_eq!(dead_letter.payload["subject"], "WELCOME");
+    let pending = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM outbox"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(pending.count, 0);
+}
+
+#[tokio::test]
+async fn a_confirmation_email_to_a_rejected_recipient_is_dead_lettered_right_away() {
+    //Arrange
+    let app = spawn_app().await;
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount_as_scoped(&app.mock_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    //Act
+    app.dispatch_outbox().await;
+    //Assert
+    let dead_letter = sqlx::query!("SELECT source, recipient, attempts, last_error FROM dead_letters")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(dead_letter.source, "outbox");
+    assert_eq!(dead_letter.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(dead_letter.attempts, 1);
+    assert!(!dead_letter.last_error.is_empty());
+    let pending = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM outbox"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(pending.count, 0);
+}
+
+#[tokio::test]
+async fn an_issue_delivery_to_a_rejected_recipient_is_dead_lettered_right_away() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    //a batch answers 200 and reports the rejection per message
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        }])))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    //Act
+    app.dispatch_all_pending_emails().await;
+    //Assert
+    let dead_letter = sqlx::query!("SELECT source, recipient, attempts, last_error FROM dead_letters")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(dead_letter.source, "newsletter_issue");
+    assert_eq!(dead_letter.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(dead_letter.attempts, 1);
+    assert!(dead_letter.last_error.contains("406"));
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_dead_letters() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = app.get_dead_letters().await;
+    //Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn admins_can_list_and_inspect_dead_letters() {
+    //Arrange
+    let app = spawn_app().await;
+    let dead_letter_id = dead_letter_an_issue(&app).await;
+    app.login_test_user().await;
+    //Act
+    let list: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    let inspected: serde_json::Value = app
+        .get_dead_letter(&dead_letter_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    //Assert
+    assert_eq!(list.as_array().unwrap().len(), 1);
+    assert_eq!(list[0]["dead_letter_id"], dead_letter_id.as_str());
+    assert_eq!(list[0]["attempts"], 5);
+    assert!(list[0].get("payload").is_none());
+    assert_eq!(inspected["source"], "newsletter_issue");
+    assert!(inspected["payload"]["newsletter_issue_id"].is_string());
+}
+
+#[tokio::test]
+async fn a_requeued_dead_letter_is_delivered_again() {
+    //Arrange
+    let app = spawn_app().await;
+    let dead_letter_id = dead_letter_an_issue(&app).await;
+    app.login_test_user().await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app.post_requeue_dead_letter(&dead_letter_id).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    let response = app.get_dead_letter(&dead_letter_id).await;
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn requeueing_an_issue_skips_subscribers_who_are_no_longer_confirmed() {
+    //Arrange
+    let app = spawn_app().await;
+    let dead_letter_id = dead_letter_an_issue(&app).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    app.login_test_user().await;
+    //Act
+    let response = app.post_requeue_dead_letter(&dead_letter_id).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+    let response = app.get_dead_letter(&dead_letter_id).await;
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn requeueing_an_outbox_email_skips_suppressed_recipients() {
+    //Arrange
+    let app = spawn_app().await;
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.mock_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    exhaust_retries(&app).await;
+    app.dispatch_outbox().await;
+    let dead_letter_id = sqlx::query!("SELECT dead_letter_id FROM dead_letters")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .dead_letter_id
+        .to_string();
+    sqlx::query!(
+        "INSERT INTO suppressed_emails(email, reason, suppressed_at) VALUES('ursula_le_guin@gmail.com', 'hard_bounce', now())"
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    app.login_test_user().await;
+    //Act
+    let response = app.post_requeue_dead_letter(&dead_letter_id).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let pending = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM outbox WHERE sent_at IS NULL"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(pending.count, 0);
+    let response = app.get_dead_letter(&dead_letter_id).await;
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn a_discarded_dead_letter_is_gone() {
+    //Arrange
+    let app = spawn_app().await;
+    let dead_letter_id = dead_letter_an_issue(&app).await;
+    app.login_test_user().await;
+    //Act
+    let response = app.delete_dead_letter(&dead_letter_id).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 204);
+    let response = app.delete_dead_letter(&dead_letter_id).await;
+    assert_eq!(response.status().as_u16(), 404);
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
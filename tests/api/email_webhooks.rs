@@ -0,0 +1,240 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const SUBSCRIBER_EMAIL: &str = "ursula_le_guin@gmail.com";
+
+fn hard_bounce(email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "HardBounce",
+        "TypeCode": 1,
+        "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+        "Email": email,
+        "Description": "The server was unable to deliver your message",
+    })
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .expect("failed to fetch the subscriber")
+        .status
+}
+
+async fn suppression_reason(app: &TestApp, email: &str) -> Option<String> {
+    sqlx::query!(
+        "SELECT reason FROM suppressed_emails WHERE email = $1",
+        email
+    )
+    .fetch_optional(&app.pool_conn)
+    .await
+    .expect("failed to query the suppression list")
+    .map(|r| r.reason)
+}
+
+#[tokio::test]
+async fn email_events_without_the_right_secret_are_rejected_with_a_401() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = app
+        .post_email_event_with_secret(&hard_bounce(SUBSCRIBER_EMAIL), "not-the-secret")
+        .await;
+    let missing = reqwest::Client::new()
+        .post(format!("{}/webhooks/email-events", app.address))
+        .json(&hard_bounce(SUBSCRIBER_EMAIL))
+        .send()
+        .await
+        .unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(missing.status().as_u16(), 401);
+    assert_eq!(suppression_reason(&app, SUBSCRIBER_EMAIL).await, None);
+}
+
+#[tokio::test]
+async fn email_events_without_a_record_type_are_rejected_with_a_400() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = app
+        .post_email_event(&serde_json::json!({"Email": SUBSCRIBER_EMAIL}))
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_hard_bounce_suppresses_the_address_and_stops_newsletters() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    //Act
+    let response = app.post_email_event(&hard_bounce(SUBSCRIBER_EMAIL)).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+    assert_eq!(
+        suppression_reason(&app, SUBSCRIBER_EMAIL).await.as_deref(),
+        Some("hard_bounce")
+    );
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn a_spam_complaint_suppresses_the_address() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    //Act
+    let response = app
+        .post_email_event(&serde_json::json!({
+            "RecordType": "SpamComplaint",
+            "Type": "SpamComplaint",
+            "MessageID": "00000000-0000-0000-0000-000000000000",
+            "Email": "Ursula_Le_Guin@gmail.com",
+        }))
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+    assert_eq!(
+        suppression_reason(&app, SUBSCRIBER_EMAIL).await.as_deref(),
+        Some("spam_complaint")
+    );
+}
+
+#[tokio::test]
+async fn soft_bounces_and_deliveries_are_recorded_but_do_not_suppress() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    //Act
+    for event in [
+        serde_json::json!({
+            "RecordType": "Bounce",
+            "Type": "SoftBounce",
+            "Email": SUBSCRIBER_EMAIL,
+        }),
+        serde_json::json!({
+            "RecordType": "Delivery",
+            "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+            "Recipient": SUBSCRIBER_EMAIL,
+        }),
+    ] {
+        let response = app.post_email_event(&event).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    //Assert
+    let events = sqlx::query!("SELECT record_type, email FROM email_events ORDER BY received_at")
+        .fetch_all(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(events
+        .iter()
+        .all(|e| e.email.as_deref() == Some(SUBSCRIBER_EMAIL)));
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    assert_eq!(suppression_reason(&app, SUBSCRIBER_EMAIL).await, None);
+}
+
+#[tokio::test]
+async fn subscribing_with_a_suppressed_address_does_not_send_an_email() {
+    //Arrange
+    let app = spawn_app().await;
+    app.post_email_event(&hard_bounce(SUBSCRIBER_EMAIL)).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_outbox().await;
+}
+
+#[tokio::test]
+async fn an_email_enqueued_before_the_address_was_suppressed_is_not_sent() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.post_email_event(&hard_bounce("Ursula_Le_Guin@gmail.com")).await;
+    //Act
+    app.dispatch_outbox().await;
+    //Assert
+    let pending = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM outbox"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(pending.count, 0);
+}
+
+#[tokio::test]
+async fn a_bounced_subscriber_cannot_be_confirmed() {
+    //Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    app.post_email_event(&hard_bounce(SUBSCRIBER_EMAIL)).await;
+    //Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 409);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn unsubscribing_a_bounced_subscriber_succeeds_and_keeps_them_bounced() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    app.post_email_event(&hard_bounce(SUBSCRIBER_EMAIL)).await;
+    let token = sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .unsubscribe_token;
+    let url = format!("{}/subscriptions/unsubscribe?token={}", app.address, token);
+    //Act
+    let link = reqwest::get(&url).await.unwrap();
+    let one_click = reqwest::Client::new()
+        .post(&url)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap();
+    //Assert
+    assert_eq!(link.status().as_u16(), 200);
+    assert_eq!(one_click.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
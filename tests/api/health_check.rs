@@ -18,6 +18,8 @@ async fn test_health_api() -> Result<(), std::io::Error> {
         .expect("Failed to execute request");
 
     assert!(response.status().is_success());
-    assert_eq!(Some(0), response.content_length());
+    let report: serde_json::Value = response.json().await.expect("health report is json");
+    assert_eq!(report["status"], "ok");
+    assert_eq!(report["email_circuit"], "closed");
     Ok(())
 }
\ No newline at end of file
@@ -1,8 +1,15 @@
 use linkify::LinkFinder;
 use reqwest::Url;
+use secrecy::{ExposeSecret, Secret};
+use std::sync::Arc;
+use uuid::Uuid;
 use zero2prod::{
-    configuration::{get_configuration, DatabaseSettings},
-    email_client::EmailClient,
+    authentication::compute_password_hash,
+    configuration::{get_configuration, DatabaseSettings, EmailTransportKind, Settings},
+    email_templates::EmailTemplates,
+    email_transport::EmailTransport,
+    issue_delivery_worker::{try_execute_task, ExecutionOutcome},
+    outbox::try_dispatch_email,
     startup::get_pool_conn,
     telemetry::{get_subscriber, init_global_logger},
 };
@@ -10,10 +17,25 @@ use zero2prod::{
 
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
-use std::net::TcpListener;
 
-use wiremock::{matchers::header, Match, MockServer};
-pub const LOCAL_HOST_WITH_RANDOM_PORT: &str = "127.0.0.1";
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+//answers a Postmark /email/batch call with an Ok for every message in it
+pub struct BatchAccepted;
+impl wiremock::Respond for BatchAccepted {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let messages: Vec<serde_json::Value> =
+            serde_json::from_slice(&request.body).expect("a batch is a json array");
+        let results: Vec<_> = messages
+            .iter()
+            .map(|_| serde_json::json!({"ErrorCode": 0, "Message": "OK", "MessageID": Uuid::new_v4()}))
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+}
 
 /// Confirmation links embedded in the request to the email API.
 pub struct ConfirmationLinks {
@@ -26,21 +48,285 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info";
     //attached to each test
     let subscriber_name = "test";
-    if let Ok(_) = std::env::var("TEST_LOG") {
-        let subscriber = get_subscriber(&default_filter_level, &subscriber_name, std::io::stdout);
+    if std::env::var("TEST_LOG").is_ok() {
+        let subscriber = get_subscriber(default_filter_level, subscriber_name, std::io::stdout);
         init_global_logger(subscriber);
     } else {
-        let subscriber = get_subscriber(&default_filter_level, &subscriber_name, std::io::sink);
+        let subscriber = get_subscriber(default_filter_level, subscriber_name, std::io::sink);
         init_global_logger(subscriber);
     };
 });
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+    async fn store(&self, pool: &PgPool) {
+        let password_hash = compute_password_hash(Secret::new(self.password.clone()))
+            .expect("failed to hash the test user password");
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash.expose_secret(),
+        )
+        .execute(pool)
+        .await
+        .expect("failed to store test user");
+    }
+}
 pub struct TestApp {
     pub address: String,
     pub pool_conn: PgPool,
     pub mock_server: MockServer,
     pub port_num: u16,
+    pub email_client: Arc<dyn EmailTransport>,
+    pub email_templates: EmailTemplates,
+    pub base_url: String,
+    pub webhook_secret: String,
+    pub test_user: TestUser,
+    //keeps cookies between calls and does not follow redirects,
+    //so tests can assert on the redirect itself
+    pub api_client: reqwest::Client,
 }
 impl TestApp {
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_change_password(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/password", self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+    pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/password", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_dead_letters(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dead-letters", self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_dead_letter(&self, dead_letter_id: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dead-letters/{}", self.address, dead_letter_id))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn post_requeue_dead_letter(&self, dead_letter_id: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/dead-letters/{}/requeue",
+                self.address, dead_letter_id
+            ))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn delete_dead_letter(&self, dead_letter_id: &str) -> reqwest::Response {
+        self.api_client
+            .delete(format!("{}/admin/dead-letters/{}", self.address, dead_letter_id))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_subscribers(&self, query: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/subscribers?{}", self.address, query))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_subscriber(&self, subscriber_id: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/subscribers/{}", self.address, subscriber_id))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn patch_subscriber(
+        &self,
+        subscriber_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        self.api_client
+            .patch(format!("{}/admin/subscribers/{}", self.address, subscriber_id))
+            .json(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn post_subscribers_import(&self, mode: &str, csv: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/subscribers/import?mode={}", self.address, mode))
+            .header("Content-Type", "text/csv")
+            .body(csv.to_owned())
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_subscribers_export(&self, query: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/subscribers/export?{}", self.address, query))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn delete_subscriber(&self, subscriber_id: &str) -> reqwest::Response {
+        self.api_client
+            .delete(format!("{}/admin/subscribers/{}", self.address, subscriber_id))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn post_password_reset_request(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/password-reset", self.address))
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn get_password_reset_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/password-reset", self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+    pub async fn post_password_reset(&self, token: &str, new_password: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/password-reset/confirm", self.address))
+            .form(&serde_json::json!({
+                "token": token,
+                "new_password": new_password,
+                "new_password_check": new_password,
+            }))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn login_test_user(&self) {
+        let response = self
+            .post_login(&serde_json::json!({
+                "username": &self.test_user.username,
+                "password": &self.test_user.password
+            }))
+            .await;
+        assert_is_redirect_to(&response, "/admin/dashboard");
+    }
+    //the app runs its own delivery worker in the background, so a task may be
+    //locked by it when we look, keep going until nothing is due anymore
+    pub async fn dispatch_all_pending_emails(&self) {
+        self.dispatch_outbox().await;
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(
+                    &self.pool_conn,
+                    self.email_client.as_ref(),
+                    &self.email_templates,
+                    &self.base_url,
+                )
+                .await
+                .unwrap()
+            {
+                let due = sqlx::query!(
+                    "SELECT COUNT(*) AS \"due!\" FROM issue_delivery_queue WHERE next_attempt_at <= now()"
+                )
+                .fetch_one(&self.pool_conn)
+                .await
+                .unwrap()
+                .due;
+                if due == 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+    }
+    //same idea for the outbox, the app's own dispatcher may hold a row
+    pub async fn dispatch_outbox(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_dispatch_email(&self.pool_conn, self.email_client.as_ref())
+                    .await
+                    .unwrap()
+            {
+                let due = sqlx::query!(
+                    "SELECT COUNT(*) AS \"due!\" FROM outbox WHERE sent_at IS NULL AND next_attempt_at <= now()"
+                )
+                .fetch_one(&self.pool_conn)
+                .await
+                .unwrap()
+                .due;
+                if due == 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+    }
     pub async fn post_subscriptions(&self, test_body: String) -> reqwest::Response {
         let resp = reqwest::Client::new()
             .post(&dbg!(format!("{}/subscriptions", self.address)))
@@ -51,30 +337,116 @@ impl TestApp {
             .expect("failed to execute request");
         resp
     }
+    pub async fn post_subscriptions_with_idempotency_key(
+        &self,
+        test_body: String,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Idempotency-Key", idempotency_key)
+            .body(test_body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: &serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn post_email_event(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.post_email_event_with_secret(body, &self.webhook_secret)
+            .await
+    }
+    pub async fn post_email_event_with_secret(
+        &self,
+        body: &serde_json::Value,
+        secret: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/email-events", self.address))
+            .header("X-Webhook-Secret", secret)
+            .json(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+    //uses the public api so tests do not couple to the schema
+    pub async fn create_unconfirmed_subscriber(&self) -> ConfirmationLinks {
+        let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+        //scoped mock, only lives until the guard is dropped at the end of this fn
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .named("Create unconfirmed subscriber")
+            .expect(1)
+            .mount_as_scoped(&self.mock_server)
+            .await;
+        self.post_subscriptions(body.into())
+            .await
+            .error_for_status()
+            .unwrap();
+        self.dispatch_outbox().await;
+        let email_request = &self
+            .mock_server
+            .received_requests()
+            .await
+            .expect("failed to get received requests")
+            .pop()
+            .unwrap();
+        self.get_confirmation_links(email_request)
+    }
+    pub async fn create_confirmed_subscriber(&self) {
+        let confirmation_links = self.create_unconfirmed_subscriber().await;
+        reqwest::get(confirmation_links.html)
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+    // Extract the only link in an email body, pointed at the test server.
+    pub fn get_link(&self, s: &str) -> Url {
+        let find_link = LinkFinder::new();
+        let links: Vec<_> = find_link
+            .links(s)
+            .filter(|link_item| *link_item.kind() == linkify::LinkKind::Url)
+            .collect();
+        assert!(links.len() == 1);
+        let raw_link = links[0].as_str().to_owned();
+
+        let mut link = Url::parse(&raw_link).expect("failed to parse url");
+        // Let's make sure we don't call random APIs on the web
+        assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+        // Let's rewrite the URL to include the port
+        link.set_port(Some(self.port_num))
+            .expect("failed to set port");
+        link
+    }
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body)
             .expect("deserialization of req body failed");
-        // Extract the link from one of the request fields.
-        let get_link = |s: &str| {
-            let find_link = LinkFinder::new();
-            let links: Vec<_> = find_link
-                .links(s)
-                .filter(|link_item| *link_item.kind() == linkify::LinkKind::Url)
-                .collect();
-            assert!(links.len() == 1);
-            let raw_link = links[0].as_str().to_owned();
-
-            let mut confirmation_link = Url::parse(&raw_link).expect("failed to parse url");
-            // Let's make sure we don't call random APIs on the web
-            assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
-            // Let's rewrite the URL to include the port
-            confirmation_link
-                .set_port(Some(self.port_num))
-                .expect("failed to set port");
-            confirmation_link
-        };
-        let html = get_link(&body["HtmlBody"].as_str().expect("failed to deserialize"));
-        let plain_text = get_link(&body["TextBody"].as_str().expect("failed to deserialize"));
+        let html = self.get_link(body["HtmlBody"].as_str().expect("failed to deserialize"));
+        let plain_text = self.get_link(body["TextBody"].as_str().expect("failed to deserialize"));
         ConfirmationLinks{
             html,
             plain_text
@@ -101,6 +473,10 @@ fn dummy_fail() {
 //spawns the server on a background thread, so that server runs in parallel to the client handler thread
 // The function is asynchronous now!
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+//for tests that need settings the rest of the suite should not see
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     //evaluate the tracing function
     //1st way
     // *TRACING;
@@ -124,7 +500,15 @@ pub async fn spawn_app() -> TestApp {
         c.db_settings.database_name = uuid::Uuid::new_v4().to_string();
         //use a random os port
         c.application.port = 0;
+        //talk to the mock server whatever the local transport is
+        c.email_client.transport = EmailTransportKind::Postmark;
         c.email_client.base_url = email_server.uri();
+        //the mocks count requests, retries are covered by the email_client unit tests
+        c.email_client.retry.max_attempts = 1;
+        //tests hammer the form on purpose, the rate limit tests lower these again
+        c.rate_limit.subscribe_burst = 1000;
+        c.rate_limit.confirmation_emails_per_address_per_day = 1000;
+        configure(&mut c);
         c
     };
     //build does this onw
@@ -154,18 +538,34 @@ pub async fn spawn_app() -> TestApp {
     //spawning server on another future
     //so as to not block the main future as server future will never return
     let port_num = server.port();
-    let _ = tokio::spawn(server.run_until_stopped());
+    tokio::spawn(server.run_until_stopped());
     // let listner =
     //     TcpListener::bind(format!("{LOCAL_HOST_WITH_RANDOM_PORT}:0")).expect("bind failed");
     // let port_num = listner.local_addr().expect("socket addr failed").port();
     let address = format!("http://localhost:{}", port_num);
 
+    let test_user = TestUser::generate();
+    test_user.store(&get_pool_conn(&settings.db_settings)).await;
     //adding mock server
     TestApp {
-        address: address,
+        address,
         pool_conn: get_pool_conn(&settings.db_settings),
         mock_server: email_server,
         port_num,
+        email_client: settings.email_client.clone().transport(),
+        email_templates: settings.email_templates.templates().unwrap(),
+        base_url: settings.application.base_url.clone(),
+        webhook_secret: settings
+            .email_client
+            .webhook_secret
+            .expose_secret()
+            .to_owned(),
+        test_user,
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap(),
     }
 }
 //process : create a random db name -> connect to an instance and create a database with the random name
@@ -195,3 +595,8 @@ pub async fn configure_test_db(db_s: &DatabaseSettings) -> PgPool {
 
     pg_pool_conn
 }
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
@@ -0,0 +1,61 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+
+#[tokio::test]
+async fn an_error_flash_message_is_set_on_failure() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act - Part 1 - Try to login
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    });
+    let response = app.post_login(&login_body).await;
+    //Assert
+    assert_is_redirect_to(&response, "/login");
+    //Act - Part 2 - Follow the redirect
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("<p><i>Authentication failed</i></p>"));
+    //Act - Part 3 - Reload the login page
+    let html_page = app.get_login_html().await;
+    assert!(!html_page.contains("Authentication failed"));
+}
+
+#[tokio::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act - Part 1 - Login
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+    //Act - Part 2 - Follow the redirect
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn sessions_are_stored_in_postgres_and_rotated_on_login() {
+    //Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    let first_session_key = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .expect("the session should be persisted")
+        .session_key;
+    //Act - login again with the same cookie jar
+    app.login_test_user().await;
+    //Assert - the old key is gone, a fresh one replaced it
+    let session_keys: Vec<_> = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_all(&app.pool_conn)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.session_key)
+        .collect();
+    assert_eq!(session_keys.len(), 1);
+    assert_ne!(session_keys[0], first_session_key);
+}
@@ -3,6 +3,19 @@
 //by having a single crate you skip this cost  as only a single crate is built with all of the tests
 
 mod helpers;
+mod admin_dashboard;
+mod admin_subscribers;
+mod admin_subscribers_csv;
+mod bot_protection;
+mod change_password;
+mod dead_letters;
+mod email_webhooks;
 mod health_check;
+mod login;
+mod newsletters;
+mod password_reset;
+mod rate_limit;
 mod subscriptions;
-mod subscriptions_confirm;
\ No newline at end of file
+mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
\ No newline at end of file
@@ -0,0 +1,482 @@
+use crate::helpers::{spawn_app, BatchAccepted};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    //no request should reach the email server
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_with_invalid_stored_emails_are_skipped() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    //bypass the domain validation by writing straight to the table
+    sqlx::query!(
+        r#"INSERT INTO subscriptions(id,email,name,subscribed_at,status,unsubscribe_token)
+        VALUES($1,'definitely-not-an-email','invalid',now(),'confirmed','invalidsubscribertoken')"#,
+        uuid::Uuid::new_v4()
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    //only the valid subscriber gets the issue
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn newsletters_are_sent_in_batches() {
+    //Arrange
+    let app = spawn_app().await;
+    //bypass the signup flow, a thousand confirmation emails would drown the test
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions(id, email, name, subscribed_at, status, unsubscribe_token)
+        SELECT md5(i::text)::uuid, 'ursula' || i || '@gmail.com', 'le guin', now(), 'confirmed', md5(i::text)
+        FROM generate_series(1, 1001) AS i
+        "#
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    //ceil(1001 / 500)
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(3)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    let requests = app.mock_server.received_requests().await.unwrap();
+    let delivered: usize = requests
+        .iter()
+        .map(|request| {
+            serde_json::from_slice::<Vec<serde_json::Value>>(&request.body)
+                .unwrap()
+                .len()
+        })
+        .sum();
+    assert_eq!(delivered, 1001);
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+    ];
+    for (invalid_body, error_message) in test_cases {
+        //Act
+        let response = app.post_newsletters(invalid_body).await;
+        //Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_rescheduled_with_a_backoff() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    //Act
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    //Assert
+    let task = sqlx::query!(
+        r#"SELECT attempts, next_attempt_at > now() AS "in_the_future!" FROM issue_delivery_queue"#
+    )
+    .fetch_one(&app.pool_conn)
+    .await
+    .expect("the failed task should still be queued");
+    assert_eq!(task.attempts, 1);
+    assert!(task.in_the_future);
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    //Act - publish, then retry the same request
+    let response = app
+        .post_newsletters_with_idempotency_key(&newsletter_request_body, &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let response = app
+        .post_newsletters_with_idempotency_key(&newsletter_request_body, &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    //Assert
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we have sent the newsletter email **once**
+}
+
+#[tokio::test]
+async fn reusing_an_idempotency_key_for_another_issue_is_rejected_with_a_422() {
+    //Arrange
+    let app = spawn_app().await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    let issue = |title: &str| {
+        serde_json::json!({
+            "title": title,
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        })
+    };
+    let response = app
+        .post_newsletters_with_idempotency_key(&issue("First issue"), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    //Act
+    let response = app
+        .post_newsletters_with_idempotency_key(&issue("Second issue"), &idempotency_key)
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 422);
+    let issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 1);
+}
+
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    //Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn users_who_must_change_their_password_cannot_publish() {
+    //Arrange
+    let app = spawn_app().await;
+    sqlx::query!(
+        "UPDATE users SET must_change_password = true WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    //Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    //Assert
+    assert_eq!(401, response.status().as_u16());
+    let issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 0);
+}
+
+#[tokio::test]
+async fn non_existing_user_is_rejected() {
+    //Arrange
+    let app = spawn_app().await;
+    //random credentials
+    let username = uuid::Uuid::new_v4().to_string();
+    let password = uuid::Uuid::new_v4().to_string();
+    //Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    //Assert
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn invalid_password_is_rejected() {
+    //Arrange
+    let app = spawn_app().await;
+    let username = &app.test_user.username;
+    //random password
+    let password = uuid::Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+    //Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    //Assert
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn deliveries_rejected_for_the_recipient_are_not_retried() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    //a batch answers 200 and reports the rejection per message
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        }])))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    //Act
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    //Assert
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
+
+#[tokio::test]
+async fn merge_tags_are_personalized_for_each_subscriber() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    sqlx::query!(r#"UPDATE subscriptions SET custom_fields = '{"company": "ACME & Co"}'"#)
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Hi {{ name|there }} from {{ custom.company }}, {{ custom.role|reader }}",
+                "html": "<p>Hi {{ name|there }} from {{ custom.company }}</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    //Assert
+    let email_request = app
+        .mock_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let body = &body[0];
+    assert!(body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("<p>Hi le guin from ACME &amp; Co</p>"));
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("Hi le guin from ACME & Co, reader"));
+}
+
+#[tokio::test]
+async fn issues_with_unknown_merge_tags_are_rejected() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Hi {{ first_name }}",
+                "html": "<p>Hi {{ name }}</p>",
+            }
+        }))
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let queued = sqlx::query!("SELECT COUNT(*) AS \"queued!\" FROM issue_delivery_queue")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .queued;
+    assert_eq!(queued, 0);
+}
@@ -0,0 +1,154 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const TEST_USER_EMAIL: &str = "admin@example.com";
+
+async fn give_test_user_an_email(app: &TestApp) {
+    sqlx::query!(
+        "UPDATE users SET email = $1 WHERE user_id = $2",
+        TEST_USER_EMAIL,
+        app.test_user.user_id
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+}
+
+//requests a reset and returns the token from the emailed link
+async fn request_reset_token(app: &TestApp) -> String {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Send password reset email")
+        .expect(1)
+        .mount_as_scoped(&app.mock_server)
+        .await;
+    let response = app.post_password_reset_request(TEST_USER_EMAIL).await;
+    assert_is_redirect_to(&response, "/password-reset");
+    app.dispatch_outbox().await;
+    let email_request = app
+        .mock_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let link = app.get_link(body["TextBody"].as_str().unwrap());
+    link.query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .expect("the reset link carries a token")
+}
+
+#[tokio::test]
+async fn unknown_emails_get_the_same_answer_and_no_email() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app.post_password_reset_request("nobody@example.com").await;
+    //Assert
+    assert_is_redirect_to(&response, "/password-reset");
+    let html_page = app.get_password_reset_html().await;
+    assert!(html_page.contains("If an account with that email exists"));
+}
+
+#[tokio::test]
+async fn known_emails_get_the_same_answer_while_the_email_server_is_down() {
+    //Arrange
+    let app = spawn_app().await;
+    give_test_user_an_email(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app.post_password_reset_request(TEST_USER_EMAIL).await;
+    //Assert
+    assert_is_redirect_to(&response, "/password-reset");
+    let html_page = app.get_password_reset_html().await;
+    assert!(html_page.contains("If an account with that email exists"));
+    //sent once the email server is back
+    let queued = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM outbox WHERE kind = 'password_reset' AND sent_at IS NULL"#
+    )
+    .fetch_one(&app.pool_conn)
+    .await
+    .unwrap();
+    assert_eq!(queued.count, 1);
+}
+
+#[tokio::test]
+async fn the_emailed_link_resets_the_password() {
+    //Arrange
+    let app = spawn_app().await;
+    give_test_user_an_email(&app).await;
+    let token = request_reset_token(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+    //Act
+    let response = app.post_password_reset(&token, &new_password).await;
+    //Assert
+    assert_is_redirect_to(&response, "/login");
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &new_password
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn reset_tokens_are_single_use() {
+    //Arrange
+    let app = spawn_app().await;
+    give_test_user_an_email(&app).await;
+    let token = request_reset_token(&app).await;
+    let response = app
+        .post_password_reset(&token, &Uuid::new_v4().to_string())
+        .await;
+    assert_is_redirect_to(&response, "/login");
+    //Act - replay the same link
+    let response = app
+        .post_password_reset(&token, &Uuid::new_v4().to_string())
+        .await;
+    //Assert
+    assert_is_redirect_to(&response, "/password-reset");
+    let html_page = app.get_password_reset_html().await;
+    assert!(html_page.contains("The password reset link is invalid or has expired."));
+}
+
+#[tokio::test]
+async fn expired_reset_tokens_are_rejected() {
+    //Arrange
+    let app = spawn_app().await;
+    give_test_user_an_email(&app).await;
+    let token = request_reset_token(&app).await;
+    sqlx::query!(
+        "UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'"
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    //Act
+    let response = app
+        .post_password_reset(&token, &Uuid::new_v4().to_string())
+        .await;
+    //Assert
+    assert_is_redirect_to(&response, "/password-reset");
+    //the old password still works
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &app.test_user.password
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+}
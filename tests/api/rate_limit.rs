@@ -0,0 +1,206 @@
+use crate::helpers::spawn_app_with;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn subscribe_is_limited_per_client_ip() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.subscribe_burst = 2;
+        c.rate_limit.subscribe_per_minute = 1;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_server)
+        .await;
+    for i in 0..2 {
+        let body = format!("name=le%20guin&email=ursula{}%40gmail.com", i);
+        assert_eq!(app.post_subscriptions(body).await.status().as_u16(), 200);
+    }
+    //Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula2%40gmail.com".into())
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after));
+}
+
+#[tokio::test]
+async fn forwarded_for_is_ignored_unless_the_peer_is_a_trusted_proxy() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.subscribe_burst = 1;
+        c.rate_limit.subscribe_per_minute = 1;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_server)
+        .await;
+    let post = |ip: &'static str, email: &'static str| {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", app.address))
+            .header("X-Forwarded-For", ip)
+            .form(&[("name", "le guin"), ("email", email)])
+            .send()
+    };
+    //Act
+    let first = post("203.0.113.1", "ursula0@gmail.com").await.unwrap();
+    //a spoofed header does not buy a fresh bucket
+    let second = post("203.0.113.2", "ursula1@gmail.com").await.unwrap();
+    //Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn clients_behind_a_trusted_proxy_are_limited_separately() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.subscribe_burst = 1;
+        c.rate_limit.subscribe_per_minute = 1;
+        c.rate_limit.trusted_proxies = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_server)
+        .await;
+    let post = |ip: &'static str, email: &'static str| {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", app.address))
+            .header("X-Forwarded-For", ip)
+            .form(&[("name", "le guin"), ("email", email)])
+            .send()
+    };
+    //Act
+    let first = post("203.0.113.1", "ursula0@gmail.com").await.unwrap();
+    let other_client = post("203.0.113.2", "ursula1@gmail.com").await.unwrap();
+    let first_again = post("203.0.113.1", "ursula2@gmail.com").await.unwrap();
+    //Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(other_client.status().as_u16(), 200);
+    assert_eq!(first_again.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn confirmation_emails_are_limited_per_address() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.confirmation_emails_per_address_per_day = 2;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        //two for ursula, one for someone_else
+        .expect(3)
+        .mount(&app.mock_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    for _ in 0..2 {
+        assert_eq!(app.post_subscriptions(body.into()).await.status().as_u16(), 200);
+    }
+    //Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=URSULA_LE_GUIN%40gmail.com".into())
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().contains_key("Retry-After"));
+    //another address is not affected
+    let response = app
+        .post_subscriptions("name=le%20guin&email=someone_else%40gmail.com".into())
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_outbox().await;
+}
+
+#[tokio::test]
+async fn the_postgres_backend_enforces_the_same_limits() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.backend = zero2prod::configuration::RateLimitBackend::Postgres;
+        c.rate_limit.subscribe_burst = 1;
+        c.rate_limit.subscribe_per_minute = 1;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let first = app
+        .post_subscriptions("name=le%20guin&email=ursula0%40gmail.com".into())
+        .await;
+    let second = app
+        .post_subscriptions("name=le%20guin&email=ursula1%40gmail.com".into())
+        .await;
+    //Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+    let buckets = sqlx::query!("SELECT COUNT(*) AS \"buckets!\" FROM rate_limit_buckets")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .buckets;
+    assert!(buckets >= 1);
+}
+
+#[tokio::test]
+async fn password_reset_is_limited_per_client_ip() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.password_reset_burst = 1;
+        c.rate_limit.password_reset_per_minute = 1;
+    })
+    .await;
+    assert_eq!(
+        app.post_password_reset_request("nobody@example.com").await.status().as_u16(),
+        303
+    );
+    //Act
+    let response = app.post_password_reset_request("nobody@example.com").await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn password_reset_emails_are_limited_per_address_without_saying_so() {
+    //Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.password_reset_emails_per_address_per_day = 1;
+    })
+    .await;
+    sqlx::query!(
+        "UPDATE users SET email = 'admin@example.com' WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let first = app.post_password_reset_request("admin@example.com").await;
+    let second = app.post_password_reset_request("admin@example.com").await;
+    app.dispatch_outbox().await;
+    //Assert
+    assert_eq!(first.status().as_u16(), 303);
+    assert_eq!(second.status().as_u16(), 303);
+}
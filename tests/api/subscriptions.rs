@@ -1,8 +1,7 @@
 use crate::helpers::spawn_app;
-use linkify::LinkFinder;
 use wiremock::{
-    matchers::{header, method, path},
-    Match, Mock, MockServer, ResponseTemplate,
+    matchers::{method, path},
+    Mock, ResponseTemplate,
 };
 
 #[tokio::test]
@@ -39,16 +38,16 @@ async fn subscribe_sends_a_valid_link() {
         //not adding expectation any more
         .mount(&app.mock_server)
         .await;
-    let client = reqwest::Client::new();
     let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
     //ACT
     let response = app.post_subscriptions(test_body.into()).await;
+    app.dispatch_outbox().await;
     let email_request = &app
         .mock_server
         .received_requests()
         .await
         .expect("failed to get received requests")[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 
     assert_eq!(200, response.status().as_u16());
@@ -64,7 +63,6 @@ async fn subscribe_sends_a_valid_link() {
 // #[should_panic]
 async fn subscribe_returns_a_200_when_fields_are_present_but_empty() {
     let app = spawn_app().await;
-    let client = reqwest::Client::new();
     let test_cases = vec![
         ("name=&email=ursula_le_guin%40gmail.com", "empty name"),
         ("name=Ursula&email=", "empty email"),
@@ -107,10 +105,10 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
         .expect(1)
         .mount(&app.mock_server)
         .await;
-    let client = reqwest::Client::new();
     let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
     let response = app.post_subscriptions(test_body.into()).await;
     assert_eq!(200, response.status().as_u16());
+    app.dispatch_outbox().await;
 }
 #[tokio::test]
 async fn subscribe_check_persistence() {
@@ -130,10 +128,10 @@ async fn subscribe_check_persistence() {
         .expect(1)
         .mount(&app.mock_server)
         .await;
-    let client = reqwest::Client::new();
     let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
     let response = app.post_subscriptions(test_body.into()).await;
     assert_eq!(200, response.status().as_u16());
+    app.dispatch_outbox().await;
     let saved = sqlx::query!("SELECT email, name,status FROM subscriptions",)
         .fetch_one(&app.pool_conn)
         .await
@@ -149,7 +147,6 @@ async fn test_html_form_subscribe_data_api_200_response() -> Result<(), std::io:
     //% encoded for non-alphanumeric, urlencoded encoding algorithm used to encode html data
     //no need for {}
     let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
-    let client = reqwest::Client::new();
     //mock settings to mount on top of a wiremock server
 
     Mock::given(path("/email"))
@@ -160,6 +157,7 @@ async fn test_html_form_subscribe_data_api_200_response() -> Result<(), std::io:
         .await;
     let response = app.post_subscriptions(test_body.into()).await;
     assert_eq!(200, response.status().as_u16());
+    app.dispatch_outbox().await;
     Ok(())
 }
 #[tokio::test]
@@ -184,3 +182,412 @@ async fn test_html_form_subscribe_data_api_400_response() -> Result<(), std::io:
 
     Ok(())
 }
+
+#[tokio::test]
+async fn subscribe_is_idempotent() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        //the retried request must not send a second confirmation email
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act - submit the form twice with the same key
+    let response = app
+        .post_subscriptions_with_idempotency_key(test_body.into(), &idempotency_key)
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    let response = app
+        .post_subscriptions_with_idempotency_key(test_body.into(), &idempotency_key)
+        .await;
+    //Assert
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_outbox().await;
+    let saved = sqlx::query!("SELECT email FROM subscriptions",)
+        .fetch_all(&app.pool_conn)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+}
+
+#[tokio::test]
+async fn idempotency_keys_of_different_addresses_do_not_collide() {
+    //Arrange
+    let app = spawn_app().await;
+    let idempotency_key = "a-key-two-clients-both-picked";
+    //Act
+    for body in [
+        "name=le%20guin&email=ursula_le_guin%40gmail.com",
+        "name=butler&email=octavia_butler%40gmail.com",
+    ] {
+        let response = app
+            .post_subscriptions_with_idempotency_key(body.into(), idempotency_key)
+            .await;
+        assert_eq!(200, response.status().as_u16());
+    }
+    //Assert
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 2);
+}
+
+#[tokio::test]
+async fn reusing_an_idempotency_key_for_a_different_request_is_rejected_with_a_422() {
+    //Arrange
+    let app = spawn_app().await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    app.post_subscriptions_with_idempotency_key(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com".into(),
+        &idempotency_key,
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    //Act
+    let response = app
+        .post_subscriptions_with_idempotency_key(
+            "name=someone%20else&email=ursula_le_guin%40gmail.com".into(),
+            &idempotency_key,
+        )
+        .await;
+    //Assert
+    assert_eq!(422, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn cleanup_removes_expired_idempotency_keys() {
+    //Arrange
+    let app = spawn_app().await;
+    for body in [
+        "name=le%20guin&email=ursula_le_guin%40gmail.com",
+        "name=butler&email=octavia_butler%40gmail.com",
+    ] {
+        app.post_subscriptions_with_idempotency_key(body.into(), &uuid::Uuid::new_v4().to_string())
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+    sqlx::query!(
+        "UPDATE idempotency SET created_at = now() - interval '2 days' WHERE ctid = (SELECT ctid FROM idempotency LIMIT 1)"
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    //Act
+    zero2prod::cleanup_worker::delete_stale_rows(&app.pool_conn)
+        .await
+        .unwrap();
+    //Assert
+    let left = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM idempotency"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(left.count, 1);
+}
+
+#[tokio::test]
+async fn concurrent_subscribe_submissions_are_handled_gracefully() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response1 =
+        app.post_subscriptions_with_idempotency_key(test_body.into(), &idempotency_key);
+    let response2 =
+        app.post_subscriptions_with_idempotency_key(test_body.into(), &idempotency_key);
+    let (response1, response2) = tokio::join!(response1, response2);
+    //Assert
+    assert_eq!(response1.status(), response2.status());
+    assert_eq!(
+        response1.text().await.unwrap(),
+        response2.text().await.unwrap()
+    );
+    app.dispatch_outbox().await;
+}
+
+#[tokio::test]
+async fn concurrent_first_time_submissions_without_a_key_all_succeed() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_server)
+        .await;
+    //Act - none of them finds the row, they all race to insert it
+    let responses = futures_util::future::join_all(
+        (0..5).map(|_| app.post_subscriptions(test_body.into())),
+    )
+    .await;
+    //Assert
+    for response in responses {
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.pool_conn)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_for_an_invalid_idempotency_key() {
+    let app = spawn_app().await;
+    let test_body = "name=Nabeel%20Naveed&email=ac3r_nabeel%40live.com";
+    let response = app
+        .post_subscriptions_with_idempotency_key(test_body.into(), &"a".repeat(60))
+        .await;
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_a_fresh_confirmation_link() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let first_links = app.create_unconfirmed_subscriber().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app.post_subscriptions(test_body.into()).await;
+    //Assert
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_outbox().await;
+    let email_request = app.mock_server.received_requests().await.unwrap().pop().unwrap();
+    let second_links = app.get_confirmation_links(&email_request);
+    assert_ne!(first_links.html, second_links.html);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    //only the latest link confirms
+    let response = reqwest::get(first_links.html).await.unwrap();
+    assert_eq!(401, response.status().as_u16());
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_again_when_confirmed_returns_200_without_an_email() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.create_confirmed_subscriber().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app.post_subscriptions(test_body.into()).await;
+    //Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_all(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_can_subscribe_again() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.create_confirmed_subscriber().await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app.post_subscriptions(test_body.into()).await;
+    //Assert
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_outbox().await;
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribe_succeeds_while_the_email_provider_is_down() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.mock_server)
+        .await;
+    //Act
+    let response = app.post_subscriptions(test_body.into()).await;
+    //Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn the_confirmation_email_is_retried_once_the_email_provider_recovers() {
+    //Arrange
+    let app = spawn_app().await;
+    let test_body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.mock_server)
+        .await;
+    app.post_subscriptions(test_body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_outbox().await;
+    drop(mock_guard);
+    let failed = sqlx::query!("SELECT attempts, last_error, sent_at FROM outbox")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(failed.attempts, 1);
+    assert!(failed.last_error.is_some());
+    assert!(failed.sent_at.is_none());
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act - skip the backoff instead of waiting it out
+    sqlx::query!("UPDATE outbox SET next_attempt_at = now()")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    app.dispatch_outbox().await;
+    //Assert
+    let sent = sqlx::query!("SELECT sent_at FROM outbox")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert!(sent.sent_at.is_some());
+}
+
+#[tokio::test]
+async fn no_confirmation_email_is_sent_if_the_subscription_is_not_committed() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //sabotage the database, the idempotency record is saved right before the commit
+    sqlx::query!("ALTER TABLE idempotency DROP COLUMN response_status_code;")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    //Act
+    let response = app
+        .post_subscriptions_with_idempotency_key(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com".into(),
+            &uuid::Uuid::new_v4().to_string(),
+        )
+        .await;
+    //Assert
+    assert_eq!(500, response.status().as_u16());
+    app.dispatch_outbox().await;
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM outbox"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
+
+#[tokio::test]
+async fn the_confirmation_email_is_rendered_from_the_templates() {
+    //Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_outbox().await;
+    //Assert
+    let email_request = &app.mock_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    let text = body["TextBody"].as_str().unwrap();
+    //the shared layout wraps the html body
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<title>WELCOME</title>"));
+    assert!(html.contains("Welcome to our newsletter, le guin!"));
+    assert!(text.contains("Welcome to our newsletter, le guin!"));
+}
+
+#[tokio::test]
+async fn the_database_rejects_unknown_statuses() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    //Act
+    let result = sqlx::query!("UPDATE subscriptions SET status = 'Confirmed'")
+        .execute(&app.pool_conn)
+        .await;
+    //Assert
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn subscribing_again_with_a_differently_cased_email_does_not_add_a_subscriber() {
+    //Arrange
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    //Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40Gmail.com".into())
+        .await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscribers = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 1);
+}
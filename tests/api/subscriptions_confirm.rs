@@ -1,6 +1,5 @@
+use zero2prod::cleanup_worker::delete_stale_rows;
 use crate::helpers::spawn_app;
-use linkify::LinkFinder;
-use reqwest::Url;
 use wiremock::{ResponseTemplate, Mock}; 
 use wiremock::matchers::{path, method};
 
@@ -18,6 +17,7 @@ async fn confirmations_without_tokens_are_rejected_with_a_400() {
     .await;
     //Act
     let _ = app.post_subscriptions(body.into()).await;
+    app.dispatch_outbox().await;
     let response = reqwest::get(&format!("{}/subscriptions/confirm",app.address)).await.expect("req failed");
     assert_eq!(response.status().as_u16(),400);
 
@@ -37,10 +37,11 @@ async fn the_link_returned_by_subscriber_returns_a_200_if_called() {
     .await;
     //Act
     let _ = app.post_subscriptions(body.into()).await;
+    app.dispatch_outbox().await;
     //fetch the request from the mock email server
     let email_request = &app.mock_server.received_requests().await.expect("failed to get a request")[0];
     //extract html and plain text link from mock  email serve request body
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     assert_eq!(confirmation_links.html,confirmation_links.plain_text);
 // Act
 //send to /confirm with token
@@ -67,15 +68,16 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     .await;
     //Act
     let _ = app.post_subscriptions(body.into()).await;
+    app.dispatch_outbox().await;
     //fetch the request from the mock email server
     let email_request = &app.mock_server.received_requests().await.expect("failed to get a request")[0];
     //extract html and plain text link from mock  email serve request body
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     assert_eq!(confirmation_links.html,confirmation_links.plain_text);
 // Act
 //send to /confirm with token
 //Assert
-let response = reqwest::get(confirmation_links.html)
+reqwest::get(confirmation_links.html)
 .await .unwrap().error_for_status() .unwrap();
 let saved = sqlx::query!("SELECT email,name,status from subscriptions").fetch_one(&app.pool_conn).await.expect("failed to get a record");
 assert_eq!(saved.email, "ursula_le_guin@gmail.com"); assert_eq!(saved.name, "le guin"); assert_eq!(saved.status, "confirmed");
@@ -83,4 +85,103 @@ assert_eq!(saved.email, "ursula_le_guin@gmail.com"); assert_eq!(saved.name, "le
 
 
 
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn confirmation_links_can_only_be_used_once() {
+    //Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    //Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn expired_confirmation_links_are_rejected_with_a_410() {
+    //Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    //Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 410);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn cleanup_removes_long_expired_confirmation_tokens_only() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    sqlx::query!(
+        r#"INSERT INTO subscriptions(id,email,name,subscribed_at,status,unsubscribe_token)
+        VALUES($1,'old@example.com','old',now(),'pending_confirmation','oldunsubscribetoken')"#,
+        uuid::Uuid::new_v4()
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO subscription_tokens(subscription_token,subscriber_id,created_at,expires_at)
+        SELECT 'stale', id, now() - interval '30 days', now() - interval '29 days'
+        FROM subscriptions WHERE email = 'old@example.com'"#
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    //Act
+    delete_stale_rows(&app.pool_conn).await.unwrap();
+    //Assert
+    let remaining = sqlx::query!("SELECT subscription_token FROM subscription_tokens")
+        .fetch_all(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_ne!(remaining[0].subscription_token, "stale");
+}
+
+#[tokio::test]
+async fn leftover_tokens_of_confirmed_subscribers_are_rejected_with_a_409() {
+    //Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    //e.g. confirmed through a link issued before tokens became single use
+    sqlx::query!("UPDATE subscriptions SET status = 'confirmed'")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    //Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn confirm_fails_if_there_is_a_fatal_database_error() {
+    //Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscriptions DROP COLUMN status;")
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    //Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 500);
+}
@@ -0,0 +1,120 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, BatchAccepted, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+async fn get_preferences_html(app: &TestApp, token: &str) -> reqwest::Response {
+    app.api_client
+        .get(format!(
+            "{}/subscriptions/preferences?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .expect("failed to execute request")
+}
+
+#[tokio::test]
+async fn issues_link_to_the_preferences_page_of_each_subscriber() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Manage your subscription: {{ preferences_link }}",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    //Assert
+    let email_request = app
+        .mock_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let token = sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .unsubscribe_token;
+    assert!(body[0]["TextBody"].as_str().unwrap().contains(&format!(
+        "Manage your subscription: {}/subscriptions/preferences?token={}",
+        app.base_url, token
+    )));
+}
+
+#[tokio::test]
+async fn subscribers_can_change_their_name() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .unsubscribe_token;
+    let html_page = get_preferences_html(&app, &token).await.text().await.unwrap();
+    assert!(html_page.contains("ursula_le_guin@gmail.com"));
+    //Act
+    let response = app
+        .api_client
+        .post(format!("{}/subscriptions/preferences", app.address))
+        .form(&[("token", token.as_str()), ("name", "Ursula K. Le Guin")])
+        .send()
+        .await
+        .unwrap();
+    //Assert
+    assert_is_redirect_to(
+        &response,
+        &format!("/subscriptions/preferences?token={}", token),
+    );
+    let html_page = get_preferences_html(&app, &token).await.text().await.unwrap();
+    assert!(html_page.contains("Your preferences have been saved."));
+    assert!(html_page.contains(r#"value="Ursula K. Le Guin""#));
+}
+
+#[tokio::test]
+async fn an_unknown_token_is_rejected_with_a_401() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = get_preferences_html(&app, "notarealtoken").await;
+    //Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_invalid_name_is_not_echoed_back_into_the_page() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap()
+        .unsubscribe_token;
+    //Act
+    app.api_client
+        .post(format!("{}/subscriptions/preferences", app.address))
+        .form(&[("token", token.as_str()), ("name", "<script>alert(1)</script>")])
+        .send()
+        .await
+        .unwrap();
+    //Assert
+    let html_page = get_preferences_html(&app, &token).await.text().await.unwrap();
+    assert!(html_page.contains("<p><i>Please enter a valid name.</i></p>"));
+    assert!(!html_page.contains("<script>"));
+}
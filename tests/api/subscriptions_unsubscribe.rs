@@ -0,0 +1,201 @@
+use crate::helpers::{spawn_app, BatchAccepted, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn get_unsubscribe_token(app: &TestApp) -> String {
+    sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .expect("failed to fetch the unsubscribe token")
+        .unsubscribe_token
+}
+
+async fn publish_an_issue(app: &TestApp) {
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn newsletter_issues_carry_list_unsubscribe_headers() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = get_unsubscribe_token(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchAccepted)
+        .expect(1)
+        .mount(&app.mock_server)
+        .await;
+    //Act
+    publish_an_issue(&app).await;
+    //Assert
+    let email_request = app
+        .mock_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(
+        body[0]["Headers"],
+        serde_json::json!([
+            {
+                "Name": "List-Unsubscribe",
+                "Value": format!("<{}/subscriptions/unsubscribe?token={}>", app.base_url, token)
+            },
+            {
+                "Name": "List-Unsubscribe-Post",
+                "Value": "List-Unsubscribe=One-Click"
+            }
+        ])
+    );
+}
+
+#[tokio::test]
+async fn unsubscribe_without_a_token_is_rejected_with_a_400() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = reqwest::get(&format!("{}/subscriptions/unsubscribe", app.address))
+        .await
+        .unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn unsubscribe_with_an_unknown_token_is_rejected_with_a_401() {
+    //Arrange
+    let app = spawn_app().await;
+    //Act
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/unsubscribe?token=notarealtoken",
+        app.address
+    ))
+    .await
+    .unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn clicking_the_unsubscribe_link_unsubscribes_a_subscriber() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = get_unsubscribe_token(&app).await;
+    //Act
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_stops_further_issues() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = get_unsubscribe_token(&app).await;
+    //Act
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/unsubscribe?token={}",
+            app.address, token
+        ))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    publish_an_issue(&app).await;
+}
+
+#[tokio::test]
+async fn subscribers_who_unsubscribe_after_publishing_are_skipped() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = get_unsubscribe_token(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_server)
+        .await;
+    //an issue queued for them before they unsubscribed, written straight to the
+    //tables since publishing would not queue it for an unsubscribed subscriber
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed' WHERE unsubscribe_token = $1", token)
+        .execute(&app.pool_conn)
+        .await
+        .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO newsletter_issues(newsletter_issue_id,title,text_content,html_content,published_at)
+        VALUES($1,'title','text','<p>html</p>',now())"#,
+        uuid::Uuid::new_v4()
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO issue_delivery_queue(newsletter_issue_id,subscriber_email)
+        SELECT newsletter_issue_id, 'ursula_le_guin@gmail.com' FROM newsletter_issues"#
+    )
+    .execute(&app.pool_conn)
+    .await
+    .unwrap();
+    //Act
+    app.dispatch_all_pending_emails().await;
+    //Assert - mock verifies on drop that nothing was sent
+}
+
+#[tokio::test]
+async fn subscribers_who_never_confirmed_can_unsubscribe() {
+    //Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    let token = get_unsubscribe_token(&app).await;
+    //Act
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/unsubscribe?token={}",
+            app.address, token
+        ))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+    //Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.pool_conn)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+}